@@ -21,7 +21,7 @@ pub fn create_guest_memory_mmap(size: u64, count: u64) -> GuestMemoryMmap {
         regions.push((GuestAddress(i * size), size as usize));
     }
 
-    GuestMemoryMmap::from_ranges(regions.as_slice()).unwrap()
+    GuestMemoryMmap::from_ranges(std::process::id() as i32, regions.as_slice()).unwrap()
 }
 
 pub fn criterion_benchmark(_c: &mut Criterion) {
@@ -15,6 +15,8 @@ use arc_swap::{ArcSwap, Guard};
 use std::ops::Deref;
 use std::sync::{Arc, LockResult, Mutex, MutexGuard, PoisonError};
 
+#[cfg(feature = "backend-mmap")]
+use crate::GuestMemoryMmap;
 use crate::{GuestAddressSpace, GuestMemory};
 
 /// A fast implementation of a mutable collection of memory regions.
@@ -75,6 +77,17 @@ impl<M: GuestMemory> GuestMemoryAtomic<M> {
     }
 }
 
+#[cfg(feature = "backend-mmap")]
+impl GuestMemoryAtomic<GuestMemoryMmap> {
+    /// Returns `false` if the process behind the current memory map is dead, see
+    /// `GuestMemoryMmap::is_alive`.  Accesses to the map then fail with
+    /// `remote_mem::Error::Dead` until a map of a new process is swapped in with
+    /// `GuestMemoryExclusiveGuard::replace`.
+    pub fn is_alive(&self) -> bool {
+        self.load().is_alive()
+    }
+}
+
 impl<M: GuestMemory> GuestAddressSpace for GuestMemoryAtomic<M> {
     type T = GuestMemoryLoadGuard<M>;
     type M = M;
@@ -133,6 +146,10 @@ impl<M: GuestMemory> GuestMemoryExclusiveGuard<'_, M> {
     /// Replace the memory map in the `GuestMemoryAtomic` that created the guard
     /// with the new memory map, `map`.  The lock is then dropped since this
     /// method consumes the guard.
+    ///
+    /// This also re-attaches to a hypervisor process after the previous one died: `map` may
+    /// access the memory of another process, and devices pick it up with their next call to
+    /// `memory()`.
     pub fn replace(self, map: M) {
         self.parent.inner.0.store(Arc::new(map))
     }
@@ -142,6 +159,7 @@ impl<M: GuestMemory> GuestMemoryExclusiveGuard<'_, M> {
 #[cfg(feature = "backend-mmap")]
 mod tests {
     use super::*;
+    use crate::test_utils::local_pid;
     use crate::{
         GuestAddress, GuestMemory, GuestMemoryMmap, GuestMemoryRegion, GuestMemoryResult,
         GuestRegionMmap, GuestUsize, MmapRegion,
@@ -157,7 +175,7 @@ mod tests {
             (GuestAddress(0x1000), region_size),
         ];
         let mut iterated_regions = Vec::new();
-        let gmm = GuestMemoryMmap::from_ranges(&regions).unwrap();
+        let gmm = GuestMemoryMmap::from_ranges(local_pid(), &regions).unwrap();
         let gm = GuestMemoryMmapAtomic::new(gmm);
         let mem = gm.memory();
 
@@ -218,7 +236,7 @@ mod tests {
             (GuestAddress(0x0), region_size),
             (GuestAddress(0x1000), region_size),
         ];
-        let gmm = GuestMemoryMmap::from_ranges(&regions).unwrap();
+        let gmm = GuestMemoryMmap::from_ranges(local_pid(), &regions).unwrap();
         let gm = GuestMemoryMmapAtomic::new(gmm);
         let mem = {
             let guard1 = gm.memory();
@@ -234,7 +252,7 @@ mod tests {
             (GuestAddress(0x0), region_size),
             (GuestAddress(0x10_0000), region_size),
         ];
-        let mut gmm = Arc::new(GuestMemoryMmap::from_ranges(&regions).unwrap());
+        let mut gmm = Arc::new(GuestMemoryMmap::from_ranges(local_pid(), &regions).unwrap());
         let gm: GuestMemoryAtomic<_> = gmm.clone().into();
         let mem_orig = gm.memory();
         assert_eq!(mem_orig.num_regions(), 2);
@@ -243,23 +261,39 @@ mod tests {
             let guard = gm.lock().unwrap();
             let new_gmm = Arc::make_mut(&mut gmm);
             let mmap = Arc::new(
-                GuestRegionMmap::new(MmapRegion::new(0x1000).unwrap(), GuestAddress(0x8000))
-                    .unwrap(),
+                GuestRegionMmap::new(
+                    local_pid(),
+                    MmapRegion::new(0x1000).unwrap(),
+                    GuestAddress(0x8000),
+                )
+                .unwrap(),
             );
             let new_gmm = new_gmm.insert_region(mmap).unwrap();
             let mmap = Arc::new(
-                GuestRegionMmap::new(MmapRegion::new(0x1000).unwrap(), GuestAddress(0x4000))
-                    .unwrap(),
+                GuestRegionMmap::new(
+                    local_pid(),
+                    MmapRegion::new(0x1000).unwrap(),
+                    GuestAddress(0x4000),
+                )
+                .unwrap(),
             );
             let new_gmm = new_gmm.insert_region(mmap).unwrap();
             let mmap = Arc::new(
-                GuestRegionMmap::new(MmapRegion::new(0x1000).unwrap(), GuestAddress(0xc000))
-                    .unwrap(),
+                GuestRegionMmap::new(
+                    local_pid(),
+                    MmapRegion::new(0x1000).unwrap(),
+                    GuestAddress(0xc000),
+                )
+                .unwrap(),
             );
             let new_gmm = new_gmm.insert_region(mmap).unwrap();
             let mmap = Arc::new(
-                GuestRegionMmap::new(MmapRegion::new(0x1000).unwrap(), GuestAddress(0xc000))
-                    .unwrap(),
+                GuestRegionMmap::new(
+                    local_pid(),
+                    MmapRegion::new(0x1000).unwrap(),
+                    GuestAddress(0xc000),
+                )
+                .unwrap(),
             );
             new_gmm.insert_region(mmap).unwrap_err();
             guard.replace(new_gmm);
@@ -269,4 +303,52 @@ mod tests {
         let mem = gm.memory();
         assert_eq!(mem.num_regions(), 5);
     }
+
+    #[test]
+    fn test_atomic_reattach() {
+        use crate::liveness::Watched;
+        use crate::remote_mem::{self, default_transport};
+        use crate::test_utils::TestProcess;
+        use crate::{Bytes, GuestMemoryError};
+        use nix::sys::signal::{kill, Signal};
+        use nix::unistd::Pid;
+        use std::thread::sleep;
+        use std::time::Duration;
+
+        let watched = |child: &TestProcess| {
+            let transport = Arc::new(Watched::new(default_transport(Pid::from_raw(child.pid()))));
+            let hints = [(GuestAddress(0), child.addr(0), 0x1000)];
+            GuestMemoryMmap::from_hints_with_transport(transport, &hints).unwrap()
+        };
+        let child = TestProcess::spawn(&[0x1000]);
+        let gm = GuestMemoryMmapAtomic::new(watched(&child));
+        assert!(gm.is_alive());
+        gm.memory().write_obj(1u32, GuestAddress(0x10)).unwrap();
+
+        kill(Pid::from_raw(child.pid()), Signal::SIGKILL).unwrap();
+        let mem = gm.memory();
+        // The process may take a moment to die.
+        for i in 0.. {
+            if mem.read_obj::<u32>(GuestAddress(0x10)).is_err() {
+                break;
+            }
+            assert!(i < 1000, "process {} didn't die", child.pid());
+            sleep(Duration::from_millis(1));
+        }
+        assert!(!gm.is_alive());
+        assert!(matches!(
+            mem.read_obj::<u32>(GuestAddress(0x10)),
+            Err(GuestMemoryError::RemoteMemError(
+                remote_mem::Error::Dead { .. }
+            ))
+        ));
+
+        let child = TestProcess::spawn(&[0x1000]);
+        gm.lock().unwrap().replace(watched(&child));
+        assert!(gm.is_alive());
+        gm.memory().write_obj(2u32, GuestAddress(0x10)).unwrap();
+        assert_eq!(gm.memory().read_obj::<u32>(GuestAddress(0x10)).unwrap(), 2);
+        // Old snapshots keep failing.
+        assert!(mem.read_obj::<u32>(GuestAddress(0x10)).is_err());
+    }
 }
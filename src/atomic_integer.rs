@@ -21,6 +21,23 @@ pub unsafe trait AtomicInteger: Sync + Send {
     fn store(&self, val: Self::V, order: Ordering);
 }
 
+/// Atomic integers that support a compare-and-exchange operation.
+///
+/// This is separate from `AtomicInteger`, so that implementations of the latter outside of this
+/// crate keep working.
+pub trait AtomicCompareExchange: AtomicInteger {
+    /// Stores `new` into the atomic integer if its value is `current`.
+    ///
+    /// Returns the previous value, wrapped in `Ok` if it was replaced and in `Err` otherwise.
+    fn compare_exchange(
+        &self,
+        current: Self::V,
+        new: Self::V,
+        success: Ordering,
+        failure: Ordering,
+    ) -> Result<Self::V, Self::V>;
+}
+
 macro_rules! impl_atomic_integer_ops {
     ($T:path, $V:ty) => {
         unsafe impl AtomicInteger for $T {
@@ -38,6 +55,18 @@ macro_rules! impl_atomic_integer_ops {
                 self.store(val, order)
             }
         }
+
+        impl AtomicCompareExchange for $T {
+            fn compare_exchange(
+                &self,
+                current: Self::V,
+                new: Self::V,
+                success: Ordering,
+                failure: Ordering,
+            ) -> Result<Self::V, Self::V> {
+                self.compare_exchange(current, new, success, failure)
+            }
+        }
     };
 }
 
@@ -70,7 +99,7 @@ mod tests {
     use std::fmt::Debug;
     use std::sync::atomic::AtomicU32;
 
-    fn check_atomic_integer_ops<A: AtomicInteger>()
+    fn check_atomic_integer_ops<A: AtomicCompareExchange>()
     where
         A::V: Copy + Debug + From<u8> + PartialEq,
     {
@@ -81,6 +110,17 @@ mod tests {
         let v2 = A::V::from(100);
         a.store(v2, Ordering::Relaxed);
         assert_eq!(a.load(Ordering::Relaxed), v2);
+
+        let v3 = A::V::from(200);
+        assert_eq!(
+            a.compare_exchange(v, v3, Ordering::SeqCst, Ordering::Relaxed),
+            Err(v2)
+        );
+        assert_eq!(
+            a.compare_exchange(v2, v3, Ordering::SeqCst, Ordering::Relaxed),
+            Ok(v2)
+        );
+        assert_eq!(a.load(Ordering::Relaxed), v3);
     }
 
     #[test]
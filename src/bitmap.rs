@@ -0,0 +1,181 @@
+//! Tracking of the guest pages written through a region.
+//!
+//! Live migration and incremental snapshots only need to transfer the pages which changed. A
+//! [`DirtyBitmap`](struct.DirtyBitmap.html) has one bit per page of a region, which is set by
+//! every write to the region through `Bytes` or a `VolatileSlice` of it, see
+//! `GuestRegionMmap::with_dirty_tracking`.
+//!
+//! Writes through references handed out by `VolatileMemory::get_ref`, `get_array_ref`,
+//! `get_atomic_ref` or raw pointers aren't tracked and need to be marked with
+//! `VolatileSlice::mark_dirty` or `GuestMemory::mark_dirty`.
+
+use std::cmp::min;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Number of pages per word of the bitmap.
+const WORD_PAGES: usize = 64;
+
+/// A bitmap of the dirty pages of `len` bytes of memory, which can be shared between threads.
+#[derive(Debug)]
+pub struct DirtyBitmap {
+    words: Vec<AtomicU64>,
+    len: usize,
+    page_shift: u32,
+}
+
+impl DirtyBitmap {
+    /// Creates a clean bitmap for `len` bytes in pages of `page_size` bytes.
+    ///
+    /// Returns `None` if `page_size` isn't a power of two.
+    pub fn new(len: usize, page_size: usize) -> Option<Self> {
+        if !page_size.is_power_of_two() {
+            return None;
+        }
+        let page_shift = page_size.trailing_zeros();
+        let pages = (len >> page_shift) + usize::from(len & (page_size - 1) != 0);
+        let words = (pages / WORD_PAGES) + usize::from(pages & (WORD_PAGES - 1) != 0);
+        Some(DirtyBitmap {
+            words: (0..words).map(|_| AtomicU64::new(0)).collect(),
+            len,
+            page_shift,
+        })
+    }
+
+    /// Returns the size of the pages in bytes.
+    pub fn page_size(&self) -> usize {
+        1 << self.page_shift
+    }
+
+    /// Marks all pages overlapping the `len` bytes at `offset` as dirty.
+    ///
+    /// The part of the range past the tracked memory is ignored.
+    pub fn mark(&self, offset: usize, len: usize) {
+        let end = min(offset.saturating_add(len), self.len);
+        if offset >= end {
+            return;
+        }
+        for page in (offset >> self.page_shift)..=((end - 1) >> self.page_shift) {
+            self.words[page / WORD_PAGES].fetch_or(1 << (page % WORD_PAGES), Ordering::Release);
+        }
+    }
+
+    /// Returns `true` if the page containing `offset` is dirty.
+    pub fn is_dirty(&self, offset: usize) -> bool {
+        if offset >= self.len {
+            return false;
+        }
+        let page = offset >> self.page_shift;
+        self.words[page / WORD_PAGES].load(Ordering::Acquire) & (1 << (page % WORD_PAGES)) != 0
+    }
+
+    /// Returns the ranges of dirty pages, as `(offset, len)` in bytes.
+    ///
+    /// The bitmap is read when this is called, later writes aren't reported.
+    pub fn dirty_ranges(&self) -> DirtyRanges {
+        self.ranges(|word| word.load(Ordering::Acquire))
+    }
+
+    /// Returns the ranges of dirty pages like `dirty_ranges` and clears them.
+    ///
+    /// Each word of the bitmap is fetched and cleared atomically, so a page written
+    /// concurrently is either reported now or stays dirty.
+    pub fn take_dirty_ranges(&self) -> DirtyRanges {
+        self.ranges(|word| word.swap(0, Ordering::AcqRel))
+    }
+
+    /// Marks all pages as clean.
+    pub fn clear(&self) {
+        for word in self.words.iter() {
+            word.store(0, Ordering::Release);
+        }
+    }
+
+    fn ranges<F: FnMut(&AtomicU64) -> u64>(&self, f: F) -> DirtyRanges {
+        DirtyRanges {
+            words: self.words.iter().map(f).collect(),
+            len: self.len,
+            page_shift: self.page_shift,
+            page: 0,
+        }
+    }
+}
+
+/// An iterator over the ranges of dirty pages of a `DirtyBitmap`, as `(offset, len)` in bytes.
+///
+/// Adjacent dirty pages are reported as one range.
+#[derive(Clone, Debug)]
+pub struct DirtyRanges {
+    words: Vec<u64>,
+    len: usize,
+    page_shift: u32,
+    page: usize,
+}
+
+impl DirtyRanges {
+    fn is_dirty(&self, page: usize) -> bool {
+        self.words[page / WORD_PAGES] & (1 << (page % WORD_PAGES)) != 0
+    }
+}
+
+impl Iterator for DirtyRanges {
+    type Item = (usize, usize);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let pages = self.words.len() * WORD_PAGES;
+        while self.page < pages && !self.is_dirty(self.page) {
+            if self.words[self.page / WORD_PAGES] == 0 {
+                self.page = (self.page / WORD_PAGES + 1) * WORD_PAGES;
+            } else {
+                self.page += 1;
+            }
+        }
+        if self.page >= pages {
+            return None;
+        }
+        let start = self.page << self.page_shift;
+        while self.page < pages && self.is_dirty(self.page) {
+            self.page += 1;
+        }
+        let end = min(self.page << self.page_shift, self.len);
+        Some((start, end - start))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_dirty_bitmap() {
+        assert!(DirtyBitmap::new(0x1000, 0x1800).is_none());
+        let b = DirtyBitmap::new(0x42800, 0x1000).unwrap();
+        assert_eq!(b.page_size(), 0x1000);
+        assert_eq!(b.words.len(), 2);
+        assert_eq!(b.dirty_ranges().next(), None);
+
+        b.mark(0x10, 0);
+        b.mark(0xfff, 2);
+        b.mark(0x3000, 0x1000);
+        b.mark(0x3f000, 0x1001);
+        b.mark(0x42700, usize::MAX);
+        b.mark(0x50000, 1);
+        assert!(b.is_dirty(0) && b.is_dirty(0x1fff) && !b.is_dirty(0x2000));
+        assert!(b.is_dirty(0x3fff) && !b.is_dirty(0x4000));
+        assert!(!b.is_dirty(0x50000));
+        let ranges = vec![
+            (0, 0x2000),
+            (0x3000, 0x1000),
+            (0x3f000, 0x2000),
+            (0x42000, 0x800),
+        ];
+        assert_eq!(b.dirty_ranges().collect::<Vec<_>>(), ranges);
+
+        // Only the fetched pages are cleared.
+        let taken = b.take_dirty_ranges();
+        b.mark(0x5000, 1);
+        assert_eq!(taken.collect::<Vec<_>>(), ranges);
+        assert_eq!(b.dirty_ranges().collect::<Vec<_>>(), vec![(0x5000, 0x1000)]);
+        b.clear();
+        assert_eq!(b.take_dirty_ranges().next(), None);
+    }
+}
@@ -0,0 +1,492 @@
+//! A read-through page cache for guest memory of another process.
+//!
+//! Every access to remote guest memory costs a system call, which adds up when devices parse
+//! descriptors with many tiny reads. [`CachedMemory`](struct.CachedMemory.html) wraps a
+//! `GuestMemoryMmap`, fetches whole pages of its remote regions on the first read and serves
+//! further small reads of them locally.
+//!
+//! Writes go through to the remote process and update the cached copies, but changes made by the
+//! remote side, e.g. by the guest, are only seen once the affected range is invalidated. Devices
+//! typically invalidate their queues when they are notified. Atomic loads always access the
+//! remote memory, and regions which are mapped in the current process aren't cached at all.
+
+use std::cmp::min;
+use std::collections::HashMap;
+use std::io::{Read, Write};
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, Mutex};
+
+use crate::address::Address;
+use crate::bitmap::DirtyBitmap;
+use crate::guest_memory::{
+    self, FileOffset, GuestAddress, GuestMemory, GuestMemoryIterator, GuestMemoryRegion,
+    GuestUsize, MemoryRegionAddress,
+};
+use crate::mmap::{GuestMemoryMmap, GuestRegionMmap};
+use crate::volatile_memory::VolatileSlice;
+use crate::{AtomicAccess, Bytes};
+
+/// Granularity of the cache. Reads of more bytes bypass the cache.
+pub const PAGE_SIZE: usize = 0x1000;
+
+/// A guest memory region whose remote memory is cached page-wise.
+#[derive(Debug)]
+pub struct CachedRegion {
+    region: Arc<GuestRegionMmap>,
+    max_pages: usize,
+    pages: Mutex<HashMap<usize, Box<[u8]>>>,
+}
+
+impl CachedRegion {
+    /// Wraps `region`, caching at most `max_pages` of its pages.
+    pub fn new(region: Arc<GuestRegionMmap>, max_pages: usize) -> Self {
+        CachedRegion {
+            region,
+            max_pages,
+            pages: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Returns the wrapped region.
+    pub fn inner(&self) -> &Arc<GuestRegionMmap> {
+        &self.region
+    }
+
+    /// Returns the number of pages currently cached.
+    pub fn cached_pages(&self) -> usize {
+        self.pages.lock().unwrap().len()
+    }
+
+    /// Drops the cached pages overlapping `len` bytes at `addr`.
+    pub fn invalidate(&self, addr: MemoryRegionAddress, len: usize) {
+        if len == 0 {
+            return;
+        }
+        let first = addr.raw_value() as usize / PAGE_SIZE;
+        let last = (addr.raw_value() as usize).saturating_add(len - 1) / PAGE_SIZE;
+        self.pages
+            .lock()
+            .unwrap()
+            .retain(|&index, _| index < first || index > last);
+    }
+
+    /// Drops all cached pages.
+    pub fn invalidate_all(&self) {
+        self.pages.lock().unwrap().clear();
+    }
+
+    /// Returns `true` if reads of `len` bytes are served from the cache.
+    fn cached(&self, len: usize) -> bool {
+        !self.region.is_local() && self.max_pages > 0 && len <= PAGE_SIZE
+    }
+
+    /// Reads up to `buf.len()` bytes at `maddr`, fetching missing pages.
+    fn read_cached(&self, buf: &mut [u8], maddr: usize) -> guest_memory::Result<usize> {
+        let size = self.region.len() as usize;
+        let len = min(buf.len(), size - maddr);
+        let mut pages = self.pages.lock().unwrap();
+        let mut done = 0;
+        while done < len {
+            let cur = maddr + done;
+            let (index, offset) = (cur / PAGE_SIZE, cur % PAGE_SIZE);
+            let n = min(PAGE_SIZE - offset, len - done);
+            if !pages.contains_key(&index) {
+                let start = index * PAGE_SIZE;
+                let mut page = vec![0u8; min(PAGE_SIZE, size - start)].into_boxed_slice();
+                match self
+                    .region
+                    .read_slice(&mut page, MemoryRegionAddress(start as u64))
+                {
+                    Ok(()) => {}
+                    Err(e) if done == 0 => return Err(e),
+                    Err(_) => break,
+                }
+                if pages.len() >= self.max_pages {
+                    // Any page will do, the cache is meant for hot descriptor tables and rings.
+                    if let Some(&evicted) = pages.keys().next() {
+                        pages.remove(&evicted);
+                    }
+                }
+                pages.insert(index, page);
+            }
+            buf[done..done + n].copy_from_slice(&pages[&index][offset..offset + n]);
+            done += n;
+        }
+        Ok(done)
+    }
+
+    /// Updates the cached copies of the pages overlapping `buf` written to `maddr`.
+    fn update(&self, buf: &[u8], maddr: usize) {
+        let mut pages = self.pages.lock().unwrap();
+        let mut done = 0;
+        while done < buf.len() {
+            let cur = maddr + done;
+            let (index, offset) = (cur / PAGE_SIZE, cur % PAGE_SIZE);
+            let n = min(PAGE_SIZE - offset, buf.len() - done);
+            if let Some(page) = pages.get_mut(&index) {
+                page[offset..offset + n].copy_from_slice(&buf[done..done + n]);
+            }
+            done += n;
+        }
+    }
+}
+
+impl Bytes<MemoryRegionAddress> for CachedRegion {
+    type E = guest_memory::Error;
+
+    fn write(&self, buf: &[u8], addr: MemoryRegionAddress) -> guest_memory::Result<usize> {
+        let written = self.region.write(buf, addr)?;
+        if !self.region.is_local() {
+            self.update(&buf[..written], addr.raw_value() as usize);
+        }
+        Ok(written)
+    }
+
+    fn read(&self, buf: &mut [u8], addr: MemoryRegionAddress) -> guest_memory::Result<usize> {
+        let maddr = addr.raw_value() as usize;
+        if !self.cached(buf.len()) || maddr >= self.region.len() as usize {
+            return self.region.read(buf, addr);
+        }
+        self.read_cached(buf, maddr)
+    }
+
+    fn write_slice(&self, buf: &[u8], addr: MemoryRegionAddress) -> guest_memory::Result<()> {
+        let written = self.write(buf, addr)?;
+        if written != buf.len() {
+            return Err(guest_memory::Error::PartialBuffer {
+                expected: buf.len(),
+                completed: written,
+            });
+        }
+        Ok(())
+    }
+
+    fn read_slice(&self, buf: &mut [u8], addr: MemoryRegionAddress) -> guest_memory::Result<()> {
+        let read = self.read(buf, addr)?;
+        if read != buf.len() {
+            return Err(guest_memory::Error::PartialBuffer {
+                expected: buf.len(),
+                completed: read,
+            });
+        }
+        Ok(())
+    }
+
+    fn read_from<F>(
+        &self,
+        addr: MemoryRegionAddress,
+        src: &mut F,
+        count: usize,
+    ) -> guest_memory::Result<usize>
+    where
+        F: Read,
+    {
+        // Invalidate after the write, so that pages read in the meantime don't keep the old data.
+        let res = self.region.read_from(addr, src, count);
+        self.invalidate(addr, count);
+        res
+    }
+
+    fn read_exact_from<F>(
+        &self,
+        addr: MemoryRegionAddress,
+        src: &mut F,
+        count: usize,
+    ) -> guest_memory::Result<()>
+    where
+        F: Read,
+    {
+        // Invalidate after the write, so that pages read in the meantime don't keep the old data.
+        let res = self.region.read_exact_from(addr, src, count);
+        self.invalidate(addr, count);
+        res
+    }
+
+    fn write_to<F>(
+        &self,
+        addr: MemoryRegionAddress,
+        dst: &mut F,
+        count: usize,
+    ) -> guest_memory::Result<usize>
+    where
+        F: Write,
+    {
+        self.region.write_to(addr, dst, count)
+    }
+
+    fn write_all_to<F>(
+        &self,
+        addr: MemoryRegionAddress,
+        dst: &mut F,
+        count: usize,
+    ) -> guest_memory::Result<()>
+    where
+        F: Write,
+    {
+        self.region.write_all_to(addr, dst, count)
+    }
+
+    fn store<T: AtomicAccess>(
+        &self,
+        val: T,
+        addr: MemoryRegionAddress,
+        order: Ordering,
+    ) -> guest_memory::Result<()> {
+        self.region.store(val, addr, order)?;
+        if !self.region.is_local() {
+            self.update(val.as_slice(), addr.raw_value() as usize);
+        }
+        Ok(())
+    }
+
+    fn load<T: AtomicAccess>(
+        &self,
+        addr: MemoryRegionAddress,
+        order: Ordering,
+    ) -> guest_memory::Result<T> {
+        self.region.load(addr, order)
+    }
+}
+
+/// Remote regions don't hand out slices, so that all accesses go through the cache.
+impl GuestMemoryRegion for CachedRegion {
+    fn len(&self) -> GuestUsize {
+        self.region.len()
+    }
+
+    fn start_addr(&self) -> GuestAddress {
+        self.region.start_addr()
+    }
+
+    fn file_offset(&self) -> Option<&FileOffset> {
+        self.region.file_offset()
+    }
+
+    unsafe fn as_slice(&self) -> Option<&[u8]> {
+        self.region.as_slice()
+    }
+
+    unsafe fn as_mut_slice(&self) -> Option<&mut [u8]> {
+        self.region.as_mut_slice()
+    }
+
+    fn get_host_address(&self, addr: MemoryRegionAddress) -> guest_memory::Result<*mut u8> {
+        if !self.region.is_local() {
+            return Err(guest_memory::Error::HostAddressNotAvailable);
+        }
+        self.region.get_host_address(addr)
+    }
+
+    fn get_slice(
+        &self,
+        offset: MemoryRegionAddress,
+        count: usize,
+    ) -> guest_memory::Result<VolatileSlice<'_>> {
+        if !self.region.is_local() {
+            return Err(guest_memory::Error::HostAddressNotAvailable);
+        }
+        self.region.get_slice(offset, count)
+    }
+
+    fn is_hugetlbfs(&self) -> Option<bool> {
+        self.region.is_hugetlbfs()
+    }
+
+    fn dirty_bitmap(&self) -> Option<&DirtyBitmap> {
+        self.region.dirty_bitmap()
+    }
+}
+
+/// [`GuestMemory`](../trait.GuestMemory.html) implementation caching the remote regions of a
+/// `GuestMemoryMmap`, see the [module documentation](index.html).
+#[derive(Debug)]
+pub struct CachedMemory {
+    regions: Vec<CachedRegion>,
+}
+
+impl CachedMemory {
+    /// Wraps the regions of `mem`, caching at most `max_pages` pages of each.
+    pub fn new(mem: &GuestMemoryMmap, max_pages: usize) -> Self {
+        CachedMemory {
+            regions: mem
+                .arc_regions()
+                .iter()
+                .map(|region| CachedRegion::new(region.clone(), max_pages))
+                .collect(),
+        }
+    }
+
+    /// Drops the cached pages overlapping `len` bytes at `addr`.
+    pub fn invalidate(&self, addr: GuestAddress, len: usize) {
+        let end = addr.0.saturating_add(len as u64);
+        for region in self.regions.iter() {
+            let start = region.start_addr().0;
+            let region_end = start + region.len();
+            if addr.0 < region_end && end > start {
+                let from = addr.0.max(start);
+                let to = end.min(region_end);
+                region.invalidate(MemoryRegionAddress(from - start), (to - from) as usize);
+            }
+        }
+    }
+
+    /// Drops all cached pages.
+    pub fn invalidate_all(&self) {
+        for region in self.regions.iter() {
+            region.invalidate_all();
+        }
+    }
+}
+
+/// An iterator over the regions of a `CachedMemory`.
+pub struct Iter<'a>(std::slice::Iter<'a, CachedRegion>);
+
+impl<'a> Iterator for Iter<'a> {
+    type Item = &'a CachedRegion;
+    fn next(&mut self) -> Option<Self::Item> {
+        self.0.next()
+    }
+}
+
+impl<'a> GuestMemoryIterator<'a, CachedRegion> for CachedMemory {
+    type Iter = Iter<'a>;
+}
+
+impl GuestMemory for CachedMemory {
+    type R = CachedRegion;
+
+    type I = Self;
+
+    fn num_regions(&self) -> usize {
+        self.regions.len()
+    }
+
+    fn find_region(&self, addr: GuestAddress) -> Option<&CachedRegion> {
+        let index = match self.regions.binary_search_by_key(&addr, |x| x.start_addr()) {
+            Ok(x) => Some(x),
+            // Within the closest region with starting address < addr
+            Err(x) if (x > 0 && addr <= self.regions[x - 1].last_addr()) => Some(x - 1),
+            _ => None,
+        };
+        index.map(|x| &self.regions[x])
+    }
+
+    fn iter(&self) -> Iter<'_> {
+        Iter(self.regions.iter())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use nix::unistd::Pid;
+
+    use crate::remote_mem::{ProcessVm, Transport};
+    use crate::test_utils::{local_pid, TestProcess};
+
+    #[test]
+    fn test_cached_memory() {
+        let (child, gm) =
+            TestProcess::guest_memory(&[(GuestAddress(0), 0x2000), (GuestAddress(0x2000), 0x1000)]);
+        let cache = CachedMemory::new(&gm, 2);
+        let guest = ProcessVm::new(Pid::from_raw(child.pid()));
+
+        // Small reads of the same page are served from the cache.
+        guest
+            .write_bytes(child.addr(0) + 0x10, &[1, 2, 3, 4])
+            .unwrap();
+        assert_eq!(cache.read_obj::<u16>(GuestAddress(0x10)).unwrap(), 0x201);
+        assert_eq!(cache.read_obj::<u16>(GuestAddress(0x12)).unwrap(), 0x403);
+        assert_eq!(gm.stats().syscalls, 1);
+
+        // Changes of the guest are seen after invalidating.
+        guest.write_bytes(child.addr(0) + 0x10, &[5]).unwrap();
+        assert_eq!(cache.read_obj::<u8>(GuestAddress(0x10)).unwrap(), 1);
+        cache.invalidate(GuestAddress(0xfff), 2);
+        assert_eq!(cache.read_obj::<u8>(GuestAddress(0x10)).unwrap(), 5);
+
+        // Writes go through and update the cached page.
+        cache.write_obj(0xaabbu16, GuestAddress(0x20)).unwrap();
+        cache
+            .store(0xccu8, GuestAddress(0x22), Ordering::Relaxed)
+            .unwrap();
+        let mut buf = [0u8; 3];
+        guest.read_bytes(&mut buf, child.addr(0) + 0x20).unwrap();
+        assert_eq!(buf, [0xbb, 0xaa, 0xcc]);
+        assert_eq!(cache.read_obj::<[u8; 3]>(GuestAddress(0x20)).unwrap(), buf);
+
+        // Reads across regions, eviction and loads, which always go to the process.
+        let mut buf = [0u8; 0x10];
+        cache.read_slice(&mut buf, GuestAddress(0x1ff8)).unwrap();
+        assert!(cache.iter().all(|r| r.cached_pages() <= 2));
+        guest.write_bytes(child.addr(1), &[7]).unwrap();
+        assert_eq!(
+            cache
+                .load::<u8>(GuestAddress(0x2000), Ordering::Relaxed)
+                .unwrap(),
+            7
+        );
+        assert_eq!(cache.read_obj::<u8>(GuestAddress(0x2000)).unwrap(), 0);
+        cache.invalidate_all();
+        assert!(cache.iter().all(|r| r.cached_pages() == 0));
+        assert_eq!(cache.read_obj::<u8>(GuestAddress(0x2000)).unwrap(), 7);
+
+        assert!(cache.find_region(GuestAddress(0x2000)).is_some());
+        assert!(cache.find_region(GuestAddress(0x3000)).is_none());
+        assert!(cache.get_slice(GuestAddress(0), 4).is_err());
+        assert!(cache.read_obj::<u8>(GuestAddress(0x3000)).is_err());
+    }
+
+    // Reads the cached memory while being read from, like another thread could.
+    struct ReadingSource<'a> {
+        cache: &'a CachedMemory,
+        data: &'a [u8],
+    }
+
+    impl Read for ReadingSource<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            self.cache.read_obj::<u8>(GuestAddress(0)).unwrap();
+            self.data.read(buf)
+        }
+    }
+
+    #[test]
+    fn test_read_from_invalidates() {
+        let (_child, gm) = TestProcess::guest_memory(&[(GuestAddress(0), 0x1000)]);
+        let cache = CachedMemory::new(&gm, 2);
+        let region = cache.find_region(GuestAddress(0)).unwrap();
+
+        let mut src = ReadingSource {
+            cache: &cache,
+            data: &[1, 2, 3, 4],
+        };
+        region
+            .read_from(MemoryRegionAddress(0), &mut src, 4)
+            .unwrap();
+        assert_eq!(cache.read_obj::<u32>(GuestAddress(0)).unwrap(), 0x0403_0201);
+
+        let mut src = ReadingSource {
+            cache: &cache,
+            data: &[5, 6, 7, 8],
+        };
+        region
+            .read_exact_from(MemoryRegionAddress(0), &mut src, 4)
+            .unwrap();
+        assert_eq!(cache.read_obj::<u32>(GuestAddress(0)).unwrap(), 0x0807_0605);
+    }
+
+    #[test]
+    fn test_local_uncached() {
+        let gm = GuestMemoryMmap::from_ranges(local_pid(), &[(GuestAddress(0), 0x1000)]).unwrap();
+        let cache = CachedMemory::new(&gm, 16);
+        cache.write_obj(5u32, GuestAddress(0x100)).unwrap();
+        assert_eq!(cache.read_obj::<u32>(GuestAddress(0x100)).unwrap(), 5);
+        assert_eq!(gm.read_obj::<u32>(GuestAddress(0x100)).unwrap(), 5);
+        assert_eq!(
+            cache.find_region(GuestAddress(0)).unwrap().cached_pages(),
+            0
+        );
+        assert!(cache.get_slice(GuestAddress(0x100), 4).is_ok());
+    }
+}
@@ -0,0 +1,229 @@
+//! Dumps of guest memory as ELF core files.
+//!
+//! [`write_core`](fn.write_core.html) writes the memory of a guest to an ELF64 core file with one
+//! `PT_LOAD` program header per region, which can be loaded into tools like gdb or crash to
+//! inspect a hung guest. Both the physical and the virtual address of a segment are the guest
+//! base address of its region, so that gdb, which only uses virtual addresses, shows guest
+//! physical memory. The file has no notes, i.e. no state of the guest's vCPUs.
+//!
+//! The memory is copied with `Bytes::write_all_to`, so the regions of a remote
+//! `GuestMemoryMmap` are streamed a page at a time instead of being buffered.
+//!
+//! # Examples
+//!
+//! ```
+//! # #[cfg(feature = "backend-mmap")]
+//! # {
+//! # use vm_memory::{GuestAddress, GuestMemoryMmap};
+//! # use vm_memory::core_dump;
+//! # let pid = std::process::id() as i32;
+//! let ranges = [(GuestAddress(0), 0xa0000), (GuestAddress(0x100000), 0x100000)];
+//! let gm = GuestMemoryMmap::from_ranges(pid, &ranges).unwrap();
+//!
+//! let mut core = Vec::new();
+//! core_dump::write_core(&gm, &mut core).unwrap();
+//! assert_eq!(&core[..4], b"\x7fELF");
+//! assert_eq!(core.len(), 64 + 2 * 56 + 0xa0000 + 0x100000);
+//! # }
+//! ```
+
+use std::error;
+use std::fmt;
+use std::io::{self, Write};
+use std::result;
+
+use crate::guest_memory::{self, GuestMemory, GuestMemoryRegion, MemoryRegionAddress};
+use crate::Bytes;
+
+/// Size of the ELF header.
+const EHDR_SIZE: u16 = 64;
+/// Size of a program header.
+const PHDR_SIZE: u16 = 56;
+
+const ET_CORE: u16 = 4;
+const PT_LOAD: u32 = 1;
+/// `PF_X | PF_W | PF_R`.
+const PF_RWX: u32 = 7;
+
+#[cfg(target_arch = "x86_64")]
+const EM_HOST: u16 = 62;
+#[cfg(target_arch = "aarch64")]
+const EM_HOST: u16 = 183;
+#[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+const EM_HOST: u16 = 0;
+
+/// Errors of writing a core file.
+#[derive(Debug)]
+pub enum Error {
+    /// Writing the core file failed.
+    Io(io::Error),
+    /// Reading the guest memory failed.
+    Memory(guest_memory::Error),
+    /// The guest memory has more regions than a core file without section headers can describe.
+    TooManyRegions(usize),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Io(e) => write!(f, "cannot write the core file: {}", e),
+            Error::Memory(e) => write!(f, "cannot read guest memory: {}", e),
+            Error::TooManyRegions(n) => write!(f, "too many regions for a core file: {}", n),
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            Error::Io(e) => Some(e),
+            Error::Memory(e) => Some(e),
+            Error::TooManyRegions(_) => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Error::Io(e)
+    }
+}
+
+/// A specialized `Result` type for writing core files.
+pub type Result<T> = result::Result<T, Error>;
+
+/// Writes the ELF header of a core file with `phnum` program headers.
+fn write_ehdr<W: Write>(w: &mut W, phnum: u16) -> io::Result<()> {
+    // ELFCLASS64, ELFDATA2LSB, EV_CURRENT, ELFOSABI_NONE and padding.
+    w.write_all(b"\x7fELF\x02\x01\x01\x00\x00\x00\x00\x00\x00\x00\x00\x00")?;
+    w.write_all(&ET_CORE.to_le_bytes())?;
+    w.write_all(&EM_HOST.to_le_bytes())?;
+    // e_version
+    w.write_all(&1u32.to_le_bytes())?;
+    // e_entry
+    w.write_all(&0u64.to_le_bytes())?;
+    // e_phoff, the program headers follow the ELF header.
+    w.write_all(&u64::from(EHDR_SIZE).to_le_bytes())?;
+    // e_shoff
+    w.write_all(&0u64.to_le_bytes())?;
+    // e_flags
+    w.write_all(&0u32.to_le_bytes())?;
+    w.write_all(&EHDR_SIZE.to_le_bytes())?;
+    w.write_all(&PHDR_SIZE.to_le_bytes())?;
+    w.write_all(&phnum.to_le_bytes())?;
+    // e_shentsize, e_shnum and e_shstrndx
+    w.write_all(&[0; 6])
+}
+
+/// Writes a `PT_LOAD` program header for `region`, whose contents are at `offset` in the file.
+fn write_phdr<W: Write, R: GuestMemoryRegion>(
+    w: &mut W,
+    region: &R,
+    offset: u64,
+) -> io::Result<()> {
+    w.write_all(&PT_LOAD.to_le_bytes())?;
+    w.write_all(&PF_RWX.to_le_bytes())?;
+    w.write_all(&offset.to_le_bytes())?;
+    // p_vaddr and p_paddr
+    w.write_all(&region.start_addr().0.to_le_bytes())?;
+    w.write_all(&region.start_addr().0.to_le_bytes())?;
+    // p_filesz and p_memsz
+    w.write_all(&region.len().to_le_bytes())?;
+    w.write_all(&region.len().to_le_bytes())?;
+    // p_align
+    w.write_all(&0u64.to_le_bytes())
+}
+
+/// Writes an ELF64 core file of all regions of `mem` to `w`.
+///
+/// The contents of the regions follow each other in the order of `GuestMemory::iter`, right
+/// after the headers. The guest shouldn't run while the dump is written, as it may see a mix of
+/// old and new contents otherwise.
+pub fn write_core<M: GuestMemory, W: Write>(mem: &M, mut w: W) -> Result<()> {
+    let count = mem.num_regions();
+    // PN_XNUM (0xffff) means that the count is in the first section header.
+    if count >= 0xffff {
+        return Err(Error::TooManyRegions(count));
+    }
+    write_ehdr(&mut w, count as u16)?;
+    let mut offset = u64::from(EHDR_SIZE) + u64::from(PHDR_SIZE) * count as u64;
+    for region in mem.iter() {
+        write_phdr(&mut w, region, offset)?;
+        offset += region.len();
+    }
+    for region in mem.iter() {
+        region
+            .write_all_to(MemoryRegionAddress(0), &mut w, region.len() as usize)
+            .map_err(Error::Memory)?;
+    }
+    w.flush()?;
+    Ok(())
+}
+
+#[cfg(all(test, feature = "backend-mmap"))]
+mod tests {
+    use super::*;
+    use std::convert::TryInto;
+
+    use crate::guest_memory::GuestAddress;
+    use crate::mmap::GuestMemoryMmap;
+    use crate::test_utils::{local_pid, TestProcess};
+
+    fn u16_at(core: &[u8], offset: usize) -> u16 {
+        u16::from_le_bytes(core[offset..offset + 2].try_into().unwrap())
+    }
+
+    fn u64_at(core: &[u8], offset: usize) -> u64 {
+        u64::from_le_bytes(core[offset..offset + 8].try_into().unwrap())
+    }
+
+    /// Checks that `core` has the segments `(guest base, len, fill byte)`.
+    fn check_core(core: &[u8], segments: &[(u64, u64, u8)]) {
+        assert_eq!(&core[..6], b"\x7fELF\x02\x01");
+        assert_eq!(u16_at(core, 16), ET_CORE);
+        assert_eq!(u16_at(core, 56), segments.len() as u16);
+        let phoff = u64_at(core, 32) as usize;
+        for (i, &(base, len, fill)) in segments.iter().enumerate() {
+            let phdr = &core[phoff + i * 56..phoff + (i + 1) * 56];
+            assert_eq!(u32::from_le_bytes(phdr[..4].try_into().unwrap()), PT_LOAD);
+            assert_eq!(u64_at(phdr, 16), base);
+            assert_eq!(u64_at(phdr, 24), base);
+            assert_eq!(u64_at(phdr, 32), len);
+            assert_eq!(u64_at(phdr, 40), len);
+            let offset = u64_at(phdr, 8) as usize;
+            let data = &core[offset..offset + len as usize];
+            assert!(data.iter().all(|&b| b == fill));
+        }
+        let end = segments.iter().map(|s| s.1).sum::<u64>() as usize;
+        assert_eq!(core.len(), 64 + 56 * segments.len() + end);
+    }
+
+    #[test]
+    fn test_write_core() {
+        let ranges = [(GuestAddress(0), 0x3000), (GuestAddress(0x10000), 0x1800)];
+        let gm = GuestMemoryMmap::from_ranges(local_pid(), &ranges).unwrap();
+        gm.write_slice(&[1; 0x3000], GuestAddress(0)).unwrap();
+        gm.write_slice(&[2; 0x1800], GuestAddress(0x10000)).unwrap();
+        let mut core = Vec::new();
+        write_core(&gm, &mut core).unwrap();
+        check_core(&core, &[(0, 0x3000, 1), (0x10000, 0x1800, 2)]);
+
+        let mut core = Vec::new();
+        write_core(&GuestMemoryMmap::new(local_pid()), &mut core).unwrap();
+        check_core(&core, &[]);
+    }
+
+    #[test]
+    fn test_write_remote_core() {
+        let (_child, gm) = TestProcess::guest_memory(&[
+            (GuestAddress(0x1000), 0x20000),
+            (GuestAddress(0x100000), 0x10000),
+        ]);
+        gm.write_slice(&[3; 0x20000], GuestAddress(0x1000)).unwrap();
+        gm.write_slice(&[4; 0x10000], GuestAddress(0x100000))
+            .unwrap();
+        let mut core = Vec::new();
+        write_core(&gm, &mut core).unwrap();
+        check_core(&core, &[(0x1000, 0x20000, 3), (0x100000, 0x10000, 4)]);
+    }
+}
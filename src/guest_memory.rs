@@ -37,17 +37,23 @@ use std::convert::From;
 use std::fmt::{self, Display};
 use std::fs::File;
 use std::io::{self, Read, Write};
+use std::mem::size_of;
 use std::ops::{BitAnd, BitOr, Deref};
 use std::rc::Rc;
+use std::result;
 use std::sync::atomic::Ordering;
 use std::sync::Arc;
 
 use crate::address::{Address, AddressValue};
+use crate::atomic_integer::AtomicCompareExchange;
+use crate::bitmap::{DirtyBitmap, DirtyRanges};
 use crate::bytes::{AtomicAccess, Bytes};
-use crate::remote_mem;
+use crate::remote_mem::{self, Transport};
+use crate::stats::AccessKind;
 use crate::volatile_memory;
+use nix::sys::uio::RemoteIoVec;
 
-static MAX_ACCESS_CHUNK: usize = 4096;
+pub(crate) static MAX_ACCESS_CHUNK: usize = 4096;
 
 /// Errors associated with handling guest memory accesses.
 #[allow(missing_docs)]
@@ -82,6 +88,8 @@ impl From<volatile_memory::Error> for Error {
                 expected,
                 completed,
             },
+            volatile_memory::Error::RemoteAddress { .. } => Error::HostAddressNotAvailable,
+            volatile_memory::Error::RemoteMemError(e) => Error::RemoteMemError(e),
         }
     }
 }
@@ -89,7 +97,14 @@ impl From<volatile_memory::Error> for Error {
 /// Result of guest memory operations.
 pub type Result<T> = std::result::Result<T, Error>;
 
-impl std::error::Error for Error {}
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::RemoteMemError(e) => Some(e),
+            _ => None,
+        }
+    }
+}
 
 impl Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
@@ -276,8 +291,9 @@ pub trait GuestMemoryRegion: Bytes<MemoryRegionAddress, E = Error> {
     /// # use vm_memory::volatile_memory::{VolatileMemory, VolatileSlice, VolatileRef};
     /// #
     /// let region = MmapRegion::new(0x400).expect("Could not create mmap region");
-    /// let region =
-    ///     GuestRegionMmap::new(region, GuestAddress(0x0)).expect("Could not create guest memory");
+    /// # let pid = std::process::id() as i32;
+    /// let region = GuestRegionMmap::new(pid, region, GuestAddress(0x0))
+    ///     .expect("Could not create guest memory");
     /// let slice = region
     ///     .as_volatile_slice()
     ///     .expect("Could not get volatile slice");
@@ -305,7 +321,8 @@ pub trait GuestMemoryRegion: Bytes<MemoryRegionAddress, E = Error> {
     /// # {
     /// #   use vm_memory::{GuestAddress, GuestMemory, GuestMemoryMmap, GuestRegionMmap};
     /// let addr = GuestAddress(0x1000);
-    /// let mem = GuestMemoryMmap::from_ranges(&[(addr, 0x1000)]).unwrap();
+    /// # let pid = std::process::id() as i32;
+    /// let mem = GuestMemoryMmap::from_ranges(pid, &[(addr, 0x1000)]).unwrap();
     /// let r = mem.find_region(addr).unwrap();
     /// assert_eq!(r.is_hugetlbfs(), None);
     /// # }
@@ -314,6 +331,12 @@ pub trait GuestMemoryRegion: Bytes<MemoryRegionAddress, E = Error> {
     fn is_hugetlbfs(&self) -> Option<bool> {
         None
     }
+
+    /// Returns the bitmap of the pages written through the region, or `None` if writes aren't
+    /// tracked. See [`DirtyBitmap`](../bitmap/struct.DirtyBitmap.html).
+    fn dirty_bitmap(&self) -> Option<&DirtyBitmap> {
+        None
+    }
 }
 
 /// `GuestAddressSpace` provides a way to retrieve a `GuestMemory` object.
@@ -351,7 +374,8 @@ pub trait GuestMemoryRegion: Bytes<MemoryRegionAddress, E = Error> {
 ///
 /// fn get_mmap() -> GuestMemoryMmap {
 ///     let start_addr = GuestAddress(0x1000);
-///     GuestMemoryMmap::from_ranges(&vec![(start_addr, 0x400)])
+///     # let pid = std::process::id() as i32;
+///     GuestMemoryMmap::from_ranges(pid, &vec![(start_addr, 0x400)])
 ///         .expect("Could not create guest memory")
 /// }
 ///
@@ -515,7 +539,8 @@ pub trait GuestMemory {
     /// #
     /// let start_addr1 = GuestAddress(0x0);
     /// let start_addr2 = GuestAddress(0x400);
-    /// let gm = GuestMemoryMmap::from_ranges(&vec![(start_addr1, 1024), (start_addr2, 2048)])
+    /// # let pid = std::process::id() as i32;
+    /// let gm = GuestMemoryMmap::from_ranges(pid, &vec![(start_addr1, 1024), (start_addr2, 2048)])
     ///     .expect("Could not create guest memory");
     ///
     /// let total_size = gm
@@ -551,7 +576,8 @@ pub trait GuestMemory {
     /// #
     /// let start_addr1 = GuestAddress(0x0);
     /// let start_addr2 = GuestAddress(0x400);
-    /// let gm = GuestMemoryMmap::from_ranges(&vec![(start_addr1, 1024), (start_addr2, 2048)])
+    /// # let pid = std::process::id() as i32;
+    /// let gm = GuestMemoryMmap::from_ranges(pid, &vec![(start_addr1, 1024), (start_addr2, 2048)])
     ///     .expect("Could not create guest memory");
     ///
     /// let total_size = gm.map_and_fold(0, |(_, region)| region.len() / 1024, |acc, size| acc + size);
@@ -577,7 +603,8 @@ pub trait GuestMemory {
     /// # use vm_memory::{Address, GuestAddress, GuestMemory, GuestMemoryMmap};
     /// #
     /// let start_addr = GuestAddress(0x1000);
-    /// let mut gm = GuestMemoryMmap::from_ranges(&vec![(start_addr, 0x400)])
+    /// # let pid = std::process::id() as i32;
+    /// let mut gm = GuestMemoryMmap::from_ranges(pid, &vec![(start_addr, 0x400)])
     ///     .expect("Could not create guest memory");
     ///
     /// assert_eq!(start_addr.checked_add(0x3ff), Some(gm.last_addr()));
@@ -689,7 +716,8 @@ pub trait GuestMemory {
     /// # use vm_memory::{GuestAddress, GuestMemory, GuestMemoryMmap};
     /// #
     /// # let start_addr = GuestAddress(0x1000);
-    /// # let mut gm = GuestMemoryMmap::from_ranges(&vec![(start_addr, 0x500)])
+    /// # let pid = std::process::id() as i32;
+    /// # let mut gm = GuestMemoryMmap::from_ranges(pid, &vec![(start_addr, 0x500)])
     /// #    .expect("Could not create guest memory");
     /// #
     /// let addr = gm
@@ -715,12 +743,149 @@ pub trait GuestMemory {
             .ok_or(Error::InvalidGuestAddress(addr))
             .and_then(|(r, addr)| r.get_slice(addr, count))
     }
+
+    /// Atomically stores `new` at `addr` if the value there is `current`.
+    ///
+    /// Returns the previous value, wrapped in `Ok` if it was replaced and in `Err` otherwise.
+    /// Only memory which is mapped in the current process can be accessed atomically, see
+    /// [`VolatileSlice::compare_exchange`](struct.VolatileSlice.html#method.compare_exchange).
+    fn compare_exchange<T: AtomicAccess>(
+        &self,
+        current: T,
+        new: T,
+        addr: GuestAddress,
+        success: Ordering,
+        failure: Ordering,
+    ) -> Result<result::Result<T, T>>
+    where
+        T::A: AtomicCompareExchange,
+    {
+        self.get_slice(addr, size_of::<T>())?
+            .compare_exchange(current, new, 0, success, failure)
+            .map_err(Into::into)
+    }
+
+    /// Returns `true` if the page containing `addr` was written, see
+    /// [`GuestMemoryRegion::dirty_bitmap`](trait.GuestMemoryRegion.html#method.dirty_bitmap).
+    ///
+    /// Pages of regions which don't track writes are never dirty.
+    fn is_dirty(&self, addr: GuestAddress) -> bool {
+        self.to_region_addr(addr)
+            .and_then(|(r, addr)| Some(r.dirty_bitmap()?.is_dirty(addr.raw_value() as usize)))
+            .unwrap_or(false)
+    }
+
+    /// Marks the pages overlapping the `len` bytes at `addr` as dirty, in the regions which track
+    /// writes.
+    ///
+    /// This is needed for writes which bypass `Bytes` and `VolatileSlice`, e.g. through
+    /// references or a transport.
+    fn mark_dirty(&self, addr: GuestAddress, len: usize) {
+        let _ = self.try_access(len, addr, |_, count, caddr, region| -> Result<usize> {
+            if let Some(bitmap) = region.dirty_bitmap() {
+                bitmap.mark(caddr.raw_value() as usize, count);
+            }
+            Ok(count)
+        });
+    }
+
+    /// Returns the ranges of dirty pages of all regions which track writes, as `(address,
+    /// length)` sorted by address.
+    fn dirty_ranges(&self) -> Vec<(GuestAddress, usize)> {
+        collect_dirty_ranges(self, DirtyBitmap::dirty_ranges)
+    }
+
+    /// Returns the ranges of dirty pages like `dirty_ranges` and marks them as clean, see
+    /// [`DirtyBitmap::take_dirty_ranges`](../bitmap/struct.DirtyBitmap.html#method.take_dirty_ranges).
+    fn take_dirty_ranges(&self) -> Vec<(GuestAddress, usize)> {
+        collect_dirty_ranges(self, DirtyBitmap::take_dirty_ranges)
+    }
+}
+
+/// Collects the ranges `f` returns for the bitmaps of the regions of `mem`, as guest addresses.
+fn collect_dirty_ranges<M, F>(mem: &M, f: F) -> Vec<(GuestAddress, usize)>
+where
+    M: GuestMemory + ?Sized,
+    F: Fn(&DirtyBitmap) -> DirtyRanges,
+{
+    let mut ranges = Vec::new();
+    for region in mem.iter() {
+        if let Some(bitmap) = region.dirty_bitmap() {
+            let base = region.start_addr();
+            ranges.extend(f(bitmap).map(|(offset, len)| (base.unchecked_add(offset as u64), len)));
+        }
+    }
+    ranges
+}
+
+/// Collects the remote ranges backing `count` bytes at `addr`, as visited by `try_access`.
+///
+/// Returns `None` unless all of them are accessed through the same remote transport, in which
+/// case the whole access can be done with a single vectored call instead of one call per region.
+pub(crate) fn remote_iovecs<M: GuestMemory>(
+    mem: &M,
+    count: usize,
+    addr: GuestAddress,
+) -> Option<(&dyn Transport, Vec<RemoteIoVec>)> {
+    // Transports are compared by address, as the slices can't outlive the closure.
+    let id = |t: &dyn Transport| t as *const dyn Transport as *const u8 as usize;
+    let mut transport = None;
+    let mut remote = true;
+    let mut iovecs: Vec<RemoteIoVec> = Vec::new();
+    let res = mem.try_access(
+        count,
+        addr,
+        |_offset, len, caddr, region| -> Result<usize> {
+            let slice = match region.get_slice(caddr, len) {
+                Ok(slice) => slice,
+                Err(_) => {
+                    remote = false;
+                    return Ok(0);
+                }
+            };
+            match (slice.transport().map(id), transport) {
+                (Some(t), None) => transport = Some(t),
+                (Some(t), Some(u)) if t == u => {}
+                _ => {
+                    remote = false;
+                    return Ok(0);
+                }
+            }
+            let base = slice.as_ptr() as usize;
+            match iovecs.last_mut() {
+                Some(last) if last.base + last.len == base => last.len += len,
+                _ => iovecs.push(RemoteIoVec { base, len }),
+            }
+            Ok(len)
+        },
+    );
+    match (res, transport) {
+        (Ok(n), Some(t)) if remote && n > 0 => {
+            let first = mem.get_slice(addr, 0).ok()?.transport()?;
+            if id(first) == t {
+                Some((first, iovecs))
+            } else {
+                None
+            }
+        }
+        _ => None,
+    }
 }
 
 impl<T: GuestMemory> Bytes<GuestAddress> for T {
     type E = Error;
 
     fn write(&self, buf: &[u8], addr: GuestAddress) -> Result<usize> {
+        if let Some((transport, iovecs)) = remote_iovecs(self, buf.len(), addr) {
+            let res = transport.writev(&[buf], &iovecs);
+            if let Some(stats) = transport.stats() {
+                stats.notify(AccessKind::Write, addr, buf.len(), &res);
+            }
+            if let Ok(written) = res {
+                self.mark_dirty(addr, written);
+            }
+            return res.map_err(Error::RemoteMemError);
+        }
         self.try_access(
             buf.len(),
             addr,
@@ -731,6 +896,14 @@ impl<T: GuestMemory> Bytes<GuestAddress> for T {
     }
 
     fn read(&self, buf: &mut [u8], addr: GuestAddress) -> Result<usize> {
+        if let Some((transport, iovecs)) = remote_iovecs(self, buf.len(), addr) {
+            let len = buf.len();
+            let res = transport.readv(&mut [buf], &iovecs);
+            if let Some(stats) = transport.stats() {
+                stats.notify(AccessKind::Read, addr, len, &res);
+            }
+            return res.map_err(Error::RemoteMemError);
+        }
         self.try_access(
             buf.len(),
             addr,
@@ -750,7 +923,8 @@ impl<T: GuestMemory> Bytes<GuestAddress> for T {
     /// # use vm_memory::{Bytes, GuestAddress, mmap::GuestMemoryMmap};
     /// #
     /// # let start_addr = GuestAddress(0x1000);
-    /// # let mut gm = GuestMemoryMmap::from_ranges(&vec![(start_addr, 0x400)])
+    /// # let pid = std::process::id() as i32;
+    /// # let mut gm = GuestMemoryMmap::from_ranges(pid, &vec![(start_addr, 0x400)])
     /// #    .expect("Could not create guest memory");
     /// #
     /// gm.write_slice(&[1, 2, 3, 4, 5], start_addr)
@@ -778,7 +952,8 @@ impl<T: GuestMemory> Bytes<GuestAddress> for T {
     /// # use vm_memory::{Bytes, GuestAddress, mmap::GuestMemoryMmap};
     /// #
     /// let start_addr = GuestAddress(0x1000);
-    /// let mut gm = GuestMemoryMmap::from_ranges(&vec![(start_addr, 0x400)])
+    /// # let pid = std::process::id() as i32;
+    /// let mut gm = GuestMemoryMmap::from_ranges(pid, &vec![(start_addr, 0x400)])
     ///     .expect("Could not create guest memory");
     /// let buf = &mut [0u8; 16];
     ///
@@ -809,7 +984,8 @@ impl<T: GuestMemory> Bytes<GuestAddress> for T {
     /// # use std::path::Path;
     /// #
     /// # let start_addr = GuestAddress(0x1000);
-    /// # let gm = GuestMemoryMmap::from_ranges(&vec![(start_addr, 0x400)])
+    /// # let pid = std::process::id() as i32;
+    /// # let gm = GuestMemoryMmap::from_ranges(pid, &vec![(start_addr, 0x400)])
     /// #    .expect("Could not create guest memory");
     /// # let addr = GuestAddress(0x1010);
     /// # let mut file = if cfg!(unix) {
@@ -840,13 +1016,17 @@ impl<T: GuestMemory> Bytes<GuestAddress> for T {
                 // This is safe cause `start` and `len` are within the `region`.
                 let start = caddr.raw_value() as usize;
                 let end = start + len;
-                loop {
+                let res = loop {
                     match src.read(&mut dst[start..end]) {
                         Ok(n) => break Ok(n),
                         Err(ref e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
                         Err(e) => break Err(Error::IOError(e)),
                     }
+                };
+                if let (Ok(n), Some(bitmap)) = (&res, region.dirty_bitmap()) {
+                    bitmap.mark(start, *n);
                 }
+                res
             } else {
                 let len = std::cmp::min(len, MAX_ACCESS_CHUNK);
                 let mut buf = vec![0u8; len].into_boxed_slice();
@@ -892,7 +1072,8 @@ impl<T: GuestMemory> Bytes<GuestAddress> for T {
     /// # use vm_memory::{Bytes, GuestAddress, GuestMemoryMmap};
     /// #
     /// # let start_addr = GuestAddress(0x1000);
-    /// # let gm = GuestMemoryMmap::from_ranges(&vec![(start_addr, 1024)])
+    /// # let pid = std::process::id() as i32;
+    /// # let gm = GuestMemoryMmap::from_ranges(pid, &vec![(start_addr, 1024)])
     /// #    .expect("Could not create guest memory");
     /// # let mut file = if cfg!(unix) {
     /// # use std::fs::OpenOptions;
@@ -957,7 +1138,8 @@ impl<T: GuestMemory> Bytes<GuestAddress> for T {
     /// # use vm_memory::{Bytes, GuestAddress, GuestMemoryMmap};
     /// #
     /// # let start_addr = GuestAddress(0x1000);
-    /// # let gm = GuestMemoryMmap::from_ranges(&vec![(start_addr, 1024)])
+    /// # let pid = std::process::id() as i32;
+    /// # let gm = GuestMemoryMmap::from_ranges(pid, &vec![(start_addr, 1024)])
     /// #    .expect("Could not create guest memory");
     /// # let mut file = if cfg!(unix) {
     /// # use std::fs::OpenOptions;
@@ -1010,6 +1192,8 @@ mod tests {
     #[cfg(feature = "backend-mmap")]
     use crate::bytes::ByteValued;
     #[cfg(feature = "backend-mmap")]
+    use crate::test_utils::local_pid;
+    #[cfg(feature = "backend-mmap")]
     use crate::{GuestAddress, GuestMemoryMmap};
     #[cfg(feature = "backend-mmap")]
     use std::io::Cursor;
@@ -1044,7 +1228,9 @@ mod tests {
     fn checked_read_from() {
         let start_addr1 = GuestAddress(0x0);
         let start_addr2 = GuestAddress(0x40);
-        let mem = GuestMemoryMmap::from_ranges(&[(start_addr1, 64), (start_addr2, 64)]).unwrap();
+        let mem =
+            GuestMemoryMmap::from_ranges(local_pid(), &[(start_addr1, 64), (start_addr2, 64)])
+                .unwrap();
         let image = make_image(0x80);
         let offset = GuestAddress(0x30);
         let count: usize = 0x20;
@@ -1115,10 +1301,13 @@ mod tests {
         // The address where we start writing/reading a Data<T> value.
         let data_start = GuestAddress((region_len - mem::size_of::<T>()) as u64);
 
-        let mem = GuestMemoryMmap::from_ranges(&[
-            (start, region_len),
-            (start.unchecked_add(region_len as u64), region_len),
-        ])
+        let mem = GuestMemoryMmap::from_ranges(
+            local_pid(),
+            &[
+                (start, region_len),
+                (start.unchecked_add(region_len as u64), region_len),
+            ],
+        )
         .unwrap();
 
         // Need to clone this and move it into the new thread we create.
@@ -1187,7 +1376,7 @@ mod tests {
         unsafe impl ByteValued for ZeroSizedStruct {}
 
         let addr = GuestAddress(0x1000);
-        let mem = GuestMemoryMmap::from_ranges(&[(addr, 0x1000)]).unwrap();
+        let mem = GuestMemoryMmap::from_ranges(local_pid(), &[(addr, 0x1000)]).unwrap();
         let obj = ZeroSizedStruct::default();
         let mut image = make_image(0x80);
 
@@ -1220,7 +1409,7 @@ mod tests {
     #[test]
     fn test_atomic_accesses() {
         let addr = GuestAddress(0x1000);
-        let mem = GuestMemoryMmap::from_ranges(&[(addr, 0x1000)]).unwrap();
+        let mem = GuestMemoryMmap::from_ranges(local_pid(), &[(addr, 0x1000)]).unwrap();
         let bad_addr = addr.unchecked_add(0x1000);
 
         crate::bytes::tests::check_atomic_accesses(mem, addr, bad_addr);
@@ -1231,7 +1420,7 @@ mod tests {
     #[test]
     fn test_guest_memory_mmap_is_hugetlbfs() {
         let addr = GuestAddress(0x1000);
-        let mem = GuestMemoryMmap::from_ranges(&[(addr, 0x1000)]).unwrap();
+        let mem = GuestMemoryMmap::from_ranges(local_pid(), &[(addr, 0x1000)]).unwrap();
         let r = mem.find_region(addr).unwrap();
         assert_eq!(r.is_hugetlbfs(), None);
     }
@@ -0,0 +1,383 @@
+//! Layouts of guest RAM around memory holes.
+//!
+//! Guest RAM usually can't be one contiguous range, e.g. on x86 the legacy VGA hole and the
+//! 32-bit PCI hole split it in three. A [`LayoutBuilder`](struct.LayoutBuilder.html) places a
+//! given amount of RAM from guest address 0 upwards around reserved and MMIO ranges. The
+//! resulting [`Layout`](struct.Layout.html) has the ranges for
+//! `GuestMemoryMmap::from_ranges_with_files` and an E820 map for the boot loader. Reserved ranges
+//! are in the E820 map as `E820_RESERVED`, while MMIO ranges are left out of it, as the guest
+//! learns about them from the devices, e.g. the PCI host bridge.
+//!
+//! # Examples
+//!
+//! ```
+//! # use vm_memory::GuestAddress;
+//! # use vm_memory::layout::{E820Entry, LayoutBuilder, E820_RAM, E820_RESERVED};
+//! let layout = LayoutBuilder::new(0x1_0000_0000)
+//!     .reserve(GuestAddress(0xa0000), 0x60000)
+//!     .mmio(GuestAddress(0xc000_0000), 0x4000_0000)
+//!     .build()
+//!     .unwrap();
+//!
+//! let ranges: Vec<_> = layout.ranges().iter().map(|r| (r.0, r.1)).collect();
+//! assert_eq!(
+//!     ranges,
+//!     vec![
+//!         (GuestAddress(0), 0xa0000),
+//!         (GuestAddress(0x100000), 0xbff0_0000),
+//!         (GuestAddress(0x1_0000_0000), 0x4006_0000),
+//!     ]
+//! );
+//! assert_eq!(layout.e820()[1], E820Entry::new(0xa0000, 0x60000, E820_RESERVED));
+//! assert_eq!(layout.e820()[2], E820Entry::new(0x100000, 0xbff0_0000, E820_RAM));
+//! // The PCI hole isn't in the map.
+//! assert_eq!(layout.e820().len(), 4);
+//!
+//! # #[cfg(feature = "backend-mmap")]
+//! # {
+//! # use vm_memory::{GuestMemory, GuestMemoryMmap};
+//! # let pid = std::process::id() as i32;
+//! let gm = GuestMemoryMmap::from_ranges_with_files(pid, layout.ranges()).unwrap();
+//! assert_eq!(gm.num_regions(), 3);
+//! # }
+//! ```
+
+use std::error;
+use std::fmt;
+use std::result;
+
+use crate::bytes::ByteValued;
+use crate::guest_memory::{FileOffset, GuestAddress, GuestUsize};
+
+/// Type of E820 entries of usable RAM.
+pub const E820_RAM: u32 = 1;
+/// Type of E820 entries of reserved ranges.
+pub const E820_RESERVED: u32 = 2;
+
+/// Errors of building a `Layout`.
+#[derive(Debug)]
+pub enum Error {
+    /// The reserved or MMIO range at this guest address extends past the end of the address
+    /// space.
+    InvalidReservedRange(GuestAddress),
+    /// The RAM doesn't fit into the address space around the reserved and MMIO ranges.
+    RamTooLarge(GuestUsize),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::InvalidReservedRange(addr) => write!(
+                f,
+                "the range at 0x{:x} extends past the end of the address space",
+                addr.0
+            ),
+            Error::RamTooLarge(size) => write!(
+                f,
+                "0x{:x} bytes of RAM don't fit around the reserved ranges",
+                size
+            ),
+        }
+    }
+}
+
+impl error::Error for Error {}
+
+/// A specialized `Result` type for building layouts.
+pub type Result<T> = result::Result<T, Error>;
+
+/// An entry of an E820 map, as in the `e820_table` of the Linux boot protocol.
+#[repr(C, packed)]
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct E820Entry {
+    /// Guest address of the range.
+    pub addr: u64,
+    /// Length of the range.
+    pub size: u64,
+    /// Type of the range, e.g. `E820_RAM`.
+    pub kind: u32,
+}
+
+// It is safe because E820Entry is a packed struct of integers.
+unsafe impl ByteValued for E820Entry {}
+
+impl E820Entry {
+    /// Creates an entry for `size` bytes at `addr` of type `kind`.
+    pub fn new(addr: u64, size: u64, kind: u32) -> Self {
+        E820Entry { addr, size, kind }
+    }
+}
+
+/// Builds a `Layout` of guest RAM around reserved and MMIO ranges, see the
+/// [module documentation](index.html).
+#[derive(Clone, Debug)]
+pub struct LayoutBuilder {
+    ram_size: GuestUsize,
+    reserved: Vec<(GuestAddress, GuestUsize)>,
+    mmio: Vec<(GuestAddress, GuestUsize)>,
+    file: Option<FileOffset>,
+}
+
+/// Sorts `ranges` and merges the overlapping and adjacent ones, dropping empty ones.
+fn merge(ranges: &[(GuestAddress, GuestUsize)]) -> Result<Vec<(GuestAddress, GuestUsize)>> {
+    let mut merged: Vec<(GuestAddress, GuestUsize)> = Vec::new();
+    let mut sorted = ranges.to_vec();
+    sorted.sort_by_key(|r| r.0);
+    for (base, len) in sorted.into_iter().filter(|r| r.1 != 0) {
+        let end = base
+            .0
+            .checked_add(len)
+            .ok_or(Error::InvalidReservedRange(base))?;
+        match merged.last_mut() {
+            Some(last) if base.0 <= last.0 .0 + last.1 => {
+                last.1 = last.1.max(end - last.0 .0);
+            }
+            _ => merged.push((base, len)),
+        }
+    }
+    Ok(merged)
+}
+
+impl LayoutBuilder {
+    /// Creates a builder for `ram_size` bytes of RAM starting at guest address 0.
+    pub fn new(ram_size: GuestUsize) -> Self {
+        LayoutBuilder {
+            ram_size,
+            reserved: Vec::new(),
+            mmio: Vec::new(),
+            file: None,
+        }
+    }
+
+    /// Keeps the `len` bytes at `base` free of RAM and marks them as `E820_RESERVED`, e.g. for
+    /// the legacy VGA and BIOS areas.
+    ///
+    /// Reserved ranges may be added in any order and may overlap.
+    pub fn reserve(mut self, base: GuestAddress, len: GuestUsize) -> Self {
+        self.reserved.push((base, len));
+        self
+    }
+
+    /// Keeps the `len` bytes at `base` free of RAM for MMIO, e.g. the 32-bit PCI hole.
+    ///
+    /// Unlike reserved ranges, MMIO ranges aren't in the E820 map. They may be added in any order
+    /// and may overlap each other and reserved ranges.
+    pub fn mmio(mut self, base: GuestAddress, len: GuestUsize) -> Self {
+        self.mmio.push((base, len));
+        self
+    }
+
+    /// Backs the RAM by `file`, with the ranges following each other in the file from the offset
+    /// of `file`.
+    pub fn with_file(mut self, file: FileOffset) -> Self {
+        self.file = Some(file);
+        self
+    }
+
+    /// Places the RAM at the lowest guest addresses outside of the reserved and MMIO ranges.
+    pub fn build(&self) -> Result<Layout> {
+        let reserved = merge(&self.reserved)?;
+        let mmio = merge(&self.mmio)?;
+        let holes = merge(&[reserved.as_slice(), mmio.as_slice()].concat())?;
+
+        let mut placed = Vec::new();
+        let mut addr = 0;
+        let mut left = self.ram_size;
+        for &(base, len) in holes.iter() {
+            if left == 0 {
+                break;
+            }
+            if addr < base.0 {
+                let size = left.min(base.0 - addr);
+                placed.push((GuestAddress(addr), size));
+                left -= size;
+            }
+            addr = addr.max(base.0 + len);
+        }
+        if left != 0 {
+            addr.checked_add(left)
+                .ok_or(Error::RamTooLarge(self.ram_size))?;
+            placed.push((GuestAddress(addr), left));
+        }
+
+        let mut offset = 0;
+        let ram = placed
+            .into_iter()
+            .map(|(base, size)| {
+                let file = self
+                    .file
+                    .as_ref()
+                    .map(|file| FileOffset::from_arc(file.arc().clone(), file.start() + offset));
+                offset += size;
+                (base, size as usize, file)
+            })
+            .collect();
+        Ok(Layout {
+            ram,
+            reserved,
+            mmio,
+        })
+    }
+}
+
+/// Guest RAM, reserved and MMIO ranges, built by a `LayoutBuilder`.
+#[derive(Clone, Debug)]
+pub struct Layout {
+    ram: Vec<(GuestAddress, usize, Option<FileOffset>)>,
+    reserved: Vec<(GuestAddress, GuestUsize)>,
+    mmio: Vec<(GuestAddress, GuestUsize)>,
+}
+
+impl Layout {
+    /// Returns the ranges of RAM, sorted by address, as (Address, Size, Option<FileOffset>)
+    /// tuples for `GuestMemoryMmap::from_ranges_with_files`.
+    pub fn ranges(&self) -> &[(GuestAddress, usize, Option<FileOffset>)] {
+        &self.ram
+    }
+
+    /// Returns the reserved ranges, sorted by address, with overlapping and adjacent ones merged.
+    pub fn reserved(&self) -> &[(GuestAddress, GuestUsize)] {
+        &self.reserved
+    }
+
+    /// Returns the MMIO ranges, sorted by address, with overlapping and adjacent ones merged.
+    pub fn mmio(&self) -> &[(GuestAddress, GuestUsize)] {
+        &self.mmio
+    }
+
+    /// Returns an E820 map of the RAM and the reserved ranges, sorted by address. MMIO ranges
+    /// aren't in the map.
+    pub fn e820(&self) -> Vec<E820Entry> {
+        let ram = self
+            .ram
+            .iter()
+            .map(|r| E820Entry::new(r.0 .0, r.1 as u64, E820_RAM));
+        let reserved = self
+            .reserved
+            .iter()
+            .map(|r| E820Entry::new(r.0 .0, r.1, E820_RESERVED));
+        let mut map: Vec<E820Entry> = ram.chain(reserved).collect();
+        map.sort_by_key(|e| e.addr);
+        map
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::mem::size_of;
+
+    use vmm_sys_util::tempfile::TempFile;
+
+    fn ram(layout: &Layout) -> Vec<(u64, usize)> {
+        layout.ranges().iter().map(|r| (r.0 .0, r.1)).collect()
+    }
+
+    #[test]
+    fn test_layout() {
+        // Unsorted and overlapping reserved ranges, one of them empty.
+        let layout = LayoutBuilder::new(0x10000)
+            .reserve(GuestAddress(0x6000), 0x1000)
+            .reserve(GuestAddress(0x2000), 0x2000)
+            .reserve(GuestAddress(0x3000), 0x2000)
+            .reserve(GuestAddress(0x5000), 0x800)
+            .reserve(GuestAddress(0x8000), 0)
+            .build()
+            .unwrap();
+        assert_eq!(
+            layout.reserved(),
+            &[
+                (GuestAddress(0x2000), 0x3800),
+                (GuestAddress(0x6000), 0x1000)
+            ]
+        );
+        assert_eq!(
+            ram(&layout),
+            vec![(0, 0x2000), (0x5800, 0x800), (0x7000, 0xd800)]
+        );
+        assert!(layout.ranges().iter().all(|r| r.2.is_none()));
+        let e820 = layout.e820();
+        assert_eq!(e820.len(), 5);
+        assert_eq!(e820[1], E820Entry::new(0x2000, 0x3800, E820_RESERVED));
+        assert_eq!(e820[4], E820Entry::new(0x7000, 0xd800, E820_RAM));
+        assert_eq!(size_of::<E820Entry>(), 20);
+
+        // The RAM ends below the reserved range, which is still in the map.
+        let layout = LayoutBuilder::new(0x1000)
+            .reserve(GuestAddress(0), 0x800)
+            .reserve(GuestAddress(0x4000), 0x1000)
+            .build()
+            .unwrap();
+        assert_eq!(ram(&layout), vec![(0x800, 0x1000)]);
+        assert_eq!(layout.e820().len(), 3);
+
+        assert!(matches!(
+            LayoutBuilder::new(0x1000)
+                .reserve(GuestAddress(u64::MAX), 2)
+                .build(),
+            Err(Error::InvalidReservedRange(GuestAddress(u64::MAX)))
+        ));
+        assert!(matches!(
+            LayoutBuilder::new(u64::MAX)
+                .reserve(GuestAddress(0), 0x1000)
+                .build(),
+            Err(Error::RamTooLarge(u64::MAX))
+        ));
+    }
+
+    #[test]
+    fn test_layout_mmio() {
+        // The MMIO range overlaps a reserved one, and is merged with it only for placing RAM.
+        let layout = LayoutBuilder::new(0x4000)
+            .reserve(GuestAddress(0x1000), 0x1000)
+            .mmio(GuestAddress(0x3000), 0x1000)
+            .mmio(GuestAddress(0x1800), 0x1000)
+            .build()
+            .unwrap();
+        assert_eq!(layout.reserved(), &[(GuestAddress(0x1000), 0x1000)]);
+        assert_eq!(
+            layout.mmio(),
+            &[
+                (GuestAddress(0x1800), 0x1000),
+                (GuestAddress(0x3000), 0x1000)
+            ]
+        );
+        assert_eq!(
+            ram(&layout),
+            vec![(0, 0x1000), (0x2800, 0x800), (0x4000, 0x2800)]
+        );
+        assert_eq!(
+            layout.e820(),
+            vec![
+                E820Entry::new(0, 0x1000, E820_RAM),
+                E820Entry::new(0x1000, 0x1000, E820_RESERVED),
+                E820Entry::new(0x2800, 0x800, E820_RAM),
+                E820Entry::new(0x4000, 0x2800, E820_RAM),
+            ]
+        );
+
+        assert!(matches!(
+            LayoutBuilder::new(0x1000)
+                .mmio(GuestAddress(u64::MAX), 2)
+                .build(),
+            Err(Error::InvalidReservedRange(GuestAddress(u64::MAX)))
+        ));
+    }
+
+    #[test]
+    fn test_layout_file() {
+        let file = TempFile::new().unwrap().into_file();
+        let layout = LayoutBuilder::new(0x3000)
+            .reserve(GuestAddress(0x1000), 0x1000)
+            .with_file(FileOffset::new(file, 0x4000))
+            .build()
+            .unwrap();
+        let offsets: Vec<u64> = layout
+            .ranges()
+            .iter()
+            .map(|r| r.2.as_ref().unwrap().start())
+            .collect();
+        assert_eq!(offsets, vec![0x4000, 0x5000]);
+        assert_eq!(ram(&layout), vec![(0, 0x1000), (0x2000, 0x2000)]);
+    }
+}
@@ -29,11 +29,21 @@ pub mod atomic;
 pub use atomic::{GuestMemoryAtomic, GuestMemoryLoadGuard};
 
 mod atomic_integer;
-pub use atomic_integer::AtomicInteger;
+pub use atomic_integer::{AtomicCompareExchange, AtomicInteger};
+
+pub mod bitmap;
+pub use bitmap::DirtyBitmap;
 
 pub mod bytes;
 pub use bytes::{AtomicAccess, ByteValued, Bytes};
 
+#[cfg(feature = "backend-mmap")]
+pub mod cache;
+#[cfg(feature = "backend-mmap")]
+pub use cache::CachedMemory;
+
+pub mod core_dump;
+
 pub mod endian;
 pub use endian::{Be16, Be32, Be64, BeSize, Le16, Le32, Le64, LeSize};
 
@@ -46,18 +56,42 @@ pub use guest_memory::{
 #[cfg(all(feature = "backend-mmap", unix))]
 mod mmap_unix;
 
+#[cfg(all(feature = "backend-mmap", unix))]
+mod mmap_remote;
+
 #[cfg(all(feature = "backend-mmap", windows))]
 mod mmap_windows;
 
 #[cfg(feature = "backend-mmap")]
 pub mod mmap;
 #[cfg(feature = "backend-mmap")]
-pub use mmap::{Error, GuestMemoryMmap, GuestRegionMmap, MmapRegion};
+pub use mmap::{Error, GuestMemoryMmap, GuestRegionMmap, MmapRegion, RemoteRegion};
+
+pub mod layout;
+pub use layout::LayoutBuilder;
+
+pub mod liveness;
+
+pub mod proc_maps;
 
 pub mod remote_mem;
 
+#[cfg(feature = "backend-mmap")]
+pub mod snapshot;
+
+pub mod stats;
+
+#[cfg(test)]
+mod test_utils;
+
 pub mod volatile_memory;
 pub use volatile_memory::{
     Error as VolatileMemoryError, Result as VolatileMemoryResult, VolatileArrayRef, VolatileMemory,
     VolatileRef, VolatileSlice,
 };
+
+pub mod worker_pool;
+pub use worker_pool::WorkerPool;
+
+pub mod write_buffer;
+pub use write_buffer::WriteBuffer;
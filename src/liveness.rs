@@ -0,0 +1,391 @@
+//! Detection of the hypervisor process exiting or replacing its address space.
+//!
+//! Once the hypervisor is gone, every access to its memory fails after a system call, and after
+//! an `execve` the guest memory isn't where the regions say anymore. Wrapping the transport of a
+//! guest memory object in a [`Watched`](struct.Watched.html) transport marks the process as dead
+//! as soon as an access fails because of that or `Watched::is_alive` notices it. From then on,
+//! all accesses fail with `remote_mem::Error::Dead` without a system call.
+//!
+//! A `GuestMemoryAtomic` can then be pointed to the memory of a new hypervisor process with
+//! `GuestMemoryExclusiveGuard::replace`.
+//!
+//! Independently of that, a pid alone doesn't identify the hypervisor: once it died, a new,
+//! unrelated process may get the same pid. The transports created by
+//! `remote_mem::default_transport` therefore hold an [`Identity`](struct.Identity.html) of the
+//! process and check it before every access, which then fails with `remote_mem::Error::Identity`
+//! instead of accessing the memory of the wrong process.
+
+use std::fs::{self, File};
+use std::io;
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use nix::errno::Errno;
+use nix::poll::{poll, PollFd, PollFlags};
+use nix::sys::uio::RemoteIoVec;
+use nix::unistd::Pid;
+
+use crate::remote_mem::{Error, ProcMem, Transport};
+use crate::stats::Stats;
+
+/// Opens a pidfd for `pid`, which becomes readable once the process exited.
+fn pidfd_open(pid: Pid) -> nix::Result<File> {
+    // Safe because the system call doesn't access memory of ours.
+    let fd = unsafe { libc::syscall(libc::SYS_pidfd_open, pid.as_raw(), 0) };
+    if fd < 0 {
+        return Err(nix::Error::Sys(Errno::last()));
+    }
+    // Safe because the file descriptor was just created and is owned by nobody else.
+    Ok(unsafe { File::from_raw_fd(fd as i32) })
+}
+
+/// Returns `true` if `pidfd` is readable, i.e. its process exited.
+fn has_exited(pidfd: &File) -> bool {
+    let mut fds = [PollFd::new(pidfd.as_raw_fd(), PollFlags::POLLIN)];
+    matches!(poll(&mut fds, 0), Ok(n) if n > 0)
+}
+
+/// Reads the start time of process `pid` from `/proc/<pid>/stat`, in clock ticks after boot.
+fn start_time(pid: Pid) -> io::Result<u64> {
+    let stat = fs::read_to_string(format!("/proc/{}/stat", pid))?;
+    // The name in parentheses may contain spaces and parentheses itself. The start time is the
+    // 22nd field, the 20th after the name.
+    stat.rsplit_once(')')
+        .and_then(|(_, fields)| fields.split_whitespace().nth(19)?.parse().ok())
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, stat.clone()))
+}
+
+/// The identity of a process, which unlike its pid isn't reused once the process is gone.
+///
+/// It's a pidfd where the kernel supports them (Linux 5.3), and the start time of the process
+/// otherwise.
+#[derive(Debug)]
+pub struct Identity {
+    pid: Pid,
+    pidfd: Option<File>,
+    start_time: Option<u64>,
+}
+
+impl Identity {
+    /// Returns the identity of the process which currently has `pid`.
+    ///
+    /// If there is no such process, the identity never verifies.
+    pub fn of(pid: Pid) -> Self {
+        let pidfd = pidfd_open(pid)
+            .map_err(|e| log::debug!("no pidfd for process {}: {}", pid, e))
+            .ok();
+        let start_time = match pidfd {
+            Some(_) => None,
+            None => start_time(pid)
+                .map_err(|e| log::warn!("cannot identify process {}: {}", pid, e))
+                .ok(),
+        };
+        Identity {
+            pid,
+            pidfd,
+            start_time,
+        }
+    }
+
+    /// Returns the pid of the process.
+    pub fn pid(&self) -> Pid {
+        self.pid
+    }
+
+    /// Checks that `pid` still belongs to the process, which costs a system call with a pidfd
+    /// and reading `/proc/<pid>/stat` without.
+    ///
+    /// The pid may still be reused after this returned, so the check only narrows the window
+    /// for accessing the wrong process from the life time of the transport to a system call.
+    pub fn verify(&self) -> Result<(), Error> {
+        let same = match (&self.pidfd, self.start_time) {
+            (Some(pidfd), _) => !has_exited(pidfd),
+            (None, Some(time)) => matches!(start_time(self.pid), Ok(t) if t == time),
+            (None, None) => false,
+        };
+        if same {
+            Ok(())
+        } else {
+            Err(Error::Identity { pid: self.pid })
+        }
+    }
+}
+
+/// A `Transport` which checks the `Identity` of its process before every access through
+/// `inner`.
+///
+/// Each call is one batch of accesses, e.g. all pieces of a `readv`.
+#[derive(Debug)]
+pub struct Verified {
+    inner: Arc<dyn Transport>,
+    identity: Identity,
+}
+
+impl Verified {
+    /// Wraps `inner`, identifying its process now.
+    pub fn new(inner: Arc<dyn Transport>) -> Self {
+        let identity = Identity::of(inner.pid());
+        Verified { inner, identity }
+    }
+
+    /// Returns the identity of the process.
+    pub fn identity(&self) -> &Identity {
+        &self.identity
+    }
+
+    /// Runs `f` on `inner` if the identity of the process verifies.
+    fn with<T, F>(&self, f: F) -> Result<T, Error>
+    where
+        F: FnOnce(&dyn Transport) -> Result<T, Error>,
+    {
+        self.identity.verify()?;
+        f(&*self.inner)
+    }
+}
+
+impl Transport for Verified {
+    fn pid(&self) -> Pid {
+        self.inner.pid()
+    }
+
+    fn read_bytes(&self, buf: &mut [u8], addr: usize) -> Result<usize, Error> {
+        self.with(|t| t.read_bytes(buf, addr))
+    }
+
+    fn write_bytes(&self, addr: usize, buf: &[u8]) -> Result<usize, Error> {
+        self.with(|t| t.write_bytes(addr, buf))
+    }
+
+    fn readv(&self, bufs: &mut [&mut [u8]], remote: &[RemoteIoVec]) -> Result<usize, Error> {
+        self.with(|t| t.readv(bufs, remote))
+    }
+
+    fn writev(&self, bufs: &[&[u8]], remote: &[RemoteIoVec]) -> Result<usize, Error> {
+        self.with(|t| t.writev(bufs, remote))
+    }
+
+    fn alignment(&self) -> usize {
+        self.inner.alignment()
+    }
+
+    fn stats(&self) -> Option<&Arc<Stats>> {
+        self.inner.stats()
+    }
+
+    fn watched(&self) -> Option<&Watched> {
+        self.inner.watched()
+    }
+}
+
+/// A `Transport` which fails fast with `Error::Dead` once the process behind `inner` exited or
+/// replaced its address space.
+///
+/// Exits are detected through the `Identity` of the process. The address space is tracked through
+/// a file of `/proc/<pid>/mem` which is opened when the transport is created, if permitted.
+#[derive(Debug)]
+pub struct Watched {
+    inner: Arc<dyn Transport>,
+    identity: Identity,
+    mem: Option<ProcMem>,
+    dead: AtomicBool,
+}
+
+impl Watched {
+    /// Wraps `inner`, watching the process whose memory it accesses.
+    pub fn new(inner: Arc<dyn Transport>) -> Self {
+        let pid = inner.pid();
+        let identity = Identity::of(pid);
+        let mem = ProcMem::open(pid)
+            .map_err(|e| log::debug!("cannot watch address space of process {}: {}", pid, e))
+            .ok();
+        Watched {
+            inner,
+            identity,
+            mem,
+            dead: AtomicBool::new(false),
+        }
+    }
+
+    /// Returns `false` if the process exited or replaced its address space, and marks it as dead
+    /// in that case. This costs up to three system calls as long as the process is alive.
+    pub fn is_alive(&self) -> bool {
+        if self.dead.load(Ordering::Acquire) {
+            return false;
+        }
+        let pid = self.inner.pid();
+        let exited = self.identity.verify().is_err();
+        // Reading from an address space which is gone succeeds with nothing read, while any
+        // other read either succeeds or fails.
+        let replaced = match &self.mem {
+            Some(mem) => matches!(mem.read_bytes(&mut [0u8], 0), Ok(0)),
+            None => false,
+        };
+        if exited || replaced {
+            log::warn!("process {} is dead", pid);
+            self.mark_dead();
+        }
+        !(exited || replaced)
+    }
+
+    /// Marks the process as dead, e.g. when the caller reaped it.
+    pub fn mark_dead(&self) {
+        self.dead.store(true, Ordering::Release);
+    }
+
+    /// Runs `f` on `inner` unless the process is dead. Checks if it died when `f` fails.
+    fn with<T, F>(&self, f: F) -> Result<T, Error>
+    where
+        F: FnOnce(&dyn Transport) -> Result<T, Error>,
+    {
+        if self.dead.load(Ordering::Acquire) {
+            return Err(Error::Dead {
+                pid: self.inner.pid(),
+            });
+        }
+        let res = f(&*self.inner);
+        if let Err(Error::ProcessGone { .. })
+        | Err(Error::BadAddress { .. })
+        | Err(Error::Identity { .. }) = res
+        {
+            self.is_alive();
+        }
+        res
+    }
+}
+
+impl Transport for Watched {
+    fn pid(&self) -> Pid {
+        self.inner.pid()
+    }
+
+    fn read_bytes(&self, buf: &mut [u8], addr: usize) -> Result<usize, Error> {
+        self.with(|t| t.read_bytes(buf, addr))
+    }
+
+    fn write_bytes(&self, addr: usize, buf: &[u8]) -> Result<usize, Error> {
+        self.with(|t| t.write_bytes(addr, buf))
+    }
+
+    fn readv(&self, bufs: &mut [&mut [u8]], remote: &[RemoteIoVec]) -> Result<usize, Error> {
+        self.with(|t| t.readv(bufs, remote))
+    }
+
+    fn writev(&self, bufs: &[&[u8]], remote: &[RemoteIoVec]) -> Result<usize, Error> {
+        self.with(|t| t.writev(bufs, remote))
+    }
+
+    fn alignment(&self) -> usize {
+        self.inner.alignment()
+    }
+
+    fn stats(&self) -> Option<&Arc<Stats>> {
+        self.inner.stats()
+    }
+
+    fn watched(&self) -> Option<&Watched> {
+        Some(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::thread::sleep;
+    use std::time::Duration;
+
+    use nix::sys::signal::{kill, Signal};
+
+    use crate::remote_mem::{default_transport, ProcessVm};
+    use crate::test_utils::TestProcess;
+
+    /// Waits until `t` notices that its process is dead.
+    fn wait_dead(t: &Watched) {
+        for _ in 0..1000 {
+            if !t.is_alive() {
+                return;
+            }
+            sleep(Duration::from_millis(1));
+        }
+        panic!("process {} didn't die", t.pid());
+    }
+
+    #[test]
+    fn test_watched() {
+        let child = TestProcess::spawn(&[0x1000]);
+        let pid = Pid::from_raw(child.pid());
+        let t = Watched::new(default_transport(pid));
+        assert!(t.is_alive());
+        assert!(t.watched().is_some());
+        assert_eq!(t.write_bytes(child.addr(0), &[1u8; 4]).unwrap(), 4);
+        // Bad addresses of a live process don't kill it.
+        assert!(matches!(
+            t.read_bytes(&mut [0u8; 4], 0),
+            Err(Error::BadAddress { .. })
+        ));
+        assert!(t.is_alive());
+
+        // The child stays a zombie until it's dropped.
+        kill(pid, Signal::SIGKILL).unwrap();
+        wait_dead(&t);
+        match t.read_bytes(&mut [0u8; 4], child.addr(0)) {
+            Err(e @ Error::Dead { .. }) => assert!(e.is_fatal()),
+            res => panic!("unexpected {:?}", res),
+        }
+
+        // A failed access notices it, too. The address space is released before the process
+        // becomes a zombie, so wait for the exit to be complete first.
+        for i in 0.. {
+            if Identity::of(pid).verify().is_err() {
+                break;
+            }
+            assert!(i < 1000, "process {} didn't exit", pid);
+            sleep(Duration::from_millis(1));
+        }
+        let t = Watched::new(Arc::new(ProcessVm::new(pid)));
+        assert!(matches!(
+            t.write_bytes(child.addr(0), &[1u8; 4]),
+            Err(Error::ProcessGone { .. })
+        ));
+        assert!(matches!(
+            t.write_bytes(child.addr(0), &[1u8; 4]),
+            Err(Error::Dead { pid: p }) if p == pid
+        ));
+        assert!(!t.is_alive());
+    }
+
+    #[test]
+    fn test_identity() {
+        let this = Identity::of(Pid::this());
+        assert_eq!(this.pid(), Pid::this());
+        this.verify().unwrap();
+        assert!(start_time(Pid::this()).unwrap() > 0);
+
+        let child = TestProcess::spawn(&[0x1000]);
+        let pid = Pid::from_raw(child.pid());
+        let t = Verified::new(Arc::new(ProcessVm::new(pid)));
+        assert_eq!(t.write_bytes(child.addr(0), &[1u8; 4]).unwrap(), 4);
+        let by_time = Identity {
+            pid,
+            pidfd: None,
+            start_time: Some(start_time(pid).unwrap()),
+        };
+        by_time.verify().unwrap();
+
+        // Another process with the pid isn't the same.
+        let other = Identity {
+            pid,
+            pidfd: None,
+            start_time: Some(by_time.start_time.unwrap() + 1),
+        };
+        assert!(matches!(other.verify(), Err(Error::Identity { pid: p }) if p == pid));
+
+        drop(child);
+        by_time.verify().unwrap_err();
+        match t.readv(&mut [&mut [0u8; 4][..]], &[RemoteIoVec { base: 0, len: 4 }]) {
+            Err(e @ Error::Identity { .. }) => assert!(e.is_fatal()),
+            res => panic!("unexpected {:?}", res),
+        }
+    }
+}
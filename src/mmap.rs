@@ -11,28 +11,40 @@
 //! The default implementation for the [`GuestMemory`](trait.GuestMemory.html) trait.
 //!
 //! This implementation is mmap-ing the memory of the guest into the current process.
+//!
+//! Statistics about accesses to remote memory are opt-in: only regions whose transport is wrapped
+//! in an [`Instrumented`](../stats/struct.Instrumented.html) transport, e.g. through
+//! `GuestMemoryMmap::from_ranges_with_transport`, count them. `from_ranges` and `from_hints` don't
+//! attach any, and regions accessed directly never have statistics, so
+//! `GuestMemoryMmap::stats` returns zeros for them.
 
 use libc::pid_t;
 use nix::unistd::Pid;
 use std::borrow::Borrow;
+use std::cmp::min;
 use std::error;
 use std::fmt;
 use std::io::{Read, Write};
+use std::mem::size_of;
 use std::ops::Deref;
 use std::result;
 use std::sync::atomic::Ordering;
 use std::sync::Arc;
 
 use crate::address::Address;
+use crate::bitmap::DirtyBitmap;
 use crate::guest_memory::{
     self, FileOffset, GuestAddress, GuestMemory, GuestMemoryIterator, GuestMemoryRegion,
     GuestUsize, MemoryRegionAddress,
 };
-use crate::remote_mem::{self, process_read_bytes, process_write_bytes};
-use crate::remote_mem::{process_load, process_store};
-use crate::volatile_memory::{VolatileMemory, VolatileSlice};
+use crate::proc_maps;
+use crate::remote_mem::{self, default_transport, Transport};
+use crate::stats::{AccessKind, Stats, StatsSnapshot};
+use crate::volatile_memory::{self, compute_offset, VolatileMemory, VolatileSlice};
 use crate::{AtomicAccess, Bytes};
 
+#[cfg(unix)]
+pub use crate::mmap_remote::RemoteRegion;
 #[cfg(unix)]
 pub use crate::mmap_unix::{Error as MmapRegionError, MmapRegion};
 
@@ -73,6 +85,30 @@ pub enum Error {
     MemoryRegionOverlap,
     /// The provided memory regions haven't been sorted.
     UnsortedMemoryRegions,
+    /// Reading the mappings of the remote process failed.
+    ProcMaps(proc_maps::Error),
+    /// A host address range isn't mapped read-write in the remote process.
+    UnmappedHostRange {
+        /// Start of the range.
+        addr: usize,
+        /// Size of the range.
+        size: usize,
+    },
+    /// A host address range isn't backed by a single shared file mapping in the remote process.
+    NotSharedFile {
+        /// Start of the range.
+        addr: usize,
+        /// Size of the range.
+        size: usize,
+    },
+    /// The page size for dirty tracking isn't a power of two.
+    InvalidPageSize(usize),
+    /// The region at this guest address is shared with another memory object and can't be
+    /// changed.
+    SharedRegion(GuestAddress),
+    /// A mapping of the current process was given for the memory of this other process, which
+    /// must be described by a `RemoteRegion` instead.
+    ForeignProcess(pid_t),
 }
 
 impl fmt::Display for Error {
@@ -91,6 +127,30 @@ impl fmt::Display for Error {
             Error::UnsortedMemoryRegions => {
                 write!(f, "The provided memory regions haven't been sorted")
             }
+            Error::ProcMaps(e) => write!(f, "{}", e),
+            Error::UnmappedHostRange { addr, size } => write!(
+                f,
+                "Host range 0x{:x}+0x{:x} isn't mapped read-write in the remote process",
+                addr, size
+            ),
+            Error::NotSharedFile { addr, size } => write!(
+                f,
+                "Host range 0x{:x}+0x{:x} isn't a shared file mapping in the remote process",
+                addr, size
+            ),
+            Error::InvalidPageSize(size) => {
+                write!(f, "The page size {} isn't a power of two", size)
+            }
+            Error::SharedRegion(addr) => write!(
+                f,
+                "The region at guest address 0x{:x} is shared and can't be changed",
+                addr.0
+            ),
+            Error::ForeignProcess(pid) => write!(
+                f,
+                "A mapping of the current process can't describe memory of process {}",
+                pid
+            ),
         }
     }
 }
@@ -128,35 +188,238 @@ pub fn check_file_offset(
 ///
 /// Represents a continuous region of the guest's physical memory that is backed by a mapping
 /// in the virtual address space of the calling process.
+///
+/// The mapping may also live in another process, e.g. the hypervisor, in which case its memory
+/// is accessed through a [`Transport`](../remote_mem/trait.Transport.html), by default
+/// `process_vm_readv`/`process_vm_writev` on process `pid` with `/proc/<pid>/mem` as fallback
+/// (see [`default_transport`](../remote_mem/fn.default_transport.html)). Either way the mapping
+/// is described by a [`RemoteRegion`](struct.RemoteRegion.html).
 #[derive(Debug)]
 pub struct GuestRegionMmap {
-    mapping: MmapRegion,
+    mapping: RemoteRegion,
+    backend: Backend,
     guest_base: GuestAddress,
-    pid: pid_t,
+    dirty: Option<Arc<DirtyBitmap>>,
+}
+
+/// How the memory of a `GuestRegionMmap` is accessed.
+#[derive(Debug)]
+enum Backend {
+    /// Mapped in the current process and accessed directly.
+    Local(MmapRegion),
+    /// Accessed through a transport. `_local` keeps a mapping of the current process alive which
+    /// is only accessed through a transport to the current process, see
+    /// `GuestRegionMmap::with_transport`.
+    Remote {
+        transport: Arc<dyn Transport>,
+        _local: Option<MmapRegion>,
+    },
 }
 
 impl GuestRegionMmap {
     /// Create a new memory-mapped memory region for the guest's physical memory.
+    ///
+    /// The mapping is accessed directly, so `pid` must be the current process; this fails with
+    /// `Error::ForeignProcess` otherwise. Memory of another process is described by a
+    /// `RemoteRegion`, see [`from_remote`](#method.from_remote).
     pub fn new(
         pid: pid_t,
         mapping: MmapRegion,
         guest_base: GuestAddress,
+    ) -> result::Result<Self, Error> {
+        if Pid::from_raw(pid) != Pid::this() {
+            return Err(Error::ForeignProcess(pid));
+        }
+        Self::new_local(mapping, guest_base)
+    }
+
+    /// Create a new memory-mapped memory region for the guest's physical memory, which is
+    /// mapped in the current process and accessed directly.
+    pub fn new_local(mapping: MmapRegion, guest_base: GuestAddress) -> result::Result<Self, Error> {
+        let remote = RemoteRegion::from(&mapping);
+        Self::build(remote, Backend::Local(mapping), guest_base)
+    }
+
+    /// Create a new memory-mapped memory region for the guest's physical memory, which is
+    /// accessed through `transport`.
+    ///
+    /// The mapping is in the current process, so `transport` must access the current process,
+    /// e.g. to count the accesses with an [`Instrumented`](../stats/struct.Instrumented.html)
+    /// transport; this fails with `Error::ForeignProcess` otherwise. Memory of another process is
+    /// described by a `RemoteRegion`, see [`from_remote`](#method.from_remote).
+    pub fn with_transport(
+        transport: Arc<dyn Transport>,
+        mapping: MmapRegion,
+        guest_base: GuestAddress,
+    ) -> result::Result<Self, Error> {
+        if transport.pid() != Pid::this() {
+            return Err(Error::ForeignProcess(transport.pid().as_raw()));
+        }
+        let remote = RemoteRegion::from(&mapping);
+        let backend = Backend::Remote {
+            transport,
+            _local: Some(mapping),
+        };
+        Self::build(remote, backend, guest_base)
+    }
+
+    /// Create a new memory region for the guest's physical memory mapped in another process,
+    /// which is accessed through `transport`.
+    pub fn from_remote(
+        transport: Arc<dyn Transport>,
+        mapping: RemoteRegion,
+        guest_base: GuestAddress,
+    ) -> result::Result<Self, Error> {
+        let backend = Backend::Remote {
+            transport,
+            _local: None,
+        };
+        Self::build(mapping, backend, guest_base)
+    }
+
+    fn build(
+        mapping: RemoteRegion,
+        backend: Backend,
+        guest_base: GuestAddress,
     ) -> result::Result<Self, Error> {
         if guest_base.0.checked_add(mapping.len() as u64).is_none() {
             return Err(Error::InvalidGuestRegion);
         }
         Ok(GuestRegionMmap {
             mapping,
+            backend,
             guest_base,
-            pid,
+            dirty: None,
         })
     }
+
+    /// Returns the region, tracking the pages written through it in a bitmap of pages of
+    /// `page_size` bytes. See [`DirtyBitmap`](../bitmap/struct.DirtyBitmap.html).
+    ///
+    /// Previously tracked writes are forgotten.
+    pub fn with_dirty_tracking(mut self, page_size: usize) -> result::Result<Self, Error> {
+        let bitmap = DirtyBitmap::new(self.mapping.size(), page_size)
+            .ok_or(Error::InvalidPageSize(page_size))?;
+        self.dirty = Some(Arc::new(bitmap));
+        Ok(self)
+    }
+
+    /// Maps the memory of the region into the current process, so that it's accessed directly.
+    ///
+    /// This requires the region to be backed by a shared file mapping, e.g. of a memfd or a
+    /// hugetlbfs file, which is reopened through `/proc/<pid>/map_files`. The returned region
+    /// refers to the same memory as `self`, but `Bytes::load`/`Bytes::store` and
+    /// `GuestMemory::compare_exchange` become real atomics instead of being emulated.
+    pub fn map_shared(&self) -> result::Result<Self, Error> {
+        let pid = self.transport().map_or_else(Pid::this, |t| t.pid());
+        let maps = read_remote_maps(pid)?;
+        let mut region = Self::map_file(pid, &maps, self.mapping.clone(), self.guest_base)?;
+        // Writes through either region are tracked together.
+        region.dirty = self.dirty.clone();
+        Ok(region)
+    }
+
+    /// Create a new memory region for the guest's physical memory from `size` bytes at `addr` in
+    /// process `pid`, by mapping the file backing them into the current process.
+    ///
+    /// The file, e.g. a memfd or a hugetlbfs file, is reopened through
+    /// `/proc/<pid>/map_files`, so the region is accessed directly without any system calls.
+    pub fn from_map_files(
+        pid: pid_t,
+        addr: usize,
+        size: usize,
+        guest_base: GuestAddress,
+    ) -> result::Result<Self, Error> {
+        let pid = Pid::from_raw(pid);
+        let maps = read_remote_maps(pid)?;
+        let mapping = describe_range(&maps, addr, size)?;
+        Self::map_file(pid, &maps, mapping, guest_base)
+    }
+
+    /// Maps the file backing `mapping` of process `pid` into the current process.
+    fn map_file(
+        pid: Pid,
+        maps: &[proc_maps::Mapping],
+        mut mapping: RemoteRegion,
+        guest_base: GuestAddress,
+    ) -> result::Result<Self, Error> {
+        let addr = mapping.as_ptr() as usize;
+        let size = mapping.size();
+        let found = proc_maps::find_range(maps, addr, size)
+            .filter(|found| found.len() == 1 && found[0].shared && found[0].inode != 0)
+            .map(|found| found[0])
+            .ok_or(Error::NotSharedFile { addr, size })?;
+
+        let file = proc_maps::open_map_file(pid, found).map_err(Error::ProcMaps)?;
+        let file_offset = FileOffset::new(file, found.offset + (addr - found.start) as u64);
+        let mut local =
+            MmapRegion::from_file(file_offset.clone(), size).map_err(Error::MmapRegion)?;
+        if let Some(hugetlbfs) = found.hugetlbfs.or_else(|| mapping.is_hugetlbfs()) {
+            local.set_hugetlbfs(hugetlbfs);
+            mapping.set_hugetlbfs(hugetlbfs);
+        }
+        let mapping = mapping.with_file_offset(file_offset);
+        Self::build(mapping, Backend::Local(local), guest_base)
+    }
+
+    /// Returns `true` if the region is mapped in the current process and accessed directly.
+    pub fn is_local(&self) -> bool {
+        matches!(self.backend, Backend::Local(_))
+    }
+
+    /// Returns the transport used to access the memory of the region, or `None` if it's
+    /// accessed directly.
+    pub fn transport(&self) -> Option<&Arc<dyn Transport>> {
+        match &self.backend {
+            Backend::Local(_) => None,
+            Backend::Remote { transport, .. } => Some(transport),
+        }
+    }
+
+    /// Reports an access through `transport` to the hook of its statistics, if any.
+    fn notify<T>(
+        &self,
+        transport: &dyn Transport,
+        kind: AccessKind,
+        addr: MemoryRegionAddress,
+        len: usize,
+        res: &result::Result<T, remote_mem::Error>,
+    ) {
+        if let Some(stats) = transport.stats() {
+            stats.notify(kind, self.guest_base.unchecked_add(addr.0), len, res);
+        }
+    }
+}
+
+/// Reads the mappings of process `pid`, preferring `smaps` which also tells about hugetlbfs.
+fn read_remote_maps(pid: Pid) -> result::Result<Vec<proc_maps::Mapping>, Error> {
+    proc_maps::read_smaps(pid)
+        .or_else(|_| proc_maps::read_maps(pid))
+        .map_err(Error::ProcMaps)
+}
+
+/// Describes `size` bytes at `addr`, which must be mapped read-write according to `maps`.
+fn describe_range(
+    maps: &[proc_maps::Mapping],
+    addr: usize,
+    size: usize,
+) -> result::Result<RemoteRegion, Error> {
+    let rw = libc::PROT_READ | libc::PROT_WRITE;
+    let found = proc_maps::find_range(maps, addr, size)
+        .filter(|found| found.iter().all(|m| m.prot & rw == rw))
+        .ok_or(Error::UnmappedHostRange { addr, size })?;
+    let mut mapping = RemoteRegion::new(addr, size, found[0].prot, found[0].flags())
+        .map_err(Error::MmapRegion)?;
+    if let Some(hugetlbfs) = found[0].hugetlbfs {
+        mapping.set_hugetlbfs(hugetlbfs);
+    }
+    Ok(mapping)
 }
 
 impl Deref for GuestRegionMmap {
-    type Target = MmapRegion;
+    type Target = RemoteRegion;
 
-    fn deref(&self) -> &MmapRegion {
+    fn deref(&self) -> &RemoteRegion {
         &self.mapping
     }
 }
@@ -171,7 +434,8 @@ impl Bytes<MemoryRegionAddress> for GuestRegionMmap {
     /// # use vm_memory::{Bytes, GuestAddress, GuestMemoryMmap};
     /// #
     /// # let start_addr = GuestAddress(0x1000);
-    /// # let mut gm = GuestMemoryMmap::from_ranges(&vec![(start_addr, 0x400)])
+    /// # let pid = std::process::id() as i32;
+    /// # let mut gm = GuestMemoryMmap::from_ranges(pid, &vec![(start_addr, 0x400)])
     /// #    .expect("Could not create guest memory");
     /// #
     /// let res = gm
@@ -181,6 +445,14 @@ impl Bytes<MemoryRegionAddress> for GuestRegionMmap {
     /// ```
     fn write(&self, buf: &[u8], addr: MemoryRegionAddress) -> guest_memory::Result<usize> {
         let maddr = addr.raw_value() as usize;
+        let transport = match &self.backend {
+            Backend::Local(_) => {
+                return self
+                    .as_volatile_slice()
+                    .and_then(|s| s.write(buf, maddr).map_err(Into::into))
+            }
+            Backend::Remote { transport, .. } => transport,
+        };
         log::trace!("write 0x{:x}", maddr);
         if maddr >= self.mapping.size() {
             return Err(guest_memory::Error::InvalidGuestAddress(GuestAddress(
@@ -188,12 +460,13 @@ impl Bytes<MemoryRegionAddress> for GuestRegionMmap {
             )));
         }
         let ptr = self.mapping.as_ptr() as usize + maddr;
-        process_write_bytes(Pid::from_raw(self.pid), ptr as *mut libc::c_void, buf)
-            .map_err(guest_memory::Error::RemoteMemError)
-        // self.as_volatile_slice()
-        //     .unwrap()
-        //     .write(buf, maddr)
-        //     .map_err(Into::into)
+        let len = min(buf.len(), self.mapping.size() - maddr);
+        let res = transport.write_bytes(ptr, &buf[..len]);
+        self.notify(&**transport, AccessKind::Write, addr, len, &res);
+        if let (Ok(written), Some(bitmap)) = (&res, &self.dirty) {
+            bitmap.mark(maddr, *written);
+        }
+        res.map_err(guest_memory::Error::RemoteMemError)
     }
 
     /// # Examples
@@ -203,7 +476,8 @@ impl Bytes<MemoryRegionAddress> for GuestRegionMmap {
     /// # use vm_memory::{Bytes, GuestAddress, GuestMemoryMmap};
     /// #
     /// # let start_addr = GuestAddress(0x1000);
-    /// # let mut gm = GuestMemoryMmap::from_ranges(&vec![(start_addr, 0x400)])
+    /// # let pid = std::process::id() as i32;
+    /// # let mut gm = GuestMemoryMmap::from_ranges(pid, &vec![(start_addr, 0x400)])
     /// #    .expect("Could not create guest memory");
     /// #
     /// let buf = &mut [0u8; 16];
@@ -214,6 +488,14 @@ impl Bytes<MemoryRegionAddress> for GuestRegionMmap {
     /// ```
     fn read(&self, buf: &mut [u8], addr: MemoryRegionAddress) -> guest_memory::Result<usize> {
         let maddr = addr.raw_value() as usize;
+        let transport = match &self.backend {
+            Backend::Local(_) => {
+                return self
+                    .as_volatile_slice()
+                    .and_then(|s| s.read(buf, maddr).map_err(Into::into))
+            }
+            Backend::Remote { transport, .. } => transport,
+        };
         log::trace!("read 0x{:x}", maddr);
         if maddr >= self.mapping.size() {
             return Err(guest_memory::Error::InvalidGuestAddress(GuestAddress(
@@ -221,15 +503,19 @@ impl Bytes<MemoryRegionAddress> for GuestRegionMmap {
             )));
         }
         let ptr = self.mapping.as_ptr() as usize + maddr;
-        process_read_bytes(Pid::from_raw(self.pid), buf, ptr as *const libc::c_void)
-            .map_err(guest_memory::Error::RemoteMemError)
-        // self.as_volatile_slice()
-        //     .unwrap()
-        //     .read(buf, maddr)
-        //     .map_err(Into::into)
+        let len = min(buf.len(), self.mapping.size() - maddr);
+        let res = transport.read_bytes(&mut buf[..len], ptr);
+        self.notify(&**transport, AccessKind::Read, addr, len, &res);
+        res.map_err(guest_memory::Error::RemoteMemError)
     }
 
     fn write_slice(&self, buf: &[u8], addr: MemoryRegionAddress) -> guest_memory::Result<()> {
+        if self.is_local() {
+            let maddr = addr.raw_value() as usize;
+            return self
+                .as_volatile_slice()
+                .and_then(|s| s.write_slice(buf, maddr).map_err(Into::into));
+        }
         let written = self.write(buf, addr)?;
         if written != buf.len() {
             return Err(guest_memory::Error::RemoteMemError(
@@ -239,15 +525,16 @@ impl Bytes<MemoryRegionAddress> for GuestRegionMmap {
                 },
             ));
         }
-        // let maddr = addr.raw_value() as usize;
-        // self.as_volatile_slice()
-        //     .unwrap()
-        //     .write_slice(buf, maddr)
-        //     .map_err(Into::into)
         Ok(())
     }
 
     fn read_slice(&self, buf: &mut [u8], addr: MemoryRegionAddress) -> guest_memory::Result<()> {
+        if self.is_local() {
+            let maddr = addr.raw_value() as usize;
+            return self
+                .as_volatile_slice()
+                .and_then(|s| s.read_slice(buf, maddr).map_err(Into::into));
+        }
         let read = self.read(buf, addr)?;
         if read != buf.len() {
             return Err(guest_memory::Error::RemoteMemError(
@@ -257,11 +544,6 @@ impl Bytes<MemoryRegionAddress> for GuestRegionMmap {
                 },
             ));
         }
-        //let maddr = addr.raw_value() as usize;
-        //self.as_volatile_slice()
-        //.unwrap()
-        //.read_slice(buf, maddr)
-        //.map_err(Into::into)
         Ok(())
     }
 
@@ -275,7 +557,8 @@ impl Bytes<MemoryRegionAddress> for GuestRegionMmap {
     /// # use std::path::Path;
     /// #
     /// # let start_addr = GuestAddress(0x1000);
-    /// # let gm = GuestMemoryMmap::from_ranges(&vec![(start_addr, 0x400)])
+    /// # let pid = std::process::id() as i32;
+    /// # let gm = GuestMemoryMmap::from_ranges(pid, &vec![(start_addr, 0x400)])
     /// #    .expect("Could not create guest memory");
     /// # let addr = GuestAddress(0x1010);
     /// # let mut file = if cfg!(unix) {
@@ -303,12 +586,9 @@ impl Bytes<MemoryRegionAddress> for GuestRegionMmap {
     where
         F: Read,
     {
-        unimplemented!();
         let maddr = addr.raw_value() as usize;
         self.as_volatile_slice()
-            .unwrap()
-            .read_from::<F>(maddr, src, count)
-            .map_err(Into::into)
+            .and_then(|s| s.read_from::<F>(maddr, src, count).map_err(Into::into))
     }
 
     /// # Examples
@@ -321,7 +601,8 @@ impl Bytes<MemoryRegionAddress> for GuestRegionMmap {
     /// # use std::path::Path;
     /// #
     /// # let start_addr = GuestAddress(0x1000);
-    /// # let gm = GuestMemoryMmap::from_ranges(&vec![(start_addr, 0x400)])
+    /// # let pid = std::process::id() as i32;
+    /// # let gm = GuestMemoryMmap::from_ranges(pid, &vec![(start_addr, 0x400)])
     /// #    .expect("Could not create guest memory");
     /// # let addr = GuestAddress(0x1010);
     /// # let mut file = if cfg!(unix) {
@@ -349,12 +630,11 @@ impl Bytes<MemoryRegionAddress> for GuestRegionMmap {
     where
         F: Read,
     {
-        unimplemented!();
         let maddr = addr.raw_value() as usize;
-        self.as_volatile_slice()
-            .unwrap()
-            .read_exact_from::<F>(maddr, src, count)
-            .map_err(Into::into)
+        self.as_volatile_slice().and_then(|s| {
+            s.read_exact_from::<F>(maddr, src, count)
+                .map_err(Into::into)
+        })
     }
 
     /// Writes data from the region to a writable object.
@@ -369,7 +649,8 @@ impl Bytes<MemoryRegionAddress> for GuestRegionMmap {
     /// # use vm_memory::{Address, Bytes, GuestAddress, GuestMemoryMmap};
     /// #
     /// # let start_addr = GuestAddress(0x1000);
-    /// # let gm = GuestMemoryMmap::from_ranges(&vec![(start_addr, 0x400)])
+    /// # let pid = std::process::id() as i32;
+    /// # let gm = GuestMemoryMmap::from_ranges(pid, &vec![(start_addr, 0x400)])
     /// #    .expect("Could not create guest memory");
     /// # let mut file = if cfg!(unix) {
     /// # use std::fs::OpenOptions;
@@ -395,12 +676,9 @@ impl Bytes<MemoryRegionAddress> for GuestRegionMmap {
     where
         F: Write,
     {
-        unimplemented!();
         let maddr = addr.raw_value() as usize;
         self.as_volatile_slice()
-            .unwrap()
-            .write_to::<F>(maddr, dst, count)
-            .map_err(Into::into)
+            .and_then(|s| s.write_to::<F>(maddr, dst, count).map_err(Into::into))
     }
 
     /// Writes data from the region to a writable object.
@@ -415,7 +693,8 @@ impl Bytes<MemoryRegionAddress> for GuestRegionMmap {
     /// # use vm_memory::{Address, Bytes, GuestAddress, GuestMemoryMmap};
     /// #
     /// # let start_addr = GuestAddress(0x1000);
-    /// # let gm = GuestMemoryMmap::from_ranges(&vec![(start_addr, 0x400)])
+    /// # let pid = std::process::id() as i32;
+    /// # let gm = GuestMemoryMmap::from_ranges(pid, &vec![(start_addr, 0x400)])
     /// #    .expect("Could not create guest memory");
     /// # let mut file = if cfg!(unix) {
     /// # use std::fs::OpenOptions;
@@ -441,12 +720,9 @@ impl Bytes<MemoryRegionAddress> for GuestRegionMmap {
     where
         F: Write,
     {
-        unimplemented!();
         let maddr = addr.raw_value() as usize;
         self.as_volatile_slice()
-            .unwrap()
-            .write_all_to::<F>(maddr, dst, count)
-            .map_err(Into::into)
+            .and_then(|s| s.write_all_to::<F>(maddr, dst, count).map_err(Into::into))
     }
 
     fn store<T: AtomicAccess>(
@@ -456,20 +732,28 @@ impl Bytes<MemoryRegionAddress> for GuestRegionMmap {
         order: Ordering,
     ) -> guest_memory::Result<()> {
         let maddr = addr.raw_value() as usize;
+        let transport = match &self.backend {
+            Backend::Local(_) => {
+                return self
+                    .as_volatile_slice()
+                    .and_then(|s| s.store(val, maddr, order).map_err(Into::into))
+            }
+            Backend::Remote { transport, .. } => transport,
+        };
         log::trace!("store 0x{:x}", maddr);
-        if maddr >= self.mapping.size() {
+        if maddr >= self.mapping.size() || self.mapping.size() - maddr < size_of::<T>() {
             log::warn!("out of bounds");
             return Err(guest_memory::Error::InvalidGuestAddress(GuestAddress(
                 addr.0,
             )));
         }
         let ptr = self.mapping.as_ptr() as usize + maddr;
-        process_store(Pid::from_raw(self.pid), ptr as *mut libc::c_void, &val)
-            .map_err(guest_memory::Error::RemoteMemError)
-        // self.as_volatile_slice().and_then(|s| {
-        //     s.store(val, addr.raw_value() as usize, order)
-        //         .map_err(Into::into)
-        // })
+        let res = remote_mem::store(&**transport, ptr, &val);
+        self.notify(&**transport, AccessKind::Store, addr, size_of::<T>(), &res);
+        if let (Ok(()), Some(bitmap)) = (&res, &self.dirty) {
+            bitmap.mark(maddr, size_of::<T>());
+        }
+        res.map_err(guest_memory::Error::RemoteMemError)
     }
 
     fn load<T: AtomicAccess>(
@@ -478,21 +762,29 @@ impl Bytes<MemoryRegionAddress> for GuestRegionMmap {
         order: Ordering,
     ) -> guest_memory::Result<T> {
         let maddr = addr.raw_value() as usize;
+        let transport = match &self.backend {
+            Backend::Local(_) => {
+                return self
+                    .as_volatile_slice()
+                    .and_then(|s| s.load(maddr, order).map_err(Into::into))
+            }
+            Backend::Remote { transport, .. } => transport,
+        };
         log::trace!("load 0x{:x}", maddr);
-        if maddr >= self.mapping.size() {
+        if maddr >= self.mapping.size() || self.mapping.size() - maddr < size_of::<T>() {
             log::warn!("out of bounds");
             return Err(guest_memory::Error::InvalidGuestAddress(GuestAddress(
                 addr.0,
             )));
         }
         let ptr = self.mapping.as_ptr() as usize + maddr;
-        process_load(Pid::from_raw(self.pid), ptr as *const libc::c_void).map_err(guest_memory::Error::RemoteMemError)
-        //self.as_volatile_slice()
-        //.and_then(|s| s.load(addr.raw_value() as usize, order).map_err(Into::into))
+        let res = remote_mem::load(&**transport, ptr);
+        self.notify(&**transport, AccessKind::Load, addr, size_of::<T>(), &res);
+        res.map_err(guest_memory::Error::RemoteMemError)
     }
 }
 
-/// A Mmap which has no support for direct memory accesses.
+/// Only regions mapped in the current process support direct memory accesses.
 impl GuestMemoryRegion for GuestRegionMmap {
     fn len(&self) -> GuestUsize {
         self.mapping.len() as GuestUsize
@@ -507,13 +799,17 @@ impl GuestMemoryRegion for GuestRegionMmap {
     }
 
     unsafe fn as_slice(&self) -> Option<&[u8]> {
-        //Some(self.mapping.as_slice())
-        None
+        match &self.backend {
+            Backend::Local(mapping) => Some(mapping.as_slice()),
+            Backend::Remote { .. } => None,
+        }
     }
 
     unsafe fn as_mut_slice(&self) -> Option<&mut [u8]> {
-        //Some(self.mapping.as_mut_slice())
-        None
+        match &self.backend {
+            Backend::Local(mapping) => Some(mapping.as_mut_slice()),
+            Backend::Remote { .. } => None,
+        }
     }
 
     fn get_host_address(&self, addr: MemoryRegionAddress) -> guest_memory::Result<*mut u8> {
@@ -529,15 +825,35 @@ impl GuestMemoryRegion for GuestRegionMmap {
         offset: MemoryRegionAddress,
         count: usize,
     ) -> guest_memory::Result<VolatileSlice> {
-        unimplemented!();
-        let slice = self.mapping.get_slice(offset.raw_value() as usize, count)?;
-        Ok(slice)
+        let offset = offset.raw_value() as usize;
+        let slice = match &self.backend {
+            Backend::Local(mapping) => mapping.get_slice(offset, count)?,
+            Backend::Remote { transport, .. } => {
+                let end = compute_offset(offset, count)?;
+                if end > self.mapping.size() {
+                    return Err(volatile_memory::Error::OutOfBounds { addr: end }.into());
+                }
+                VolatileSlice::new_remote(
+                    &**transport,
+                    self.mapping.as_ptr() as usize + offset,
+                    count,
+                )
+            }
+        };
+        Ok(match &self.dirty {
+            Some(bitmap) => slice.with_dirty_bitmap(bitmap, offset),
+            None => slice,
+        })
     }
 
     #[cfg(target_os = "linux")]
     fn is_hugetlbfs(&self) -> Option<bool> {
         self.mapping.is_hugetlbfs()
     }
+
+    fn dirty_bitmap(&self) -> Option<&DirtyBitmap> {
+        self.dirty.as_deref()
+    }
 }
 
 /// [`GuestMemory`](trait.GuestMemory.html) implementation that mmaps the guest's memory
@@ -575,31 +891,216 @@ impl GuestMemoryMmap {
     ///
     /// Valid memory regions are specified as a sequence of (Address, Size, Option<FileOffset>)
     /// tuples sorted by Address.
+    ///
+    /// The memory is allocated in the current process, so `pid` must be the current process;
+    /// this fails with `Error::ForeignProcess` otherwise. The memory of another process is found
+    /// with [`from_hints`](struct.GuestMemoryMmap.html#method.from_hints).
     pub fn from_ranges_with_files<A, T>(pid: pid_t, ranges: T) -> result::Result<Self, Error>
     where
         A: Borrow<(GuestAddress, usize, Option<FileOffset>)>,
         T: IntoIterator<Item = A>,
     {
+        if Pid::from_raw(pid) != Pid::this() {
+            return Err(Error::ForeignProcess(pid));
+        }
         Self::from_regions(
             pid,
             ranges
                 .into_iter()
                 .map(|x| {
-                    let guest_base = x.borrow().0;
-                    let size = x.borrow().1;
+                    Self::map_range(x.borrow())
+                        .and_then(|r| GuestRegionMmap::new_local(r, x.borrow().0))
+                })
+                .collect::<result::Result<Vec<_>, Error>>()?,
+        )
+    }
+
+    /// Creates a container and allocates anonymous memory for guest memory regions, which are
+    /// all accessed through `transport`.
+    ///
+    /// Valid memory regions are specified as a sequence of (Address, Size, Option<FileOffset>)
+    /// tuples sorted by Address. Like
+    /// [`GuestRegionMmap::with_transport`](struct.GuestRegionMmap.html#method.with_transport),
+    /// this fails with `Error::ForeignProcess` unless `transport` accesses the current process.
+    pub fn from_ranges_with_transport<A, T>(
+        transport: Arc<dyn Transport>,
+        ranges: T,
+    ) -> result::Result<Self, Error>
+    where
+        A: Borrow<(GuestAddress, usize, Option<FileOffset>)>,
+        T: IntoIterator<Item = A>,
+    {
+        if transport.pid() != Pid::this() {
+            return Err(Error::ForeignProcess(transport.pid().as_raw()));
+        }
+        Self::from_regions(
+            transport.pid().as_raw(),
+            ranges
+                .into_iter()
+                .map(|x| {
+                    Self::map_range(x.borrow()).and_then(|r| {
+                        GuestRegionMmap::with_transport(transport.clone(), r, x.borrow().0)
+                    })
+                })
+                .collect::<result::Result<Vec<_>, Error>>()?,
+        )
+    }
+
+    /// Maps the memory for a (Address, Size, Option<FileOffset>) tuple in the current process.
+    fn map_range(
+        range: &(GuestAddress, usize, Option<FileOffset>),
+    ) -> result::Result<MmapRegion, Error> {
+        if let Some(ref f_off) = range.2 {
+            MmapRegion::from_file(f_off.clone(), range.1)
+        } else {
+            MmapRegion::new(range.1)
+        }
+        .map_err(Error::MmapRegion)
+    }
+
+    /// Creates a container for the guest memory of the running hypervisor `pid`.
+    ///
+    /// Valid memory regions are specified as a slice of (Address, Host address, Size) tuples
+    /// sorted by Address, where Host address is the virtual address of the region in `pid`.
+    /// Each host range is validated against `/proc/<pid>/smaps` (or `/proc/<pid>/maps` if that
+    /// can't be read) and must be mapped read-write; the protection, flags and hugetlbfs backing
+    /// of the mapping are recorded in the region. Candidates for such hints can be found with
+    /// [`guest_ram_candidates`](../proc_maps/fn.guest_ram_candidates.html).
+    pub fn from_hints(
+        pid: pid_t,
+        hints: &[(GuestAddress, usize, usize)],
+    ) -> result::Result<Self, Error> {
+        Self::from_hints_with_transport(default_transport(Pid::from_raw(pid)), hints)
+    }
+
+    /// Creates a container for the guest memory of the running hypervisor, which is accessed
+    /// through `transport`.
+    ///
+    /// Like [`from_hints`](struct.GuestMemoryMmap.html#method.from_hints), for the process of
+    /// `transport`.
+    pub fn from_hints_with_transport(
+        transport: Arc<dyn Transport>,
+        hints: &[(GuestAddress, usize, usize)],
+    ) -> result::Result<Self, Error> {
+        let maps = read_remote_maps(transport.pid())?;
+
+        Self::from_regions(
+            transport.pid().as_raw(),
+            hints
+                .iter()
+                .map(|&(guest_base, addr, size)| {
+                    let mapping = describe_range(&maps, addr, size)?;
+                    GuestRegionMmap::from_remote(transport.clone(), mapping, guest_base)
+                })
+                .collect::<result::Result<Vec<_>, Error>>()?,
+        )
+    }
+
+    /// Creates a container for guest memory regions of another process, which are mapped into
+    /// the current process from the files backing them.
+    ///
+    /// Like [`from_hints`](struct.GuestMemoryMmap.html#method.from_hints), but each range is
+    /// mapped with
+    /// [`GuestRegionMmap::from_map_files`](struct.GuestRegionMmap.html#method.from_map_files)
+    /// and therefore must be backed by a single shared file mapping, e.g. of a memfd or a
+    /// hugetlbfs file.
+    pub fn from_map_files(
+        pid: pid_t,
+        hints: &[(GuestAddress, usize, usize)],
+    ) -> result::Result<Self, Error> {
+        let remote = Pid::from_raw(pid);
+        let maps = read_remote_maps(remote)?;
+
+        Self::from_regions(
+            pid,
+            hints
+                .iter()
+                .map(|&(guest_base, addr, size)| {
+                    let mapping = describe_range(&maps, addr, size)?;
+                    GuestRegionMmap::map_file(remote, &maps, mapping, guest_base)
+                })
+                .collect::<result::Result<Vec<_>, Error>>()?,
+        )
+    }
 
-                    if let Some(ref f_off) = x.borrow().2 {
-                        MmapRegion::from_file(f_off.clone(), size)
+    /// Maps the memory of all regions into the current process, see
+    /// [`GuestRegionMmap::map_shared`](struct.GuestRegionMmap.html#method.map_shared).
+    ///
+    /// Regions which are already accessed directly are shared with `self`.
+    pub fn map_shared(&self) -> result::Result<Self, Error> {
+        Self::from_arc_regions(
+            self.pid,
+            self.regions
+                .iter()
+                .map(|region| {
+                    if region.is_local() {
+                        Ok(region.clone())
                     } else {
-                        MmapRegion::new(size)
+                        region.map_shared().map(Arc::new)
                     }
-                    .map_err(Error::MmapRegion)
-                    .and_then(|r| GuestRegionMmap::new(pid, r, guest_base))
                 })
                 .collect::<result::Result<Vec<_>, Error>>()?,
         )
     }
 
+    /// Returns the sum of the statistics of the transports of all regions, see
+    /// [`Instrumented`](../stats/struct.Instrumented.html).
+    ///
+    /// Statistics shared by several regions are counted once. Regions which are accessed
+    /// directly or through a transport without statistics don't contribute.
+    ///
+    /// Statistics are opt-in: the memory must be built with an `Instrumented` transport, e.g.
+    /// with `from_ranges_with_transport`. For memory built with `from_ranges` or `from_hints`,
+    /// this always returns zeros.
+    pub fn stats(&self) -> StatsSnapshot {
+        let mut seen: Vec<&Arc<Stats>> = Vec::new();
+        let mut total = StatsSnapshot::default();
+        for stats in self.regions.iter().filter_map(|r| r.transport()?.stats()) {
+            if !seen.iter().any(|s| Arc::ptr_eq(s, stats)) {
+                total.merge(&stats.snapshot());
+                seen.push(stats);
+            }
+        }
+        total
+    }
+
+    /// Returns the memory with all regions tracking the pages written through them, see
+    /// [`GuestRegionMmap::with_dirty_tracking`](struct.GuestRegionMmap.html#method.with_dirty_tracking).
+    ///
+    /// Fails with `Error::SharedRegion` if a region is also part of another memory object, e.g.
+    /// a clone of `self`.
+    pub fn with_dirty_tracking(self, page_size: usize) -> result::Result<Self, Error> {
+        let regions = self
+            .regions
+            .into_iter()
+            .map(|region| {
+                let addr = region.start_addr();
+                Arc::try_unwrap(region)
+                    .map_err(|_| Error::SharedRegion(addr))?
+                    .with_dirty_tracking(page_size)
+                    .map(Arc::new)
+            })
+            .collect::<result::Result<Vec<_>, Error>>()?;
+        Self::from_arc_regions(self.pid, regions)
+    }
+
+    /// Returns `false` if the process behind any region is dead, see
+    /// [`Watched`](../liveness/struct.Watched.html).
+    ///
+    /// Regions which are accessed directly or through a transport which isn't watched are
+    /// assumed to be alive.
+    pub fn is_alive(&self) -> bool {
+        self.regions
+            .iter()
+            .filter_map(|r| r.transport()?.watched())
+            .all(|w| w.is_alive())
+    }
+
+    /// Returns the regions, sorted by their start address.
+    pub(crate) fn arc_regions(&self) -> &[Arc<GuestRegionMmap>] {
+        &self.regions
+    }
+
     /// Creates a new `GuestMemoryMmap` from a vector of regions.
     ///
     /// # Arguments
@@ -614,6 +1115,27 @@ impl GuestMemoryMmap {
         Self::from_arc_regions(pid, regions.drain(..).map(Arc::new).collect())
     }
 
+    /// Creates a new `GuestMemoryMmap` from a vector of mappings of the process of `transport`
+    /// and their guest addresses, which are all accessed through `transport`.
+    ///
+    /// Sharing the transport lets accesses spanning several regions be done with a single
+    /// vectored call, see
+    /// [`GuestRegionMmap::from_remote`](struct.GuestRegionMmap.html#method.from_remote).
+    pub fn from_regions_with_transport(
+        transport: Arc<dyn Transport>,
+        regions: Vec<(RemoteRegion, GuestAddress)>,
+    ) -> result::Result<Self, Error> {
+        Self::from_regions(
+            transport.pid().as_raw(),
+            regions
+                .into_iter()
+                .map(|(mapping, guest_base)| {
+                    GuestRegionMmap::from_remote(transport.clone(), mapping, guest_base)
+                })
+                .collect::<result::Result<Vec<_>, Error>>()?,
+        )
+    }
+
     /// Creates a new `GuestMemoryMmap` from a vector of Arc regions.
     ///
     /// Similar to the constructor `from_regions()` as it returns a
@@ -739,9 +1261,11 @@ mod tests {
     extern crate vmm_sys_util;
 
     use super::*;
+    use crate::test_utils::{local_pid, TestProcess};
     use crate::GuestAddressSpace;
 
     use std::fs::File;
+    use std::io::Cursor;
     use std::mem;
     use std::path::Path;
     use vmm_sys_util::tempfile::TempFile;
@@ -782,18 +1306,23 @@ mod tests {
     fn new_guest_memory_mmap(
         regions_summary: &[(GuestAddress, usize)],
     ) -> Result<GuestMemoryMmap, Error> {
-        GuestMemoryMmap::from_ranges(regions_summary)
+        GuestMemoryMmap::from_ranges(local_pid(), regions_summary)
     }
 
     fn new_guest_memory_mmap_from_regions(
         regions_summary: &[(GuestAddress, usize)],
     ) -> Result<GuestMemoryMmap, Error> {
         GuestMemoryMmap::from_regions(
+            local_pid(),
             regions_summary
                 .iter()
                 .map(|(region_addr, region_size)| {
-                    GuestRegionMmap::new(MmapRegion::new(*region_size).unwrap(), *region_addr)
-                        .unwrap()
+                    GuestRegionMmap::new(
+                        local_pid(),
+                        MmapRegion::new(*region_size).unwrap(),
+                        *region_addr,
+                    )
+                    .unwrap()
                 })
                 .collect(),
         )
@@ -803,12 +1332,17 @@ mod tests {
         regions_summary: &[(GuestAddress, usize)],
     ) -> Result<GuestMemoryMmap, Error> {
         GuestMemoryMmap::from_arc_regions(
+            local_pid(),
             regions_summary
                 .iter()
                 .map(|(region_addr, region_size)| {
                     Arc::new(
-                        GuestRegionMmap::new(MmapRegion::new(*region_size).unwrap(), *region_addr)
-                            .unwrap(),
+                        GuestRegionMmap::new(
+                            local_pid(),
+                            MmapRegion::new(*region_size).unwrap(),
+                            *region_addr,
+                        )
+                        .unwrap(),
                     )
                 })
                 .collect(),
@@ -828,7 +1362,7 @@ mod tests {
             })
             .collect();
 
-        GuestMemoryMmap::from_ranges_with_files(&regions)
+        GuestMemoryMmap::from_ranges_with_files(local_pid(), &regions)
     }
 
     #[test]
@@ -973,7 +1507,7 @@ mod tests {
             (GuestAddress(100), 100 as usize),
         ];
 
-        let guest_mem = GuestMemoryMmap::new();
+        let guest_mem = GuestMemoryMmap::new(local_pid());
         assert_eq!(guest_mem.regions.len(), 0);
 
         check_guest_memory_mmap(new_guest_memory_mmap(&regions_summary), &regions_summary);
@@ -1025,12 +1559,18 @@ mod tests {
 
         let start_addr1 = GuestAddress(0x0);
         let start_addr2 = GuestAddress(0x800);
-        let guest_mem =
-            GuestMemoryMmap::from_ranges(&[(start_addr1, 0x400), (start_addr2, 0x400)]).unwrap();
-        let guest_mem_backed_by_file = GuestMemoryMmap::from_ranges_with_files(&[
-            (start_addr1, 0x400, Some(FileOffset::new(f1, 0))),
-            (start_addr2, 0x400, Some(FileOffset::new(f2, 0))),
-        ])
+        let guest_mem = GuestMemoryMmap::from_ranges(
+            local_pid(),
+            &[(start_addr1, 0x400), (start_addr2, 0x400)],
+        )
+        .unwrap();
+        let guest_mem_backed_by_file = GuestMemoryMmap::from_ranges_with_files(
+            local_pid(),
+            &[
+                (start_addr1, 0x400, Some(FileOffset::new(f1, 0))),
+                (start_addr2, 0x400, Some(FileOffset::new(f2, 0))),
+            ],
+        )
         .unwrap();
 
         let guest_mem_list = vec![guest_mem, guest_mem_backed_by_file];
@@ -1051,12 +1591,18 @@ mod tests {
 
         let start_addr1 = GuestAddress(0x0);
         let start_addr2 = GuestAddress(0x800);
-        let guest_mem =
-            GuestMemoryMmap::from_ranges(&[(start_addr1, 0x400), (start_addr2, 0x400)]).unwrap();
-        let guest_mem_backed_by_file = GuestMemoryMmap::from_ranges_with_files(&[
-            (start_addr1, 0x400, Some(FileOffset::new(f1, 0))),
-            (start_addr2, 0x400, Some(FileOffset::new(f2, 0))),
-        ])
+        let guest_mem = GuestMemoryMmap::from_ranges(
+            local_pid(),
+            &[(start_addr1, 0x400), (start_addr2, 0x400)],
+        )
+        .unwrap();
+        let guest_mem_backed_by_file = GuestMemoryMmap::from_ranges_with_files(
+            local_pid(),
+            &[
+                (start_addr1, 0x400, Some(FileOffset::new(f1, 0))),
+                (start_addr2, 0x400, Some(FileOffset::new(f2, 0))),
+            ],
+        )
         .unwrap();
 
         let guest_mem_list = vec![guest_mem, guest_mem_backed_by_file];
@@ -1083,12 +1629,18 @@ mod tests {
 
         let start_addr1 = GuestAddress(0x0);
         let start_addr2 = GuestAddress(0x800);
-        let guest_mem =
-            GuestMemoryMmap::from_ranges(&[(start_addr1, 0x400), (start_addr2, 0x400)]).unwrap();
-        let guest_mem_backed_by_file = GuestMemoryMmap::from_ranges_with_files(&[
-            (start_addr1, 0x400, Some(FileOffset::new(f1, 0))),
-            (start_addr2, 0x400, Some(FileOffset::new(f2, 0))),
-        ])
+        let guest_mem = GuestMemoryMmap::from_ranges(
+            local_pid(),
+            &[(start_addr1, 0x400), (start_addr2, 0x400)],
+        )
+        .unwrap();
+        let guest_mem_backed_by_file = GuestMemoryMmap::from_ranges_with_files(
+            local_pid(),
+            &[
+                (start_addr1, 0x400, Some(FileOffset::new(f1, 0))),
+                (start_addr2, 0x400, Some(FileOffset::new(f2, 0))),
+            ],
+        )
         .unwrap();
 
         let guest_mem_list = vec![guest_mem, guest_mem_backed_by_file];
@@ -1111,12 +1663,18 @@ mod tests {
 
         let start_addr1 = GuestAddress(0x0);
         let start_addr2 = GuestAddress(0x800);
-        let guest_mem =
-            GuestMemoryMmap::from_ranges(&[(start_addr1, 0x400), (start_addr2, 0x400)]).unwrap();
-        let guest_mem_backed_by_file = GuestMemoryMmap::from_ranges_with_files(&[
-            (start_addr1, 0x400, Some(FileOffset::new(f1, 0))),
-            (start_addr2, 0x400, Some(FileOffset::new(f2, 0))),
-        ])
+        let guest_mem = GuestMemoryMmap::from_ranges(
+            local_pid(),
+            &[(start_addr1, 0x400), (start_addr2, 0x400)],
+        )
+        .unwrap();
+        let guest_mem_backed_by_file = GuestMemoryMmap::from_ranges_with_files(
+            local_pid(),
+            &[
+                (start_addr1, 0x400, Some(FileOffset::new(f1, 0))),
+                (start_addr2, 0x400, Some(FileOffset::new(f2, 0))),
+            ],
+        )
         .unwrap();
 
         let guest_mem_list = vec![guest_mem, guest_mem_backed_by_file];
@@ -1138,12 +1696,11 @@ mod tests {
         f.set_len(0x400).unwrap();
 
         let start_addr = GuestAddress(0x0);
-        let guest_mem = GuestMemoryMmap::from_ranges(&[(start_addr, 0x400)]).unwrap();
-        let guest_mem_backed_by_file = GuestMemoryMmap::from_ranges_with_files(&[(
-            start_addr,
-            0x400,
-            Some(FileOffset::new(f, 0)),
-        )])
+        let guest_mem = GuestMemoryMmap::from_ranges(local_pid(), &[(start_addr, 0x400)]).unwrap();
+        let guest_mem_backed_by_file = GuestMemoryMmap::from_ranges_with_files(
+            local_pid(),
+            &[(start_addr, 0x400, Some(FileOffset::new(f, 0)))],
+        )
         .unwrap();
 
         let guest_mem_list = vec![guest_mem, guest_mem_backed_by_file];
@@ -1176,12 +1733,18 @@ mod tests {
         let bad_addr2 = GuestAddress(0x1ffc);
         let max_addr = GuestAddress(0x2000);
 
-        let gm =
-            GuestMemoryMmap::from_ranges(&[(start_addr1, 0x1000), (start_addr2, 0x1000)]).unwrap();
-        let gm_backed_by_file = GuestMemoryMmap::from_ranges_with_files(&[
-            (start_addr1, 0x1000, Some(FileOffset::new(f1, 0))),
-            (start_addr2, 0x1000, Some(FileOffset::new(f2, 0))),
-        ])
+        let gm = GuestMemoryMmap::from_ranges(
+            local_pid(),
+            &[(start_addr1, 0x1000), (start_addr2, 0x1000)],
+        )
+        .unwrap();
+        let gm_backed_by_file = GuestMemoryMmap::from_ranges_with_files(
+            local_pid(),
+            &[
+                (start_addr1, 0x1000, Some(FileOffset::new(f1, 0))),
+                (start_addr2, 0x1000, Some(FileOffset::new(f2, 0))),
+            ],
+        )
         .unwrap();
 
         let gm_list = vec![gm, gm_backed_by_file];
@@ -1216,12 +1779,11 @@ mod tests {
         f.set_len(0x400).unwrap();
 
         let mut start_addr = GuestAddress(0x1000);
-        let gm = GuestMemoryMmap::from_ranges(&[(start_addr, 0x400)]).unwrap();
-        let gm_backed_by_file = GuestMemoryMmap::from_ranges_with_files(&[(
-            start_addr,
-            0x400,
-            Some(FileOffset::new(f, 0)),
-        )])
+        let gm = GuestMemoryMmap::from_ranges(local_pid(), &[(start_addr, 0x400)]).unwrap();
+        let gm_backed_by_file = GuestMemoryMmap::from_ranges_with_files(
+            local_pid(),
+            &[(start_addr, 0x400, Some(FileOffset::new(f, 0)))],
+        )
         .unwrap();
 
         let gm_list = vec![gm, gm_backed_by_file];
@@ -1247,12 +1809,12 @@ mod tests {
         let f = TempFile::new().unwrap().into_file();
         f.set_len(0x400).unwrap();
 
-        let gm = GuestMemoryMmap::from_ranges(&[(GuestAddress(0x1000), 0x400)]).unwrap();
-        let gm_backed_by_file = GuestMemoryMmap::from_ranges_with_files(&[(
-            GuestAddress(0x1000),
-            0x400,
-            Some(FileOffset::new(f, 0)),
-        )])
+        let gm =
+            GuestMemoryMmap::from_ranges(local_pid(), &[(GuestAddress(0x1000), 0x400)]).unwrap();
+        let gm_backed_by_file = GuestMemoryMmap::from_ranges_with_files(
+            local_pid(),
+            &[(GuestAddress(0x1000), 0x400, Some(FileOffset::new(f, 0)))],
+        )
         .unwrap();
 
         let gm_list = vec![gm, gm_backed_by_file];
@@ -1284,6 +1846,58 @@ mod tests {
         }
     }
 
+    #[test]
+    fn read_from_and_write_to_in_chunks() {
+        let (_child, gm) = TestProcess::guest_memory(&[(GuestAddress(0), 0x4000)]);
+        let region = gm.find_region(GuestAddress(0)).unwrap();
+        assert!(!region.is_local());
+        let image: Vec<u8> = (0..0x2800u32).map(|i| (i % 251) as u8).collect();
+
+        // Larger than a single chunk.
+        let addr = MemoryRegionAddress(0x10);
+        assert_eq!(
+            region
+                .read_from(addr, &mut Cursor::new(&image), image.len())
+                .unwrap(),
+            image.len()
+        );
+        let mut sink = Vec::new();
+        assert_eq!(
+            region.write_to(addr, &mut sink, image.len()).unwrap(),
+            image.len()
+        );
+        assert_eq!(sink, image);
+
+        // The source runs dry before `count` bytes were read.
+        assert_eq!(
+            region
+                .read_from(addr, &mut Cursor::new(&image[..10]), 0x100)
+                .unwrap(),
+            10
+        );
+        assert!(region
+            .read_exact_from(addr, &mut Cursor::new(&image[..10]), 0x100)
+            .is_err());
+
+        // The destination takes fewer bytes than requested.
+        let mut small = [0u8; 0x20];
+        assert_eq!(
+            region
+                .write_to(addr, &mut Cursor::new(&mut small[..]), 0x100)
+                .unwrap(),
+            0x20
+        );
+        assert_eq!(small[..], image[..0x20]);
+
+        // Accesses past the end of the region are rejected.
+        assert!(region
+            .read_from(MemoryRegionAddress(0x3f00), &mut Cursor::new(&image), 0x200)
+            .is_err());
+        assert!(region
+            .write_all_to(MemoryRegionAddress(0x3f00), &mut sink, 0x200)
+            .is_err());
+    }
+
     #[test]
     fn create_vec_with_regions() {
         let region_size = 0x400;
@@ -1292,7 +1906,7 @@ mod tests {
             (GuestAddress(0x1000), region_size),
         ];
         let mut iterated_regions = Vec::new();
-        let gm = GuestMemoryMmap::from_ranges(&regions).unwrap();
+        let gm = GuestMemoryMmap::from_ranges(local_pid(), &regions).unwrap();
         let res: guest_memory::Result<()> = gm.with_regions(|_, region| {
             assert_eq!(region.len(), region_size as GuestUsize);
             Ok(())
@@ -1322,7 +1936,7 @@ mod tests {
             (GuestAddress(0x1000), region_size),
         ];
         let mut iterated_regions = Vec::new();
-        let gm = Arc::new(GuestMemoryMmap::from_ranges(&regions).unwrap());
+        let gm = Arc::new(GuestMemoryMmap::from_ranges(local_pid(), &regions).unwrap());
         let mem = gm.memory();
 
         let res: guest_memory::Result<()> = mem.with_regions(|_, region| {
@@ -1355,12 +1969,18 @@ mod tests {
 
         let start_addr1 = GuestAddress(0x0);
         let start_addr2 = GuestAddress(0x1000);
-        let gm =
-            GuestMemoryMmap::from_ranges(&[(start_addr1, 0x1000), (start_addr2, 0x1000)]).unwrap();
-        let gm_backed_by_file = GuestMemoryMmap::from_ranges_with_files(&[
-            (start_addr1, 0x1000, Some(FileOffset::new(f1, 0))),
-            (start_addr2, 0x1000, Some(FileOffset::new(f2, 0))),
-        ])
+        let gm = GuestMemoryMmap::from_ranges(
+            local_pid(),
+            &[(start_addr1, 0x1000), (start_addr2, 0x1000)],
+        )
+        .unwrap();
+        let gm_backed_by_file = GuestMemoryMmap::from_ranges_with_files(
+            local_pid(),
+            &[
+                (start_addr1, 0x1000, Some(FileOffset::new(f1, 0))),
+                (start_addr2, 0x1000, Some(FileOffset::new(f2, 0))),
+            ],
+        )
         .unwrap();
 
         let gm_list = vec![gm, gm_backed_by_file];
@@ -1379,16 +1999,15 @@ mod tests {
         f.set_len(0x400).unwrap();
 
         let start_addr = GuestAddress(0x0);
-        let gm = GuestMemoryMmap::from_ranges(&[(start_addr, 0x400)]).unwrap();
+        let gm = GuestMemoryMmap::from_ranges(local_pid(), &[(start_addr, 0x400)]).unwrap();
         assert!(gm.find_region(start_addr).is_some());
         let region = gm.find_region(start_addr).unwrap();
         assert!(region.file_offset().is_none());
 
-        let gm = GuestMemoryMmap::from_ranges_with_files(&[(
-            start_addr,
-            0x400,
-            Some(FileOffset::new(f, 0)),
-        )])
+        let gm = GuestMemoryMmap::from_ranges_with_files(
+            local_pid(),
+            &[(start_addr, 0x400, Some(FileOffset::new(f, 0)))],
+        )
         .unwrap();
         assert!(gm.find_region(start_addr).is_some());
         let region = gm.find_region(start_addr).unwrap();
@@ -1408,16 +2027,15 @@ mod tests {
         let offset = 0x1000;
 
         let start_addr = GuestAddress(0x0);
-        let gm = GuestMemoryMmap::from_ranges(&[(start_addr, 0x400)]).unwrap();
+        let gm = GuestMemoryMmap::from_ranges(local_pid(), &[(start_addr, 0x400)]).unwrap();
         assert!(gm.find_region(start_addr).is_some());
         let region = gm.find_region(start_addr).unwrap();
         assert!(region.file_offset().is_none());
 
-        let gm = GuestMemoryMmap::from_ranges_with_files(&[(
-            start_addr,
-            0x400,
-            Some(FileOffset::new(f, offset)),
-        )])
+        let gm = GuestMemoryMmap::from_ranges_with_files(
+            local_pid(),
+            &[(start_addr, 0x400, Some(FileOffset::new(f, offset)))],
+        )
         .unwrap();
         assert!(gm.find_region(start_addr).is_some());
         let region = gm.find_region(start_addr).unwrap();
@@ -1432,24 +2050,44 @@ mod tests {
             (GuestAddress(0x0), region_size),
             (GuestAddress(0x10_0000), region_size),
         ];
-        let gm = Arc::new(GuestMemoryMmap::from_ranges(&regions).unwrap());
+        let gm = Arc::new(GuestMemoryMmap::from_ranges(local_pid(), &regions).unwrap());
         let mem_orig = gm.memory();
         assert_eq!(mem_orig.num_regions(), 2);
 
         let mmap = Arc::new(
-            GuestRegionMmap::new(MmapRegion::new(0x1000).unwrap(), GuestAddress(0x8000)).unwrap(),
+            GuestRegionMmap::new(
+                local_pid(),
+                MmapRegion::new(0x1000).unwrap(),
+                GuestAddress(0x8000),
+            )
+            .unwrap(),
         );
         let gm = gm.insert_region(mmap).unwrap();
         let mmap = Arc::new(
-            GuestRegionMmap::new(MmapRegion::new(0x1000).unwrap(), GuestAddress(0x4000)).unwrap(),
+            GuestRegionMmap::new(
+                local_pid(),
+                MmapRegion::new(0x1000).unwrap(),
+                GuestAddress(0x4000),
+            )
+            .unwrap(),
         );
         let gm = gm.insert_region(mmap).unwrap();
         let mmap = Arc::new(
-            GuestRegionMmap::new(MmapRegion::new(0x1000).unwrap(), GuestAddress(0xc000)).unwrap(),
+            GuestRegionMmap::new(
+                local_pid(),
+                MmapRegion::new(0x1000).unwrap(),
+                GuestAddress(0xc000),
+            )
+            .unwrap(),
         );
         let gm = gm.insert_region(mmap).unwrap();
         let mmap = Arc::new(
-            GuestRegionMmap::new(MmapRegion::new(0x1000).unwrap(), GuestAddress(0xc000)).unwrap(),
+            GuestRegionMmap::new(
+                local_pid(),
+                MmapRegion::new(0x1000).unwrap(),
+                GuestAddress(0xc000),
+            )
+            .unwrap(),
         );
         gm.insert_region(mmap).unwrap_err();
 
@@ -1470,7 +2108,7 @@ mod tests {
             (GuestAddress(0x0), region_size),
             (GuestAddress(0x10_0000), region_size),
         ];
-        let gm = Arc::new(GuestMemoryMmap::from_ranges(&regions).unwrap());
+        let gm = Arc::new(GuestMemoryMmap::from_ranges(local_pid(), &regions).unwrap());
         let mem_orig = gm.memory();
         assert_eq!(mem_orig.num_regions(), 2);
 
@@ -1489,8 +2127,12 @@ mod tests {
     fn test_guest_memory_mmap_get_slice() {
         let region_addr = GuestAddress(0);
         let region_size = 0x400;
-        let region =
-            GuestRegionMmap::new(MmapRegion::new(region_size).unwrap(), region_addr).unwrap();
+        let region = GuestRegionMmap::new(
+            local_pid(),
+            MmapRegion::new(region_size).unwrap(),
+            region_addr,
+        )
+        .unwrap();
 
         // Normal case.
         let slice_addr = MemoryRegionAddress(0x100);
@@ -1514,8 +2156,12 @@ mod tests {
     fn test_guest_memory_mmap_as_volatile_slice() {
         let region_addr = GuestAddress(0);
         let region_size = 0x400;
-        let region =
-            GuestRegionMmap::new(MmapRegion::new(region_size).unwrap(), region_addr).unwrap();
+        let region = GuestRegionMmap::new(
+            local_pid(),
+            MmapRegion::new(region_size).unwrap(),
+            region_addr,
+        )
+        .unwrap();
 
         // Test slice length.
         let slice = region.as_volatile_slice().unwrap();
@@ -1532,8 +2178,11 @@ mod tests {
     fn test_guest_memory_get_slice() {
         let start_addr1 = GuestAddress(0);
         let start_addr2 = GuestAddress(0x800);
-        let guest_mem =
-            GuestMemoryMmap::from_ranges(&[(start_addr1, 0x400), (start_addr2, 0x400)]).unwrap();
+        let guest_mem = GuestMemoryMmap::from_ranges(
+            local_pid(),
+            &[(start_addr1, 0x400), (start_addr2, 0x400)],
+        )
+        .unwrap();
 
         // Normal cases.
         let slice_size = 0x200;
@@ -1565,11 +2214,14 @@ mod tests {
         let start_addr1 = GuestAddress(0);
         let start_addr2 = GuestAddress(0x800);
         let start_addr3 = GuestAddress(0xc00);
-        let guest_mem = GuestMemoryMmap::from_ranges(&[
-            (start_addr1, 0x400),
-            (start_addr2, 0x400),
-            (start_addr3, 0x400),
-        ])
+        let guest_mem = GuestMemoryMmap::from_ranges(
+            local_pid(),
+            &[
+                (start_addr1, 0x400),
+                (start_addr2, 0x400),
+                (start_addr3, 0x400),
+            ],
+        )
         .unwrap();
 
         assert_eq!(
@@ -1599,11 +2251,14 @@ mod tests {
         let start_addr1 = GuestAddress(0);
         let start_addr2 = GuestAddress(0x800);
         let start_addr3 = GuestAddress(0xc00);
-        let guest_mem = GuestMemoryMmap::from_ranges(&[
-            (start_addr1, 0x400),
-            (start_addr2, 0x400),
-            (start_addr3, 0x400),
-        ])
+        let guest_mem = GuestMemoryMmap::from_ranges(
+            local_pid(),
+            &[
+                (start_addr1, 0x400),
+                (start_addr2, 0x400),
+                (start_addr3, 0x400),
+            ],
+        )
         .unwrap();
 
         assert_eq!(guest_mem.check_range(start_addr1, 0x0), true);
@@ -1619,8 +2274,12 @@ mod tests {
 
     #[test]
     fn test_atomic_accesses() {
-        let region =
-            GuestRegionMmap::new(MmapRegion::new(0x1000).unwrap(), GuestAddress(0)).unwrap();
+        let region = GuestRegionMmap::new(
+            local_pid(),
+            MmapRegion::new(0x1000).unwrap(),
+            GuestAddress(0),
+        )
+        .unwrap();
 
         crate::bytes::tests::check_atomic_accesses(
             region,
@@ -1628,4 +2287,597 @@ mod tests {
             MemoryRegionAddress(0x1000),
         );
     }
+
+    #[test]
+    fn read_write_across_regions() {
+        let (_child, gm) = TestProcess::guest_memory(&[
+            (GuestAddress(0), 0x1000),
+            (GuestAddress(0x1000), 0x1000),
+            (GuestAddress(0x2000), 0x1000),
+        ]);
+        let data: Vec<u8> = (0..0x2000u32).map(|i| (i % 253) as u8).collect();
+
+        gm.write_slice(&data, GuestAddress(0x800)).unwrap();
+        let mut buf = vec![0u8; data.len()];
+        gm.read_slice(&mut buf, GuestAddress(0x800)).unwrap();
+        assert_eq!(buf, data);
+        for (i, region) in gm.iter().take(2).enumerate() {
+            let mut b = [0u8; 1];
+            region
+                .read_slice(&mut b, MemoryRegionAddress(0x800))
+                .unwrap();
+            assert_eq!(b[0], data[i * 0x1000]);
+        }
+
+        // Accesses running past the end of guest memory are short.
+        assert_eq!(gm.write(&data, GuestAddress(0x2800)).unwrap(), 0x800);
+        assert_eq!(gm.read(&mut buf, GuestAddress(0x2800)).unwrap(), 0x800);
+        assert_eq!(buf[..0x800], data[..0x800]);
+        assert!(gm.read(&mut buf, GuestAddress(0x3000)).is_err());
+    }
+
+    #[test]
+    fn local_transport() {
+        // Safe because the transport only accesses the mappings of `gm`.
+        let transport: Arc<dyn Transport> = Arc::new(unsafe { remote_mem::Local::new() });
+        let gm = GuestMemoryMmap::from_ranges_with_transport(
+            transport.clone(),
+            vec![
+                (GuestAddress(0), 0x1000, None),
+                (GuestAddress(0x1000), 0x1000, None),
+            ],
+        )
+        .unwrap();
+        assert!(gm
+            .iter()
+            .all(|r| !r.is_local() && Arc::ptr_eq(r.transport().unwrap(), &transport)));
+
+        gm.write_obj(0x1234_5678u32, GuestAddress(0xffe)).unwrap();
+        assert_eq!(
+            gm.read_obj::<u32>(GuestAddress(0xffe)).unwrap(),
+            0x1234_5678
+        );
+        gm.store(7u16, GuestAddress(0x1002), Ordering::Relaxed)
+            .unwrap();
+        assert_eq!(
+            gm.load::<u16>(GuestAddress(0x1002), Ordering::Relaxed)
+                .unwrap(),
+            7
+        );
+    }
+
+    #[test]
+    fn shared_transport() {
+        use crate::stats::Instrumented;
+
+        let child = TestProcess::spawn(&[0x1000, 0x1000]);
+        let stats = Arc::new(Stats::new());
+        let transport: Arc<dyn Transport> = Arc::new(Instrumented::new(
+            default_transport(Pid::from_raw(child.pid())),
+            stats.clone(),
+        ));
+        let prot = libc::PROT_READ | libc::PROT_WRITE;
+        let flags = libc::MAP_PRIVATE | libc::MAP_ANONYMOUS;
+        let regions = vec![
+            (
+                RemoteRegion::new(child.addr(0), 0x1000, prot, flags).unwrap(),
+                GuestAddress(0),
+            ),
+            (
+                RemoteRegion::new(child.addr(1), 0x1000, prot, flags).unwrap(),
+                GuestAddress(0x1000),
+            ),
+        ];
+        let gm = GuestMemoryMmap::from_regions_with_transport(transport.clone(), regions).unwrap();
+        assert!(gm
+            .iter()
+            .all(|r| Arc::ptr_eq(r.transport().unwrap(), &transport)));
+
+        // The accesses span both regions, but take a single vectored call each.
+        let data = [0x5au8; 0x10];
+        gm.write_slice(&data, GuestAddress(0xff8)).unwrap();
+        let mut buf = [0u8; 0x10];
+        gm.read_slice(&mut buf, GuestAddress(0xff8)).unwrap();
+        assert_eq!(buf, data);
+        assert_eq!(stats.snapshot().syscalls, 2);
+    }
+
+    #[test]
+    fn foreign_process() {
+        let child = TestProcess::spawn(&[0x1000]);
+        let pid = child.pid();
+        assert!(matches!(
+            GuestRegionMmap::new(pid, MmapRegion::new(0x1000).unwrap(), GuestAddress(0)),
+            Err(Error::ForeignProcess(p)) if p == pid
+        ));
+        assert!(matches!(
+            GuestMemoryMmap::from_ranges(pid, &[(GuestAddress(0), 0x1000)]),
+            Err(Error::ForeignProcess(p)) if p == pid
+        ));
+        let transport = default_transport(Pid::from_raw(pid));
+        assert!(matches!(
+            GuestRegionMmap::with_transport(
+                transport.clone(),
+                MmapRegion::new(0x1000).unwrap(),
+                GuestAddress(0)
+            ),
+            Err(Error::ForeignProcess(p)) if p == pid
+        ));
+        assert!(matches!(
+            GuestMemoryMmap::from_ranges_with_transport(
+                transport,
+                &[(GuestAddress(0), 0x1000, None)]
+            ),
+            Err(Error::ForeignProcess(p)) if p == pid
+        ));
+    }
+
+    #[test]
+    fn local_and_remote_regions() {
+        let pid = std::process::id() as pid_t;
+        let gm = GuestMemoryMmap::from_ranges(pid, &[(GuestAddress(0), 0x1000)]).unwrap();
+        assert!(gm.iter().all(|r| r.is_local() && r.transport().is_none()));
+
+        // Safe because the transport only accesses the mapping of `remote`.
+        let transport: Arc<dyn Transport> = Arc::new(unsafe { remote_mem::Local::new() });
+        let local =
+            GuestRegionMmap::new_local(MmapRegion::new(0x1000).unwrap(), GuestAddress(0)).unwrap();
+        let remote = GuestRegionMmap::with_transport(
+            transport,
+            MmapRegion::new(0x1000).unwrap(),
+            GuestAddress(0x1000),
+        )
+        .unwrap();
+        assert!(local.as_volatile_slice().unwrap().pid().is_none());
+        assert!(remote.as_volatile_slice().unwrap().pid().is_some());
+        assert!(unsafe { local.as_slice() }.is_some());
+        assert!(unsafe { remote.as_slice() }.is_none());
+
+        let gm = GuestMemoryMmap::from_regions(pid, vec![local, remote]).unwrap();
+        gm.write_obj(0x1122_3344_5566_7788u64, GuestAddress(0xffc))
+            .unwrap();
+        assert_eq!(
+            gm.read_obj::<u64>(GuestAddress(0xffc)).unwrap(),
+            0x1122_3344_5566_7788
+        );
+        for addr in [0x8, 0x1008].iter() {
+            gm.store(0xabu8, GuestAddress(*addr), Ordering::Relaxed)
+                .unwrap();
+            assert_eq!(
+                gm.load::<u8>(GuestAddress(*addr), Ordering::Relaxed)
+                    .unwrap(),
+                0xab
+            );
+        }
+    }
+
+    #[test]
+    fn from_hints() {
+        let pid = std::process::id() as pid_t;
+        let ram = MmapRegion::new(0x4000).unwrap();
+        let hva = ram.as_ptr() as usize;
+        let gm = GuestMemoryMmap::from_hints(
+            pid,
+            &[
+                (GuestAddress(0), hva, 0x2000),
+                (GuestAddress(0x10_0000), hva + 0x2000, 0x2000),
+            ],
+        )
+        .unwrap();
+
+        gm.write_obj(0xdeadu16, GuestAddress(0x10_0004)).unwrap();
+        assert_eq!(
+            ram.as_volatile_slice().read_obj::<u16>(0x2004).unwrap(),
+            0xdead
+        );
+        let region = gm.find_region(GuestAddress(0)).unwrap();
+        assert_eq!(region.prot(), libc::PROT_READ | libc::PROT_WRITE);
+        assert_eq!(region.flags(), libc::MAP_PRIVATE | libc::MAP_ANONYMOUS);
+        assert_eq!(region.is_hugetlbfs(), Some(false));
+
+        // The first pages are never mapped.
+        let err =
+            GuestMemoryMmap::from_hints(pid, &[(GuestAddress(0), 0x1000, 0x1000)]).unwrap_err();
+        assert_eq!(
+            format!("{}", err),
+            "Host range 0x1000+0x1000 isn't mapped read-write in the remote process"
+        );
+        assert!(GuestMemoryMmap::from_hints(-1, &[(GuestAddress(0), hva, 0x1000)]).is_err());
+    }
+
+    #[test]
+    fn map_shared_not_shared_file() {
+        let pid = std::process::id() as pid_t;
+        // Private anonymous memory can't be mapped again.
+        let anon = MmapRegion::new(0x1000).unwrap();
+        let hints = [(GuestAddress(0), anon.as_ptr() as usize, 0x1000)];
+        let gm = GuestMemoryMmap::from_hints(pid, &hints).unwrap();
+        assert!(matches!(
+            gm.map_shared().unwrap_err(),
+            Error::NotSharedFile { .. }
+        ));
+        assert!(matches!(
+            GuestMemoryMmap::from_map_files(pid, &hints).unwrap_err(),
+            Error::NotSharedFile { .. }
+        ));
+    }
+
+    // Opening `map_files` requires `CAP_SYS_ADMIN`, run with `cargo test -- --ignored`.
+    #[test]
+    #[ignore = "needs CAP_SYS_ADMIN to open /proc/<pid>/map_files"]
+    fn map_shared() {
+        let pid = std::process::id() as pid_t;
+        let f = TempFile::new().unwrap().into_file();
+        f.set_len(0x2000).unwrap();
+        let ram = MmapRegion::from_file(FileOffset::new(f, 0), 0x2000).unwrap();
+        let hva = ram.as_ptr() as usize;
+        let gm = GuestMemoryMmap::from_hints(
+            pid,
+            &[
+                (GuestAddress(0), hva, 0x1000),
+                (GuestAddress(0x1000), hva + 0x1000, 0x1000),
+            ],
+        )
+        .unwrap();
+        assert!(gm
+            .compare_exchange(
+                0u32,
+                1,
+                GuestAddress(0x1004),
+                Ordering::SeqCst,
+                Ordering::SeqCst
+            )
+            .is_err());
+
+        let shared = gm.map_shared().unwrap();
+        assert!(shared.iter().all(|r| r.is_local()));
+        let region = shared.find_region(GuestAddress(0x1000)).unwrap();
+        assert_eq!(region.as_ptr() as usize, hva + 0x1000);
+        assert_eq!(region.file_offset().unwrap().start(), 0x1000);
+
+        gm.write_obj(7u32, GuestAddress(0x1004)).unwrap();
+        assert_eq!(
+            shared
+                .compare_exchange(
+                    7u32,
+                    8,
+                    GuestAddress(0x1004),
+                    Ordering::SeqCst,
+                    Ordering::SeqCst
+                )
+                .unwrap(),
+            Ok(7)
+        );
+        assert_eq!(gm.read_obj::<u32>(GuestAddress(0x1004)).unwrap(), 8);
+        assert_eq!(shared.map_shared().unwrap().num_regions(), 2);
+    }
+
+    #[test]
+    #[ignore = "needs CAP_SYS_ADMIN to open /proc/<pid>/map_files"]
+    fn from_map_files() {
+        let pid = std::process::id() as pid_t;
+        let f = TempFile::new().unwrap().into_file();
+        f.set_len(0x3000).unwrap();
+        let ram = MmapRegion::from_file(FileOffset::new(f, 0x1000), 0x2000).unwrap();
+        let hva = ram.as_ptr() as usize;
+
+        let region =
+            GuestRegionMmap::from_map_files(pid, hva + 0x1000, 0x1000, GuestAddress(0x1000))
+                .unwrap();
+        assert!(region.is_local());
+        assert_eq!(region.file_offset().unwrap().start(), 0x2000);
+        assert_eq!(region.is_hugetlbfs(), Some(false));
+        assert!(unsafe { region.as_slice() }.is_some());
+        ram.as_volatile_slice()
+            .write_obj(0xbeefu16, 0x1010)
+            .unwrap();
+        assert_eq!(
+            region.read_obj::<u16>(MemoryRegionAddress(0x10)).unwrap(),
+            0xbeef
+        );
+
+        let gm = GuestMemoryMmap::from_map_files(
+            pid,
+            &[
+                (GuestAddress(0), hva, 0x1000),
+                (GuestAddress(0x1000), hva + 0x1000, 0x1000),
+            ],
+        )
+        .unwrap();
+        assert!(gm.iter().all(|r| r.is_local()));
+        gm.write_obj(0x1234u16, GuestAddress(0x20)).unwrap();
+        assert_eq!(
+            ram.as_volatile_slice().read_obj::<u16>(0x20).unwrap(),
+            0x1234
+        );
+    }
+
+    #[test]
+    fn remote_region_bytes() {
+        let (child, gm) = TestProcess::guest_memory(&[(GuestAddress(0), 0x1000)]);
+        let region = gm.find_region(GuestAddress(0)).unwrap();
+        assert!(!region.is_local());
+        assert_eq!(region.as_ptr() as usize, child.addr(0));
+        assert!(unsafe { region.as_slice() }.is_none());
+        assert_eq!(
+            region.as_volatile_slice().unwrap().pid(),
+            Some(Pid::from_raw(child.pid()))
+        );
+
+        let data = [1u8, 2, 3, 4, 5];
+        assert_eq!(region.write(&data, MemoryRegionAddress(0xffe)).unwrap(), 2);
+        region
+            .write_slice(&data, MemoryRegionAddress(0x10))
+            .unwrap();
+        let mut buf = [0u8; 5];
+        region
+            .read_slice(&mut buf, MemoryRegionAddress(0x10))
+            .unwrap();
+        assert_eq!(buf, data);
+        assert_eq!(
+            region.read(&mut buf, MemoryRegionAddress(0xffe)).unwrap(),
+            2
+        );
+        assert_eq!(buf[..2], data[..2]);
+        assert!(region.write(&data, MemoryRegionAddress(0x1000)).is_err());
+
+        region
+            .write_obj(0x0102_0304u32, MemoryRegionAddress(0x20))
+            .unwrap();
+        assert_eq!(
+            region.read_obj::<u32>(MemoryRegionAddress(0x20)).unwrap(),
+            0x0102_0304
+        );
+        region
+            .store(0xffu8, MemoryRegionAddress(0x21), Ordering::Relaxed)
+            .unwrap();
+        assert_eq!(
+            region
+                .load::<u32>(MemoryRegionAddress(0x20), Ordering::Relaxed)
+                .unwrap(),
+            0x0102_ff04
+        );
+        assert!(region
+            .store(0u32, MemoryRegionAddress(0xffe), Ordering::Relaxed)
+            .is_err());
+        assert!(region
+            .load::<u32>(MemoryRegionAddress(0xffe), Ordering::Relaxed)
+            .is_err());
+
+        let image: Vec<u8> = (0..0x100).map(|i| i as u8).collect();
+        region
+            .read_exact_from(MemoryRegionAddress(0x100), &mut Cursor::new(&image), 0x100)
+            .unwrap();
+        let mut sink = Vec::new();
+        region
+            .write_all_to(MemoryRegionAddress(0x100), &mut sink, 0x100)
+            .unwrap();
+        assert_eq!(sink, image);
+    }
+
+    #[test]
+    fn remote_try_access_across_regions() {
+        let (child, gm) = TestProcess::guest_memory(&[
+            (GuestAddress(0), 0x1000),
+            (GuestAddress(0x1000), 0x2000),
+            (GuestAddress(0x3000), 0x1000),
+        ]);
+        assert!(gm.iter().all(|r| !r.is_local()));
+
+        let mut visited = Vec::new();
+        gm.try_access(
+            0x2002,
+            GuestAddress(0xfff),
+            |offset, count, addr, region| {
+                visited.push((offset, count, addr, region.as_ptr() as usize));
+                Ok(count)
+            },
+        )
+        .unwrap();
+        assert_eq!(
+            visited,
+            vec![
+                (0, 1, MemoryRegionAddress(0xfff), child.addr(0)),
+                (1, 0x2000, MemoryRegionAddress(0), child.addr(1)),
+                (0x2001, 1, MemoryRegionAddress(0), child.addr(2)),
+            ]
+        );
+
+        let data: Vec<u8> = (0..0x2002).map(|i| (i % 251) as u8).collect();
+        gm.write_slice(&data, GuestAddress(0xfff)).unwrap();
+        let mut buf = vec![0u8; data.len()];
+        gm.read_slice(&mut buf, GuestAddress(0xfff)).unwrap();
+        assert_eq!(buf, data);
+
+        // The bytes ended up in the separate buffers of the child.
+        let transport = gm
+            .find_region(GuestAddress(0))
+            .unwrap()
+            .transport()
+            .unwrap();
+        let mut last = [0u8; 2];
+        transport
+            .read_bytes(&mut last[..1], child.addr(0) + 0xfff)
+            .unwrap();
+        transport.read_bytes(&mut last[1..], child.addr(2)).unwrap();
+        assert_eq!(last, [data[0], data[0x2001]]);
+    }
+
+    #[test]
+    fn remote_process_gone() {
+        let (child, gm) =
+            TestProcess::guest_memory(&[(GuestAddress(0), 0x1000), (GuestAddress(0x1000), 0x1000)]);
+        drop(child);
+
+        let mut buf = [0u8; 0x10];
+        let errors = vec![
+            gm.read_slice(&mut buf, GuestAddress(0xff8)).unwrap_err(),
+            gm.write_obj(1u32, GuestAddress(0x10)).unwrap_err(),
+            gm.load::<u16>(GuestAddress(0x1000), Ordering::Relaxed)
+                .unwrap_err(),
+        ];
+        for e in errors.iter() {
+            match e {
+                guest_memory::Error::RemoteMemError(e) => assert!(e.is_fatal()),
+                e => panic!("unexpected error {:?}", e),
+            }
+            assert!(std::error::Error::source(e).is_some());
+        }
+    }
+
+    #[test]
+    fn remote_stats() {
+        use crate::stats::{AccessEvent, Instrumented};
+        use std::sync::Mutex;
+
+        let child = TestProcess::spawn(&[0x1000, 0x1000]);
+        let stats = Arc::new(Stats::new());
+        let transport =
+            Instrumented::new(default_transport(Pid::from_raw(child.pid())), stats.clone());
+        let hints = [
+            (GuestAddress(0), child.addr(0), 0x1000),
+            (GuestAddress(0x1000), child.addr(1), 0x1000),
+        ];
+        let gm = GuestMemoryMmap::from_hints_with_transport(Arc::new(transport), &hints).unwrap();
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let e = events.clone();
+        stats.set_hook(Some(Arc::new(move |event: &AccessEvent| {
+            e.lock().unwrap().push((event.kind, event.addr, event.len))
+        })));
+
+        let mut buf = [0u8; 0x10];
+        gm.read_slice(&mut buf, GuestAddress(0xff8)).unwrap();
+        gm.write_obj(1u32, GuestAddress(0x10)).unwrap();
+        gm.store(2u16, GuestAddress(0x1002), Ordering::Relaxed)
+            .unwrap();
+        assert_eq!(
+            gm.load::<u16>(GuestAddress(0x1002), Ordering::Relaxed)
+                .unwrap(),
+            2
+        );
+
+        assert_eq!(
+            *events.lock().unwrap(),
+            vec![
+                (AccessKind::Read, GuestAddress(0xff8), 0x10),
+                (AccessKind::Write, GuestAddress(0x10), 4),
+                (AccessKind::Store, GuestAddress(0x1002), 2),
+                (AccessKind::Load, GuestAddress(0x1002), 2),
+            ]
+        );
+        let s = gm.stats();
+        assert_eq!(s, stats.snapshot());
+        assert_eq!(s.syscalls, 4);
+        assert_eq!(s.bytes_read, 0x10 + 8);
+        assert_eq!(s.bytes_written, 4 + 2);
+        assert_eq!(
+            GuestMemoryMmap::from_ranges(local_pid(), &[(GuestAddress(0), 0x1000)])
+                .unwrap()
+                .stats(),
+            StatsSnapshot::default()
+        );
+    }
+
+    #[test]
+    fn test_dirty_tracking() {
+        let ranges = [(GuestAddress(0), 0x4000), (GuestAddress(0x10000), 0x2000)];
+        let gm = GuestMemoryMmap::from_ranges(local_pid(), &ranges).unwrap();
+        assert!(matches!(
+            gm.clone().with_dirty_tracking(0x1000),
+            Err(Error::SharedRegion(GuestAddress(0)))
+        ));
+        let gm = gm.with_dirty_tracking(0x1000).unwrap();
+        assert!(matches!(
+            GuestMemoryMmap::from_ranges(local_pid(), &ranges)
+                .unwrap()
+                .with_dirty_tracking(0x1001),
+            Err(Error::InvalidPageSize(0x1001))
+        ));
+        assert!(gm.dirty_ranges().is_empty());
+
+        let mut buf = [0u8; 0x10];
+        gm.read_slice(&mut buf, GuestAddress(0x3ff0)).unwrap();
+        gm.load::<u32>(GuestAddress(0x10000), Ordering::Relaxed)
+            .unwrap();
+        assert!(gm.dirty_ranges().is_empty());
+
+        gm.write_slice(&[1u8; 2], GuestAddress(0xfff)).unwrap();
+        gm.store(2u16, GuestAddress(0x11002), Ordering::Relaxed)
+            .unwrap();
+        gm.read_exact_from(GuestAddress(0x3000), &mut Cursor::new(vec![1u8; 4]), 4)
+            .unwrap();
+        gm.get_slice(GuestAddress(0x10000), 0x1000)
+            .unwrap()
+            .write_obj(3u8, 0x10)
+            .unwrap();
+        assert!(gm.is_dirty(GuestAddress(0x1800)) && !gm.is_dirty(GuestAddress(0x2000)));
+        assert!(!gm.is_dirty(GuestAddress(0x20000)));
+        let dirty = vec![
+            (GuestAddress(0), 0x2000),
+            (GuestAddress(0x3000), 0x1000),
+            (GuestAddress(0x10000), 0x2000),
+        ];
+        assert_eq!(gm.dirty_ranges(), dirty);
+
+        // Regions are fetched and cleared one by one.
+        let region = gm.find_region(GuestAddress(0x10000)).unwrap();
+        let taken: Vec<_> = region.dirty_bitmap().unwrap().take_dirty_ranges().collect();
+        assert_eq!(taken, vec![(0, 0x2000)]);
+        assert_eq!(gm.take_dirty_ranges(), dirty[..2].to_vec());
+        assert!(gm.dirty_ranges().is_empty());
+
+        // Writes through references are marked by hand.
+        gm.get_slice(GuestAddress(0x2000), 4)
+            .unwrap()
+            .get_ref::<u32>(0)
+            .unwrap()
+            .store(4);
+        assert!(gm.dirty_ranges().is_empty());
+        // Up to the first hole, like all accesses.
+        gm.mark_dirty(GuestAddress(0x3ffc), 0xc008);
+        gm.mark_dirty(GuestAddress(0x10ffc), 8);
+        assert_eq!(
+            gm.dirty_ranges(),
+            vec![
+                (GuestAddress(0x3000), 0x1000),
+                (GuestAddress(0x10000), 0x2000)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_remote_dirty_tracking() {
+        use crate::write_buffer::WriteBuffer;
+
+        let (_child, gm) =
+            TestProcess::guest_memory(&[(GuestAddress(0), 0x2000), (GuestAddress(0x2000), 0x2000)]);
+        let gm = gm.with_dirty_tracking(0x1000).unwrap();
+
+        // A single vectored write across the regions.
+        gm.write_slice(&[1u8; 0x10], GuestAddress(0x1ff8)).unwrap();
+        gm.find_region(GuestAddress(0))
+            .unwrap()
+            .write_obj(2u32, MemoryRegionAddress(0x10))
+            .unwrap();
+        gm.store(3u32, GuestAddress(0x3ffc), Ordering::Relaxed)
+            .unwrap();
+        assert_eq!(
+            gm.take_dirty_ranges(),
+            vec![(GuestAddress(0), 0x2000), (GuestAddress(0x2000), 0x2000)]
+        );
+
+        gm.read_exact_from(GuestAddress(0x1000), &mut Cursor::new(vec![1u8; 4]), 4)
+            .unwrap();
+        assert_eq!(gm.take_dirty_ranges(), vec![(GuestAddress(0x1000), 0x1000)]);
+
+        let wb = WriteBuffer::new(&gm);
+        wb.write_slice(&[4u8; 4], GuestAddress(0x10)).unwrap();
+        wb.write_slice(&[4u8; 4], GuestAddress(0x3000)).unwrap();
+        assert!(gm.dirty_ranges().is_empty());
+        wb.flush().unwrap();
+        assert_eq!(
+            gm.take_dirty_ranges(),
+            vec![(GuestAddress(0), 0x1000), (GuestAddress(0x3000), 0x1000)]
+        );
+    }
 }
@@ -0,0 +1,180 @@
+//! Helper structure describing a memory mapping in another process.
+
+use std::result;
+
+use crate::guest_memory::FileOffset;
+use crate::mmap::MmapRegion;
+use crate::mmap_unix::Error;
+
+/// A specialized `Result` type for remote region operations.
+pub type Result<T> = result::Result<T, Error>;
+
+/// Describes a mapping in the virtual address space of another process.
+///
+/// Unlike [`MmapRegion`](struct.MmapRegion.html) this is only metadata: the address is never
+/// dereferenced in the current process and nothing is unmapped when the descriptor is dropped.
+/// The memory itself is accessed through a
+/// [`Transport`](../remote_mem/trait.Transport.html).
+#[derive(Clone, Debug)]
+pub struct RemoteRegion {
+    addr: usize,
+    size: usize,
+    file_offset: Option<FileOffset>,
+    prot: i32,
+    flags: i32,
+    hugetlbfs: Option<bool>,
+}
+
+impl RemoteRegion {
+    /// Creates a descriptor for an existing mapping in another process.
+    ///
+    /// # Arguments
+    /// * `addr` - Start address of the mapping in the remote process. Must be page-aligned.
+    /// * `size` - The size of the memory region in bytes. Must be a multiple of the page size.
+    /// * `prot` - The memory protection attributes of the mapping.
+    /// * `flags` - The flags the mapping was created with.
+    pub fn new(addr: usize, size: usize, prot: i32, flags: i32) -> Result<Self> {
+        // Safe because this call just returns the page size and doesn't have any side effects.
+        let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as usize;
+
+        if addr & (page_size - 1) != 0 {
+            return Err(Error::InvalidPointer);
+        }
+        if size & (page_size - 1) != 0 {
+            return Err(Error::InvalidSize);
+        }
+        if addr.checked_add(size).is_none() {
+            return Err(Error::InvalidOffsetLength);
+        }
+
+        Ok(RemoteRegion {
+            addr,
+            size,
+            file_offset: None,
+            prot,
+            flags,
+            hugetlbfs: None,
+        })
+    }
+
+    /// Records the file and offset backing the mapping.
+    pub fn with_file_offset(mut self, file_offset: FileOffset) -> Self {
+        self.file_offset = Some(file_offset);
+        self
+    }
+
+    /// Returns the start address of the mapping in the remote process.
+    ///
+    /// The pointer must not be dereferenced in the current process.
+    pub fn as_ptr(&self) -> *mut u8 {
+        self.addr as *mut u8
+    }
+
+    /// Returns the size of this region.
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Returns the size of this region.
+    pub fn len(&self) -> usize {
+        self.size
+    }
+
+    /// Returns `true` if the region is empty.
+    pub fn is_empty(&self) -> bool {
+        self.size == 0
+    }
+
+    /// Returns information regarding the offset into the file backing this region (if any).
+    pub fn file_offset(&self) -> Option<&FileOffset> {
+        self.file_offset.as_ref()
+    }
+
+    /// Returns the memory protection attributes of the mapping.
+    pub fn prot(&self) -> i32 {
+        self.prot
+    }
+
+    /// Returns the flags the mapping was created with.
+    pub fn flags(&self) -> i32 {
+        self.flags
+    }
+
+    /// Set the hugetlbfs of the region
+    pub fn set_hugetlbfs(&mut self, hugetlbfs: bool) {
+        self.hugetlbfs = Some(hugetlbfs)
+    }
+
+    /// Returns `true` if the region is hugetlbfs
+    pub fn is_hugetlbfs(&self) -> Option<bool> {
+        self.hugetlbfs
+    }
+}
+
+impl From<&MmapRegion> for RemoteRegion {
+    /// Describes a mapping of the current process, as seen by a transport accessing it.
+    fn from(mapping: &MmapRegion) -> Self {
+        RemoteRegion {
+            addr: mapping.as_ptr() as usize,
+            size: mapping.size(),
+            file_offset: mapping.file_offset().cloned(),
+            prot: mapping.prot(),
+            flags: mapping.flags(),
+            hugetlbfs: mapping.is_hugetlbfs(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use vmm_sys_util::tempfile::TempFile;
+
+    #[test]
+    fn test_remote_region_new() {
+        let prot = libc::PROT_READ | libc::PROT_WRITE;
+        let flags = libc::MAP_PRIVATE | libc::MAP_ANONYMOUS;
+
+        let r = RemoteRegion::new(0x10_0000, 0x2000, prot, flags).unwrap();
+        assert_eq!(r.as_ptr() as usize, 0x10_0000);
+        assert_eq!(r.size(), 0x2000);
+        assert_eq!(r.len(), 0x2000);
+        assert!(!r.is_empty());
+        assert_eq!(r.prot(), prot);
+        assert_eq!(r.flags(), flags);
+        assert!(r.file_offset().is_none());
+        assert!(r.is_hugetlbfs().is_none());
+
+        let f = TempFile::new().unwrap().into_file();
+        let mut r = r.with_file_offset(FileOffset::new(f, 0x1000));
+        r.set_hugetlbfs(true);
+        assert_eq!(r.file_offset().unwrap().start(), 0x1000);
+        assert_eq!(r.is_hugetlbfs(), Some(true));
+
+        assert!(matches!(
+            RemoteRegion::new(0x10_0001, 0x1000, prot, flags),
+            Err(Error::InvalidPointer)
+        ));
+        assert!(matches!(
+            RemoteRegion::new(0x10_0000, 0x1001, prot, flags),
+            Err(Error::InvalidSize)
+        ));
+        assert!(matches!(
+            RemoteRegion::new(usize::max_value() & !0xfff, 0x1000, prot, flags),
+            Err(Error::InvalidOffsetLength)
+        ));
+    }
+
+    #[test]
+    fn test_remote_region_from_mmap() {
+        let mut m = MmapRegion::new(0x1000).unwrap();
+        m.set_hugetlbfs(false);
+        let r = RemoteRegion::from(&m);
+        assert_eq!(r.as_ptr(), m.as_ptr());
+        assert_eq!(r.size(), m.size());
+        assert_eq!(r.prot(), m.prot());
+        assert_eq!(r.flags(), m.flags());
+        assert_eq!(r.is_hugetlbfs(), Some(false));
+    }
+}
@@ -343,6 +343,7 @@ mod tests {
     // Adding a helper method to extract the errno within an Error::Mmap(e), or return a
     // distinctive value when the error is represented by another variant.
     impl Error {
+        /// Returns the errno of an `Error::Mmap`, or `i32::MIN` for other variants.
         pub fn raw_os_error(&self) -> i32 {
             match self {
                 Error::Mmap(e) => e.raw_os_error().unwrap(),
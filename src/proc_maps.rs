@@ -0,0 +1,314 @@
+//! Discovery of the memory mappings of another process.
+//!
+//! Parses `/proc/<pid>/maps` and `/proc/<pid>/smaps` to find the mappings of a hypervisor which
+//! may back guest RAM, so that their host virtual addresses don't have to be known upfront.
+
+use std::error;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::result;
+
+use nix::unistd::Pid;
+
+/// Errors that can occur when reading the mappings of a process.
+#[derive(Debug)]
+pub enum Error {
+    /// Reading the maps file failed.
+    Io(io::Error),
+    /// A line of the maps file couldn't be parsed.
+    Parse(String),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Io(e) => write!(f, "cannot read process mappings: {}", e),
+            Error::Parse(line) => write!(f, "cannot parse process mapping: {:?}", line),
+        }
+    }
+}
+
+impl error::Error for Error {}
+
+/// A specialized `Result` type for mapping discovery.
+pub type Result<T> = result::Result<T, Error>;
+
+/// A mapping of a process, as listed in `/proc/<pid>/maps`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Mapping {
+    /// First address of the mapping.
+    pub start: usize,
+    /// First address after the mapping.
+    pub end: usize,
+    /// Memory protection of the mapping, as `PROT_*` flags.
+    pub prot: i32,
+    /// Whether the mapping is shared instead of private.
+    pub shared: bool,
+    /// Offset of the mapping in the backing file.
+    pub offset: u64,
+    /// Inode of the backing file, 0 for anonymous mappings.
+    pub inode: u64,
+    /// Path of the backing file, a pseudo path like `[heap]` or empty for anonymous mappings.
+    pub pathname: String,
+    /// Whether the mapping is backed by hugetlbfs. Only known when read from `smaps`.
+    pub hugetlbfs: Option<bool>,
+}
+
+impl Mapping {
+    /// Returns the size of the mapping in bytes.
+    pub fn len(&self) -> usize {
+        self.end - self.start
+    }
+
+    /// Returns `true` if the mapping is empty.
+    pub fn is_empty(&self) -> bool {
+        self.start == self.end
+    }
+
+    /// Returns the `MAP_*` flags the mapping was most likely created with.
+    pub fn flags(&self) -> i32 {
+        let mut flags = if self.shared {
+            libc::MAP_SHARED
+        } else {
+            libc::MAP_PRIVATE
+        };
+        if self.inode == 0 {
+            flags |= libc::MAP_ANONYMOUS;
+        }
+        if self.hugetlbfs == Some(true) {
+            flags |= libc::MAP_HUGETLB;
+        }
+        flags
+    }
+
+    /// Returns `true` if `size` bytes at `addr` are within the mapping.
+    pub fn contains(&self, addr: usize, size: usize) -> bool {
+        addr >= self.start && matches!(addr.checked_add(size), Some(end) if end <= self.end)
+    }
+
+    fn parse(line: &str) -> Option<Mapping> {
+        let mut fields = line.splitn(6, char::is_whitespace);
+        let (start, end) = split_pair(fields.next()?, '-')?;
+        let perms = fields.next()?.as_bytes();
+        let offset = u64::from_str_radix(fields.next()?, 16).ok()?;
+        split_pair(fields.next()?, ':')?;
+        let inode = fields.next()?.parse().ok()?;
+        let pathname = fields.next().unwrap_or("").trim_start().to_string();
+
+        if perms.len() != 4 {
+            return None;
+        }
+        let mut prot = libc::PROT_NONE;
+        for (&c, (flag, bit)) in perms.iter().zip(&[
+            (b'r', libc::PROT_READ),
+            (b'w', libc::PROT_WRITE),
+            (b'x', libc::PROT_EXEC),
+        ]) {
+            if c == *flag {
+                prot |= bit;
+            }
+        }
+
+        Some(Mapping {
+            start,
+            end,
+            prot,
+            shared: perms[3] == b's',
+            offset,
+            inode,
+            pathname,
+            hugetlbfs: None,
+        })
+    }
+}
+
+/// Parses two hexadecimal numbers separated by `sep`.
+fn split_pair(s: &str, sep: char) -> Option<(usize, usize)> {
+    let mut parts = s.splitn(2, sep);
+    let a = usize::from_str_radix(parts.next()?, 16).ok()?;
+    let b = usize::from_str_radix(parts.next()?, 16).ok()?;
+    Some((a, b))
+}
+
+/// Parses the contents of a `/proc/<pid>/maps` file.
+pub fn parse_maps(content: &str) -> Result<Vec<Mapping>> {
+    content
+        .lines()
+        .filter(|line| !line.is_empty())
+        .map(|line| Mapping::parse(line).ok_or_else(|| Error::Parse(line.to_string())))
+        .collect()
+}
+
+/// Parses the contents of a `/proc/<pid>/smaps` file.
+///
+/// Like `parse_maps`, but also fills in `Mapping::hugetlbfs` from the `VmFlags` of each mapping.
+pub fn parse_smaps(content: &str) -> Result<Vec<Mapping>> {
+    let mut maps: Vec<Mapping> = Vec::new();
+    for line in content.lines().filter(|line| !line.is_empty()) {
+        let key = line.split_whitespace().next().unwrap_or("");
+        if !key.ends_with(':') {
+            maps.push(Mapping::parse(line).ok_or_else(|| Error::Parse(line.to_string()))?);
+            continue;
+        }
+        let mapping = maps
+            .last_mut()
+            .ok_or_else(|| Error::Parse(line.to_string()))?;
+        if key == "VmFlags:" {
+            mapping.hugetlbfs = Some(line.split_whitespace().skip(1).any(|flag| flag == "ht"));
+        }
+    }
+    Ok(maps)
+}
+
+/// Reads the mappings of process `pid` from `/proc/<pid>/maps`.
+pub fn read_maps(pid: Pid) -> Result<Vec<Mapping>> {
+    let content = fs::read_to_string(format!("/proc/{}/maps", pid)).map_err(Error::Io)?;
+    parse_maps(&content)
+}
+
+/// Reads the mappings of process `pid` from `/proc/<pid>/smaps`.
+pub fn read_smaps(pid: Pid) -> Result<Vec<Mapping>> {
+    let content = fs::read_to_string(format!("/proc/{}/smaps", pid)).map_err(Error::Io)?;
+    parse_smaps(&content)
+}
+
+/// Opens the file backing `mapping` of process `pid` through `/proc/<pid>/map_files`.
+///
+/// This also works for files which are not reachable by path, like a memfd or a deleted
+/// hugetlbfs file, but requires `CAP_SYS_ADMIN` (or `CAP_CHECKPOINT_RESTORE`).
+pub fn open_map_file(pid: Pid, mapping: &Mapping) -> Result<fs::File> {
+    fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(format!(
+            "/proc/{}/map_files/{:x}-{:x}",
+            pid, mapping.start, mapping.end
+        ))
+        .map_err(Error::Io)
+}
+
+/// Returns the mappings which may back guest RAM.
+///
+/// These are readable, writable and not executable mappings of at least `min_size` bytes, which
+/// are anonymous or backed by a file (like a memfd or a hugetlbfs file), but not one of the
+/// kernel's pseudo mappings like `[heap]` or `[stack]`.
+pub fn guest_ram_candidates(maps: &[Mapping], min_size: usize) -> Vec<&Mapping> {
+    let rw = libc::PROT_READ | libc::PROT_WRITE;
+    maps.iter()
+        .filter(|m| m.prot & (rw | libc::PROT_EXEC) == rw)
+        .filter(|m| m.len() >= min_size)
+        .filter(|m| !m.pathname.starts_with('['))
+        .collect()
+}
+
+/// Finds the mappings covering `size` bytes at `addr` without gaps.
+///
+/// Returns `None` if part of the range isn't mapped.
+pub fn find_range(maps: &[Mapping], addr: usize, size: usize) -> Option<Vec<&Mapping>> {
+    let end = addr.checked_add(size)?;
+    let mut cur = addr;
+    let mut found = Vec::new();
+    for m in maps.iter().filter(|m| m.end > addr && m.start < end) {
+        if m.start > cur {
+            return None;
+        }
+        found.push(m);
+        cur = m.end;
+    }
+    if cur >= end {
+        Some(found)
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MAPS: &str = "\
+55846a74e000-55846a750000 r--p 00000000 fe:00 317783                     /usr/bin/head
+7f0000000000-7f0040000000 rw-s 00000000 00:01 1025                       /memfd:ram (deleted)
+7f0040000000-7f0080000000 rw-p 00000000 00:00 0
+7f0080000000-7f0080001000 rw-p 00000000 00:00 0                          [stack]
+7ffd1e5fe000-7ffd1e600000 r-xp 00000000 00:00 0                          [vdso]
+";
+
+    #[test]
+    fn test_parse_maps() {
+        let maps = parse_maps(MAPS).unwrap();
+        assert_eq!(maps.len(), 5);
+        assert_eq!(
+            maps[1],
+            Mapping {
+                start: 0x7f00_0000_0000,
+                end: 0x7f00_4000_0000,
+                prot: libc::PROT_READ | libc::PROT_WRITE,
+                shared: true,
+                offset: 0,
+                inode: 1025,
+                pathname: "/memfd:ram (deleted)".to_string(),
+                hugetlbfs: None,
+            }
+        );
+        assert_eq!(maps[0].prot, libc::PROT_READ);
+        assert_eq!(maps[2].pathname, "");
+        assert_eq!(maps[2].flags(), libc::MAP_PRIVATE | libc::MAP_ANONYMOUS);
+        assert_eq!(maps[1].flags(), libc::MAP_SHARED);
+        assert_eq!(maps[4].prot, libc::PROT_READ | libc::PROT_EXEC);
+
+        assert!(parse_maps("7f00-7f01 rw-p").is_err());
+        assert!(parse_maps("zzzz-7f01 rw-p 00000000 00:00 0").is_err());
+    }
+
+    #[test]
+    fn test_parse_smaps() {
+        let smaps = "\
+7f0000000000-7f0040000000 rw-s 00000000 00:0f 1025                       /dev/hugepages/ram
+Size:            1048576 kB
+VmFlags: rd wr sh mr mw me ms de ht sd
+7f0040000000-7f0080000000 rw-p 00000000 00:00 0
+Size:            1048576 kB
+VmFlags: rd wr mr mw me ac sd
+";
+        let maps = parse_smaps(smaps).unwrap();
+        assert_eq!(maps.len(), 2);
+        assert_eq!(maps[0].hugetlbfs, Some(true));
+        assert_eq!(maps[0].flags(), libc::MAP_SHARED | libc::MAP_HUGETLB);
+        assert_eq!(maps[1].hugetlbfs, Some(false));
+
+        assert!(parse_smaps("Size: 4 kB").is_err());
+    }
+
+    #[test]
+    fn test_guest_ram_candidates() {
+        let maps = parse_maps(MAPS).unwrap();
+        let candidates = guest_ram_candidates(&maps, 0x1000);
+        assert_eq!(candidates, vec![&maps[1], &maps[2]]);
+        assert!(guest_ram_candidates(&maps, 0x1_0000_0000).is_empty());
+    }
+
+    #[test]
+    fn test_find_range() {
+        let maps = parse_maps(MAPS).unwrap();
+        let found = find_range(&maps, 0x7f00_3fff_f000, 0x2000).unwrap();
+        assert_eq!(found, vec![&maps[1], &maps[2]]);
+        assert!(maps[2].contains(0x7f00_4000_0000, 0x1000));
+        assert!(!maps[2].contains(0x7f00_7fff_f000, 0x2000));
+        assert!(find_range(&maps, 0x7f00_8000_0000, 0x2000).is_none());
+        assert!(find_range(&maps, 0x1000, 0x1000).is_none());
+    }
+
+    #[test]
+    fn test_read_maps() {
+        let buf = vec![0u8; 0x10_0000];
+        let addr = buf.as_ptr() as usize;
+        for maps in [read_maps(Pid::this()), read_smaps(Pid::this())].iter() {
+            let maps = maps.as_ref().unwrap();
+            let found = find_range(maps, addr, buf.len()).unwrap();
+            assert!(found.iter().all(|m| m.prot & libc::PROT_WRITE != 0));
+        }
+        assert!(read_maps(Pid::from_raw(-1)).is_err());
+    }
+}
@@ -6,11 +6,25 @@
 //! Note: From the `process_vm_readv` man page: Permission  to  read  from  or  write to another
 //! process is governed by a ptrace access mode PTRACE_MODE_AT‐TACH_REALCREDS check; see
 //! `ptrace(2)`.
+//!
+//! How the memory is accessed is abstracted by the [`Transport`](trait.Transport.html) trait.
 use libc::c_void;
-use nix::sys::uio::{process_vm_readv, process_vm_writev, IoVec, RemoteIoVec};
+use nix::errno::Errno;
+use nix::sys::ptrace;
+use nix::sys::uio::{pread, process_vm_readv, process_vm_writev, pwrite, IoVec, RemoteIoVec};
 use nix::unistd::Pid;
+use std::cmp::min;
+use std::error;
+use std::fmt;
+use std::fs::{File, OpenOptions};
 use std::mem::size_of;
 use std::mem::MaybeUninit;
+use std::os::unix::io::AsRawFd;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use crate::liveness::{Verified, Watched};
+use crate::stats::Stats;
 
 /// This is relevant for process_load/store. We assume the platforms memcopy (used in
 /// process_vm_read/write) copies chunks of data aligned by a fixed n<=ALG atomically. 
@@ -21,6 +35,9 @@ use std::mem::MaybeUninit;
 /// The process_vm_read linux impls i checked (arm64+x64) do use atomic chunks of 8B or 4B and thus
 /// adhere to this rationale.
 /// 
+/// `load` reads whole aligned windows. `store` writes only the bytes of the value, but
+/// `store_window` writes back the whole window it read, which is what the following is about.
+///
 /// # Safety
 ///
 /// In theory we often access bytes before and after the memory we own. This is in practice not an
@@ -35,13 +52,66 @@ use std::mem::MaybeUninit;
 /// Memory after: As virtq_used is big (>=NR_QUEUES*8+4(+2)) and begins aligned, it is reasonable
 /// to assume that there has been added enough spacing to accomondate the 2 bytes out-of-bound
 /// access caused by .avail_event accesses.
+///
+/// Real atomics require the memory to be mapped into the current process, see
+/// `GuestRegionMmap::map_shared`.
+///
+/// This is the default of `Transport::alignment`, [`Aligned`](struct.Aligned.html) configures
+/// other granularities.
 const ALG: usize = 8;
 
+/// Size of the largest values `load` and `store` support, and the largest alignment granularity.
+pub const MAX_ATOMIC: usize = 16;
+
+/// Maximum number of iovecs `process_vm_readv`/`process_vm_writev` accept in one call, for the
+/// local and the remote side each.
+pub const IOV_MAX: usize = libc::UIO_MAXIOV as usize;
+
 /// An Error Type.
+///
+/// Failed system calls are classified by their errno, see `Error::from_nix`. All of them carry
+/// the remote process and the remote range of the failed access.
 #[derive(Debug)]
 pub enum Error {
-    /// process_vm_readv failed
-    Rw(nix::Error),
+    /// The remote process doesn't exist anymore (`ESRCH`). With `Ptrace` this also means that
+    /// the process isn't stopped under our trace.
+    ProcessGone {
+        /// The remote process.
+        pid: Pid,
+        /// Start of the remote range.
+        addr: usize,
+        /// Length of the remote range.
+        len: usize,
+    },
+    /// The remote range isn't mapped (`EFAULT`, or `EIO` for `/proc/<pid>/mem`).
+    BadAddress {
+        /// The remote process.
+        pid: Pid,
+        /// Start of the remote range.
+        addr: usize,
+        /// Length of the remote range.
+        len: usize,
+    },
+    /// Accessing the memory of the remote process isn't permitted (`EPERM`, `EACCES`).
+    PermissionDenied {
+        /// The remote process.
+        pid: Pid,
+        /// Start of the remote range.
+        addr: usize,
+        /// Length of the remote range.
+        len: usize,
+    },
+    /// Accessing the remote memory failed otherwise.
+    Rw {
+        /// The remote process.
+        pid: Pid,
+        /// Start of the remote range.
+        addr: usize,
+        /// Length of the remote range.
+        len: usize,
+        /// The error of the system call.
+        source: nix::Error,
+    },
     /// process_vm_readv read {} bytes when {} were expected
     ByteCount {
         /// ffs
@@ -49,17 +119,129 @@ pub enum Error {
         /// nope
         should: usize,
     },
+    /// opening /proc/<pid>/mem failed
+    Open(std::io::Error),
+    /// `load` and `store` don't support values of this many bytes.
+    ValueSize(usize),
+    /// The alignment granularity isn't a power of two of at most `MAX_ATOMIC` bytes.
+    Alignment(usize),
+    /// The remote process exited or replaced its address space, see
+    /// [`Watched`](../liveness/struct.Watched.html).
+    Dead {
+        /// The remote process.
+        pid: Pid,
+    },
+    /// The process with the pid isn't the one the memory belongs to anymore, see
+    /// [`Identity`](../liveness/struct.Identity.html).
+    Identity {
+        /// The pid of the remote process.
+        pid: Pid,
+    },
 }
 
 impl std::fmt::Display for Error {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
-            Error::Rw(e) => write!(f, "cannot read/write from remote process memory: {}", e),
+            Error::ProcessGone { pid, addr, len } => write!(
+                f,
+                "cannot access 0x{:x}+0x{:x} of process {}: no such process",
+                addr, len, pid
+            ),
+            Error::BadAddress { pid, addr, len } => write!(
+                f,
+                "cannot access 0x{:x}+0x{:x} of process {}: bad address",
+                addr, len, pid
+            ),
+            Error::PermissionDenied { pid, addr, len } => write!(
+                f,
+                "cannot access 0x{:x}+0x{:x} of process {}: permission denied",
+                addr, len, pid
+            ),
+            Error::Rw {
+                pid,
+                addr,
+                len,
+                source,
+            } => write!(
+                f,
+                "cannot read/write 0x{:x}+0x{:x} of process {}: {}",
+                addr, len, pid, source
+            ),
             Error::ByteCount { is, should } => write!(
                 f,
                 "reading from remote process memory: {} bytes completed, {} bytes expected",
                 is, should
             ),
+            Error::Open(e) => write!(f, "cannot open remote process memory: {}", e),
+            Error::ValueSize(len) => write!(
+                f,
+                "cannot atomically access {} bytes, at most {} are supported",
+                len, MAX_ATOMIC
+            ),
+            Error::Alignment(alignment) => {
+                write!(f, "invalid alignment granularity: {} bytes", alignment)
+            }
+            Error::Dead { pid } => write!(f, "process {} is dead", pid),
+            Error::Identity { pid } => write!(
+                f,
+                "process {} isn't the process the memory belongs to anymore",
+                pid
+            ),
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            Error::Rw { source, .. } => Some(source),
+            Error::Open(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl Error {
+    /// Classifies the failure `source` of a system call accessing `len` bytes at `addr` of
+    /// process `pid`.
+    pub fn from_nix(source: nix::Error, pid: Pid, addr: usize, len: usize) -> Self {
+        match source {
+            nix::Error::Sys(Errno::ESRCH) => Error::ProcessGone { pid, addr, len },
+            nix::Error::Sys(Errno::EFAULT) | nix::Error::Sys(Errno::EIO) => {
+                Error::BadAddress { pid, addr, len }
+            }
+            nix::Error::Sys(Errno::EPERM) | nix::Error::Sys(Errno::EACCES) => {
+                Error::PermissionDenied { pid, addr, len }
+            }
+            source => Error::Rw {
+                pid,
+                addr,
+                len,
+                source,
+            },
+        }
+    }
+
+    /// Returns `true` if no further access to the remote process can succeed, because it's gone
+    /// or we aren't permitted to access its memory.
+    pub fn is_fatal(&self) -> bool {
+        matches!(
+            self,
+            Error::ProcessGone { .. }
+                | Error::PermissionDenied { .. }
+                | Error::Dead { .. }
+                | Error::Identity { .. }
+        )
+    }
+
+    /// Returns `true` if the failure is transient and the same access may succeed when retried.
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            Error::Rw {
+                source: nix::Error::Sys(errno),
+                ..
+            } => matches!(errno, Errno::EINTR | Errno::EAGAIN | Errno::ENOMEM),
+            _ => false,
         }
     }
 }
@@ -77,18 +259,56 @@ pub unsafe fn any_as_bytes<T: Sized>(p: &T) -> &[u8] {
 ///
 /// see `remote_mem::ALG`
 pub fn process_load<T: Sized + Copy>(pid: Pid, addr: *const c_void) -> Result<T, Error> {
-    let len = size_of::<T>();
-    assert!(len <= ALG);
+    load(&ProcessVm::new(pid), addr as usize)
+}
+
+/// Returns the start and length of the window of `transport.alignment()` aligned blocks which
+/// covers `len` bytes at `addr`.
+///
+/// Values which don't fit into one block get a window of two blocks. Only accesses to a single
+/// block are atomic, see `remote_mem::ALG`.
+fn window(transport: &dyn Transport, addr: usize, len: usize) -> Result<(usize, usize), Error> {
+    let alignment = transport.alignment();
+    if !alignment.is_power_of_two() || alignment > MAX_ATOMIC {
+        return Err(Error::Alignment(alignment));
+    }
+    if len > MAX_ATOMIC {
+        return Err(Error::ValueSize(len));
+    }
 
-    let offset = ALG - addr.align_offset(ALG); // alignment border <--offset--> addr <----> algn b.
+    let mask = alignment - 1;
+    let aligned = addr & !mask;
+    let end = addr
+        .checked_add(len + mask)
+        .map(|end| end & !mask)
+        .ok_or(Error::BadAddress {
+            pid: transport.pid(),
+            addr,
+            len,
+        })?;
+    Ok((aligned, end - aligned))
+}
+
+/// atomically read from a virtual addr of the hypervisor through `transport`
+///
+/// Values of up to `MAX_ATOMIC` bytes are supported, misaligned ones are read with a window of two
+/// alignment blocks.
+///
+/// # Safety
+///
+/// see `remote_mem::ALG`
+pub fn load<T: Sized + Copy>(transport: &dyn Transport, addr: usize) -> Result<T, Error> {
+    let len = size_of::<T>();
+    let (aligned, size) = window(transport, addr, len)?;
+    let offset = addr - aligned; // alignment border <--offset--> addr <----> algn b.
     log::trace!("load offset {}", offset);
-    let aligned = unsafe { addr.sub(offset) } as usize;
-    assert!(addr as usize + len <= aligned + ALG); // value must not extend beyond this 8b aligned space
 
     assert_eq!(size_of::<MaybeUninit::<T>>(), size_of::<T>());
     let mut t_mem = MaybeUninit::<T>::uninit();
     let t_slice = unsafe { std::slice::from_raw_parts_mut(t_mem.as_mut_ptr() as *mut u8, len) };
-    let data: [u8; ALG] = process_read(pid, aligned as *const c_void)?;
+    let mut buf = [0u8; 2 * MAX_ATOMIC];
+    let data = &mut buf[..size];
+    read_exact(transport, data, aligned)?;
     log::trace!("load::read {:?}", data);
     t_slice.copy_from_slice(&data[offset .. (offset+len)]);
     log::trace!("load = {:?}", t_slice);
@@ -123,35 +343,106 @@ pub fn process_read_bytes(pid: Pid, buf: &mut [u8], addr: *const c_void) -> Resu
     }];
 
     let f = process_vm_readv(pid, local_iovec.as_slice(), remote_iovec.as_slice())
-        .map_err(Error::Rw)?;
+        .map_err(|e| Error::from_nix(e, pid, addr as usize, len))?;
     std::sync::atomic::fence(std::sync::atomic::Ordering::SeqCst);
     Ok(f)
 }
 
-/// atomically write to a virtual addr of the hypervisor
+/// atomically write to a virtual addr of the hypervisor, see `store`
+pub fn process_store<T: Sized + Copy>(pid: Pid, addr: *mut c_void, val: &T) -> Result<(), Error> {
+    store(&ProcessVm::new(pid), addr as usize, val)
+}
+
+/// atomically write to a virtual addr of the hypervisor through `transport`
+///
+/// Only the bytes of `val` are written, with a single write, so concurrent updates of
+/// neighbouring fields are never overwritten. This is atomic if `val` is 1, 2, 4 or 8 bytes large
+/// and naturally aligned; other values of up to `MAX_ATOMIC` bytes may tear.
+pub fn store<T: Sized + Copy>(
+    transport: &dyn Transport,
+    addr: usize,
+    val: &T,
+) -> Result<(), Error> {
+    let len = size_of::<T>();
+    if len > MAX_ATOMIC {
+        return Err(Error::ValueSize(len));
+    }
+    if !(len.is_power_of_two() && len <= 8 && addr & (len - 1) == 0) {
+        log::trace!("store of {} bytes at 0x{:x} may tear", len, addr);
+    }
+    // safe, because we won't need val_b for long
+    let val_b: &[u8] = unsafe { any_as_bytes(val) };
+    write_all(transport, addr, val_b)
+}
+
+/// atomically write to a virtual addr of the hypervisor, see `store_window`
 ///
 /// # Safety
 ///
 /// see `remote_mem::ALG`
-pub fn process_store<T: Sized + Copy>(pid: Pid, addr: *mut c_void, val: &T) -> Result<(), Error> {
-    let len = size_of::<T>();
-    assert!(len <= ALG);
+pub fn process_store_window<T: Sized + Copy>(
+    pid: Pid,
+    addr: *mut c_void,
+    val: &T,
+) -> Result<(), Error> {
+    store_window(&ProcessVm::new(pid), addr as usize, val)
+}
 
-    let offset = ALG - addr.align_offset(ALG); // alignment border <--offset--> addr <----> algn b.
+/// atomically write to a virtual addr of the hypervisor through `transport` by reading the
+/// aligned window around it, patching it and writing it back
+///
+/// Unlike `store` this is atomic for misaligned values within one block, but overwrites
+/// concurrent updates to the rest of the window. Supports the same values as `load`.
+///
+/// # Safety
+///
+/// see `remote_mem::ALG`
+pub fn store_window<T: Sized + Copy>(
+    transport: &dyn Transport,
+    addr: usize,
+    val: &T,
+) -> Result<(), Error> {
+    let len = size_of::<T>();
+    let (aligned, size) = window(transport, addr, len)?;
+    let offset = addr - aligned; // alignment border <--offset--> addr <----> algn b.
     log::trace!("store offset {}", offset);
-    let aligned = unsafe { addr.sub(offset) } as usize;
-    assert!(addr as usize + len <= aligned + ALG); // value must not extend beyond this 8b aligned space
 
-    let mut data: [u8; ALG] = process_read(pid, aligned as *const c_void)?;
+    let mut buf = [0u8; 2 * MAX_ATOMIC];
+    let data = &mut buf[..size];
+    read_exact(transport, data, aligned)?;
     log::trace!("store::read {:?}", data); // 0
     let val_b: &[u8] = unsafe { any_as_bytes(val) };
     data[offset .. (offset+len)].copy_from_slice(val_b);
     log::trace!("store({:?}) = {:?}", val_b, data); // 0
-    process_write(pid, aligned as *mut c_void, &data)?;
+    write_all(transport, aligned, data)?;
 
     Ok(())
 }
 
+/// Reads exactly `buf.len()` bytes at `addr` through `transport`.
+fn read_exact(transport: &dyn Transport, buf: &mut [u8], addr: usize) -> Result<(), Error> {
+    let read = transport.read_bytes(buf, addr)?;
+    if read != buf.len() {
+        return Err(Error::ByteCount {
+            is: read,
+            should: buf.len(),
+        });
+    }
+    Ok(())
+}
+
+/// Writes all of `buf` to `addr` through `transport`.
+fn write_all(transport: &dyn Transport, addr: usize, buf: &[u8]) -> Result<(), Error> {
+    let written = transport.write_bytes(addr, buf)?;
+    if written != buf.len() {
+        return Err(Error::ByteCount {
+            is: written,
+            should: buf.len(),
+        });
+    }
+    Ok(())
+}
+
 /// write to a virtual addr of the hypervisor
 pub fn process_write<T: Sized + Copy>(pid: Pid, addr: *mut c_void, val: &T) -> Result<(), Error> {
     let len = size_of::<T>();
@@ -178,7 +469,978 @@ pub fn process_write_bytes(pid: Pid, addr: *mut c_void, val: &[u8]) -> Result<us
     }];
 
     let f = process_vm_writev(pid, local_iovec.as_slice(), remote_iovec.as_slice())
-        .map_err(Error::Rw)?;
+        .map_err(|e| Error::from_nix(e, pid, addr as usize, len))?;
     std::sync::atomic::fence(std::sync::atomic::Ordering::SeqCst);
     Ok(f)
 }
+
+/// Pairs up local buffers of `local_lens` bytes with the `remote` ranges. Returns a
+/// `(buffer index, buffer offset, remote address, length)` tuple for every piece that is
+/// contiguous on both sides.
+fn pieces(local_lens: &[usize], remote: &[RemoteIoVec]) -> Vec<(usize, usize, usize, usize)> {
+    let mut pieces = Vec::new();
+    let (mut li, mut loff) = (0, 0);
+    let (mut ri, mut roff) = (0, 0);
+
+    while li < local_lens.len() && ri < remote.len() {
+        if loff == local_lens[li] {
+            li += 1;
+            loff = 0;
+            continue;
+        }
+        if roff == remote[ri].len {
+            ri += 1;
+            roff = 0;
+            continue;
+        }
+        let n = min(local_lens[li] - loff, remote[ri].len - roff);
+        pieces.push((li, loff, remote[ri].base + roff, n));
+        loff += n;
+        roff += n;
+    }
+    pieces
+}
+
+/// The iovecs of a single `process_vm_readv`/`process_vm_writev` call. Local entries are
+/// `(buffer index, offset, length)` triples; both sides cover `len` bytes.
+#[derive(Default)]
+struct Batch {
+    local: Vec<(usize, usize, usize)>,
+    remote: Vec<RemoteIoVec>,
+    len: usize,
+}
+
+/// Splits a scatter-gather transfer between local buffers of `local_lens` bytes and the `remote`
+/// ranges into batches of at most `IOV_MAX` iovecs per side. Buffers and ranges crossing a batch
+/// boundary are split, so the transfer continues exactly where the previous batch stopped.
+fn batches(local_lens: &[usize], remote: &[RemoteIoVec]) -> Vec<Batch> {
+    let mut batches = Vec::new();
+    let mut cur = Batch::default();
+
+    for (i, off, addr, n) in pieces(local_lens, remote) {
+        let mut new_local = !matches!(cur.local.last(), Some(&(j, _, _)) if j == i);
+        let mut new_remote = !matches!(cur.remote.last(), Some(r) if r.base + r.len == addr);
+        if (new_local && cur.local.len() == IOV_MAX) || (new_remote && cur.remote.len() == IOV_MAX)
+        {
+            batches.push(std::mem::take(&mut cur));
+            new_local = true;
+            new_remote = true;
+        }
+
+        match cur.local.last_mut() {
+            Some(last) if !new_local => last.2 += n,
+            _ => cur.local.push((i, off, n)),
+        }
+        match cur.remote.last_mut() {
+            Some(last) if !new_remote => last.len += n,
+            _ => cur.remote.push(RemoteIoVec { base: addr, len: n }),
+        }
+        cur.len += n;
+    }
+    if cur.len > 0 {
+        batches.push(cur);
+    }
+    batches
+}
+
+/// Runs `f` for every batch and sums up the transferred bytes. Stops at the first partial
+/// transfer; an error is only reported if nothing was transferred before it.
+fn transfer_vectored<F>(
+    pid: Pid,
+    local_lens: &[usize],
+    remote: &[RemoteIoVec],
+    mut f: F,
+) -> Result<usize, Error>
+where
+    F: FnMut(&Batch) -> nix::Result<usize>,
+{
+    let mut total = 0;
+    for batch in batches(local_lens, remote) {
+        match f(&batch) {
+            Ok(n) => {
+                total += n;
+                if n < batch.len {
+                    break;
+                }
+            }
+            Err(e) if total == 0 => {
+                return Err(Error::from_nix(e, pid, batch.remote[0].base, batch.len))
+            }
+            Err(_) => break,
+        }
+    }
+    std::sync::atomic::fence(std::sync::atomic::Ordering::SeqCst);
+    Ok(total)
+}
+
+/// Scatter-gather read from the hypervisor: fills `bufs` in order with the contents of the
+/// `remote` ranges, using as few `process_vm_readv` calls as `IOV_MAX` permits.
+///
+/// Local buffers and remote ranges don't need to line up; at most
+/// `min(sum of buffer lengths, sum of range lengths)` bytes are read. Returns the number of bytes
+/// read, which is short if a remote range is not accessible.
+pub fn process_readv(
+    pid: Pid,
+    bufs: &mut [&mut [u8]],
+    remote: &[RemoteIoVec],
+) -> Result<usize, Error> {
+    let lens: Vec<usize> = bufs.iter().map(|b| b.len()).collect();
+    let ptrs: Vec<*mut u8> = bufs.iter_mut().map(|b| b.as_mut_ptr()).collect();
+    transfer_vectored(pid, &lens, remote, |batch| {
+        let local: Vec<IoVec<&mut [u8]>> = batch
+            .local
+            .iter()
+            .map(|&(i, off, len)| {
+                // Safe because a batch references each buffer at most once and stays within its
+                // bounds, so the slices don't alias.
+                IoVec::from_mut_slice(unsafe {
+                    std::slice::from_raw_parts_mut(ptrs[i].add(off), len)
+                })
+            })
+            .collect();
+        process_vm_readv(pid, local.as_slice(), batch.remote.as_slice())
+    })
+}
+
+/// Scatter-gather write to the hypervisor: writes the contents of `bufs` in order to the `remote`
+/// ranges, using as few `process_vm_writev` calls as `IOV_MAX` permits.
+///
+/// Returns the number of bytes written, see `process_readv`.
+pub fn process_writev(pid: Pid, bufs: &[&[u8]], remote: &[RemoteIoVec]) -> Result<usize, Error> {
+    let lens: Vec<usize> = bufs.iter().map(|b| b.len()).collect();
+    transfer_vectored(pid, &lens, remote, |batch| {
+        let local: Vec<IoVec<&[u8]>> = batch
+            .local
+            .iter()
+            .map(|&(i, off, len)| IoVec::from_slice(&bufs[i][off..off + len]))
+            .collect();
+        process_vm_writev(pid, local.as_slice(), batch.remote.as_slice())
+    })
+}
+
+/// Calls `f` for every piece of a scatter-gather transfer (see `pieces`) and sums up the
+/// transferred bytes. Stops at the first partial transfer; an error is only reported if nothing
+/// was transferred before it.
+fn transfer_pieces<F>(
+    local_lens: &[usize],
+    remote: &[RemoteIoVec],
+    mut f: F,
+) -> Result<usize, Error>
+where
+    F: FnMut(usize, usize, usize, usize) -> Result<usize, Error>,
+{
+    let mut total = 0;
+    for (i, off, addr, len) in pieces(local_lens, remote) {
+        match f(i, off, addr, len) {
+            Ok(n) => {
+                total += n;
+                if n < len {
+                    break;
+                }
+            }
+            Err(e) if total == 0 => return Err(e),
+            Err(_) => break,
+        }
+    }
+    Ok(total)
+}
+
+/// A way to access the memory of the hypervisor process.
+///
+/// Addresses are virtual addresses of the hypervisor. [`ProcessVm`](struct.ProcessVm.html) is
+/// the default and uses `process_vm_readv`/`process_vm_writev`; the other implementations cover
+/// setups where those are unavailable.
+pub trait Transport: fmt::Debug + Send + Sync {
+    /// Returns the process whose memory is accessed.
+    fn pid(&self) -> Pid;
+
+    /// Reads up to `buf.len()` bytes at `addr`. Returns the number of bytes read.
+    fn read_bytes(&self, buf: &mut [u8], addr: usize) -> Result<usize, Error>;
+
+    /// Writes up to `buf.len()` bytes to `addr`. Returns the number of bytes written.
+    fn write_bytes(&self, addr: usize, buf: &[u8]) -> Result<usize, Error>;
+
+    /// Scatter-gather version of `read_bytes` with the semantics of `process_readv`.
+    ///
+    /// The default implementation calls `read_bytes` for every contiguous piece.
+    fn readv(&self, bufs: &mut [&mut [u8]], remote: &[RemoteIoVec]) -> Result<usize, Error> {
+        let lens: Vec<usize> = bufs.iter().map(|b| b.len()).collect();
+        transfer_pieces(&lens, remote, |i, off, addr, len| {
+            self.read_bytes(&mut bufs[i][off..off + len], addr)
+        })
+    }
+
+    /// Scatter-gather version of `write_bytes` with the semantics of `process_writev`.
+    ///
+    /// The default implementation calls `write_bytes` for every contiguous piece.
+    fn writev(&self, bufs: &[&[u8]], remote: &[RemoteIoVec]) -> Result<usize, Error> {
+        let lens: Vec<usize> = bufs.iter().map(|b| b.len()).collect();
+        transfer_pieces(&lens, remote, |i, off, addr, len| {
+            self.write_bytes(addr, &bufs[i][off..off + len])
+        })
+    }
+
+    /// Returns the granularity in bytes of the aligned blocks `load` and `store` access, see
+    /// `remote_mem::ALG`.
+    fn alignment(&self) -> usize {
+        ALG
+    }
+
+    /// Returns the statistics of the accesses through the transport, if they are counted, see
+    /// [`Instrumented`](../stats/struct.Instrumented.html).
+    fn stats(&self) -> Option<&Arc<Stats>> {
+        None
+    }
+
+    /// Returns the liveness of the remote process, if it's watched, see
+    /// [`Watched`](../liveness/struct.Watched.html).
+    fn watched(&self) -> Option<&Watched> {
+        None
+    }
+}
+
+/// The default `Transport`, using `process_vm_readv`/`process_vm_writev`.
+#[derive(Clone, Copy, Debug)]
+pub struct ProcessVm {
+    pid: Pid,
+}
+
+impl ProcessVm {
+    /// Creates a transport for the memory of process `pid`.
+    pub fn new(pid: Pid) -> Self {
+        ProcessVm { pid }
+    }
+}
+
+impl Transport for ProcessVm {
+    fn pid(&self) -> Pid {
+        self.pid
+    }
+
+    fn read_bytes(&self, buf: &mut [u8], addr: usize) -> Result<usize, Error> {
+        process_read_bytes(self.pid, buf, addr as *const c_void)
+    }
+
+    fn write_bytes(&self, addr: usize, buf: &[u8]) -> Result<usize, Error> {
+        process_write_bytes(self.pid, addr as *mut c_void, buf)
+    }
+
+    fn readv(&self, bufs: &mut [&mut [u8]], remote: &[RemoteIoVec]) -> Result<usize, Error> {
+        process_readv(self.pid, bufs, remote)
+    }
+
+    fn writev(&self, bufs: &[&[u8]], remote: &[RemoteIoVec]) -> Result<usize, Error> {
+        process_writev(self.pid, bufs, remote)
+    }
+}
+
+/// A `Transport` using `PTRACE_PEEKDATA`/`PTRACE_POKEDATA`, for hypervisors whose seccomp filter
+/// blocks `process_vm_readv`/`process_vm_writev`.
+///
+/// The calling thread must be attached to `pid` as its tracer and `pid` must be stopped. Every
+/// machine word costs a syscall, and partial words are written with a read-modify-write.
+#[derive(Clone, Copy, Debug)]
+pub struct Ptrace {
+    pid: Pid,
+}
+
+impl Ptrace {
+    const WORD: usize = size_of::<libc::c_long>();
+
+    /// Creates a transport for the memory of the tracee `pid`.
+    pub fn new(pid: Pid) -> Self {
+        Ptrace { pid }
+    }
+
+    fn peek(&self, addr: usize) -> Result<[u8; Self::WORD], Error> {
+        let word = ptrace::read(self.pid, addr as ptrace::AddressType)
+            .map_err(|e| Error::from_nix(e, self.pid, addr, Self::WORD))?;
+        Ok(word.to_ne_bytes())
+    }
+
+    fn poke(&self, addr: usize, word: [u8; Self::WORD]) -> Result<(), Error> {
+        let word = libc::c_long::from_ne_bytes(word);
+        // Safe because the data argument of PTRACE_POKEDATA is the value to store, not a
+        // pointer.
+        unsafe { ptrace::write(self.pid, addr as ptrace::AddressType, word as *mut c_void) }
+            .map_err(|e| Error::from_nix(e, self.pid, addr, Self::WORD))
+    }
+}
+
+impl Transport for Ptrace {
+    fn pid(&self) -> Pid {
+        self.pid
+    }
+
+    fn read_bytes(&self, buf: &mut [u8], addr: usize) -> Result<usize, Error> {
+        let mut done = 0;
+        while done < buf.len() {
+            let cur = addr + done;
+            let offset = cur % Self::WORD;
+            let len = min(Self::WORD - offset, buf.len() - done);
+            match self.peek(cur - offset) {
+                Ok(word) => buf[done..done + len].copy_from_slice(&word[offset..offset + len]),
+                Err(e) if done == 0 => return Err(e),
+                Err(_) => break,
+            }
+            done += len;
+        }
+        Ok(done)
+    }
+
+    fn write_bytes(&self, addr: usize, buf: &[u8]) -> Result<usize, Error> {
+        let mut done = 0;
+        while done < buf.len() {
+            let cur = addr + done;
+            let offset = cur % Self::WORD;
+            let len = min(Self::WORD - offset, buf.len() - done);
+            let res = if len == Self::WORD {
+                let mut word = [0u8; Self::WORD];
+                word.copy_from_slice(&buf[done..done + len]);
+                self.poke(cur, word)
+            } else {
+                self.peek(cur - offset).and_then(|mut word| {
+                    word[offset..offset + len].copy_from_slice(&buf[done..done + len]);
+                    self.poke(cur - offset, word)
+                })
+            };
+            match res {
+                Ok(()) => done += len,
+                Err(e) if done == 0 => return Err(e),
+                Err(_) => break,
+            }
+        }
+        Ok(done)
+    }
+}
+
+/// A `Transport` using `pread`/`pwrite` on `/proc/<pid>/mem`.
+///
+/// This often still works when a seccomp filter or Yama's `ptrace_scope` prevents
+/// `process_vm_readv`/`process_vm_writev`. The file is opened once and kept open.
+#[derive(Debug)]
+pub struct ProcMem {
+    pid: Pid,
+    file: File,
+}
+
+impl ProcMem {
+    /// Opens `/proc/<pid>/mem` for reading and writing.
+    pub fn open(pid: Pid) -> Result<Self, Error> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(format!("/proc/{}/mem", pid))
+            .map_err(Error::Open)?;
+        Ok(ProcMem { pid, file })
+    }
+
+    /// Calls `f` with increasing offsets until `len` bytes at `addr` are transferred, `f`
+    /// transfers nothing or fails.
+    fn transfer<F>(&self, addr: usize, len: usize, mut f: F) -> Result<usize, Error>
+    where
+        F: FnMut(usize) -> nix::Result<usize>,
+    {
+        let mut done = 0;
+        while done < len {
+            match f(done) {
+                Ok(0) => break,
+                Ok(n) => done += n,
+                Err(nix::Error::Sys(Errno::EINTR)) => continue,
+                Err(e) if done == 0 => return Err(Error::from_nix(e, self.pid, addr, len)),
+                Err(_) => break,
+            }
+        }
+        std::sync::atomic::fence(std::sync::atomic::Ordering::SeqCst);
+        Ok(done)
+    }
+}
+
+impl Transport for ProcMem {
+    fn pid(&self) -> Pid {
+        self.pid
+    }
+
+    fn read_bytes(&self, buf: &mut [u8], addr: usize) -> Result<usize, Error> {
+        let fd = self.file.as_raw_fd();
+        self.transfer(addr, buf.len(), |done| {
+            pread(fd, &mut buf[done..], (addr + done) as libc::off_t)
+        })
+    }
+
+    fn write_bytes(&self, addr: usize, buf: &[u8]) -> Result<usize, Error> {
+        let fd = self.file.as_raw_fd();
+        self.transfer(addr, buf.len(), |done| {
+            pwrite(fd, &buf[done..], (addr + done) as libc::off_t)
+        })
+    }
+}
+
+/// A `Transport` which uses `primary` until it fails with `Error::PermissionDenied` or `ENOSYS`,
+/// and `fallback` from then on.
+#[derive(Debug)]
+pub struct Fallback {
+    primary: Box<dyn Transport>,
+    fallback: Box<dyn Transport>,
+    failed: AtomicBool,
+}
+
+impl Fallback {
+    /// Creates a transport trying `primary` first. Both must access the memory of the same
+    /// process.
+    pub fn new(primary: Box<dyn Transport>, fallback: Box<dyn Transport>) -> Self {
+        Fallback {
+            primary,
+            fallback,
+            failed: AtomicBool::new(false),
+        }
+    }
+
+    /// Runs `f` on the current transport, switching to the fallback if the primary is denied.
+    fn with<T, F>(&self, mut f: F) -> Result<T, Error>
+    where
+        F: FnMut(&dyn Transport) -> Result<T, Error>,
+    {
+        if !self.failed.load(Ordering::Acquire) {
+            match f(&*self.primary) {
+                Err(e @ Error::PermissionDenied { .. })
+                | Err(
+                    e @ Error::Rw {
+                        source: nix::Error::Sys(Errno::ENOSYS),
+                        ..
+                    },
+                ) => {
+                    log::warn!("{}, falling back", e);
+                    self.failed.store(true, Ordering::Release);
+                }
+                res => return res,
+            }
+        }
+        f(&*self.fallback)
+    }
+}
+
+impl Transport for Fallback {
+    fn pid(&self) -> Pid {
+        self.primary.pid()
+    }
+
+    fn read_bytes(&self, buf: &mut [u8], addr: usize) -> Result<usize, Error> {
+        self.with(|t| t.read_bytes(buf, addr))
+    }
+
+    fn write_bytes(&self, addr: usize, buf: &[u8]) -> Result<usize, Error> {
+        self.with(|t| t.write_bytes(addr, buf))
+    }
+
+    fn readv(&self, bufs: &mut [&mut [u8]], remote: &[RemoteIoVec]) -> Result<usize, Error> {
+        self.with(|t| t.readv(bufs, remote))
+    }
+
+    fn writev(&self, bufs: &[&[u8]], remote: &[RemoteIoVec]) -> Result<usize, Error> {
+        self.with(|t| t.writev(bufs, remote))
+    }
+
+    fn alignment(&self) -> usize {
+        self.primary.alignment()
+    }
+
+    fn stats(&self) -> Option<&Arc<Stats>> {
+        self.primary.stats()
+    }
+
+    fn watched(&self) -> Option<&Watched> {
+        self.primary.watched()
+    }
+}
+
+/// A `Transport` which changes the alignment granularity of `load` and `store` on `inner`.
+///
+/// Use this if the hypervisor's platform copies memory in chunks other than `remote_mem::ALG`,
+/// or to access 16 byte values atomically where that is the case.
+#[derive(Debug)]
+pub struct Aligned {
+    inner: Arc<dyn Transport>,
+    alignment: usize,
+}
+
+impl Aligned {
+    /// Wraps `inner` with an alignment granularity of `alignment` bytes, which must be a power of
+    /// two of at most `MAX_ATOMIC`.
+    pub fn new(inner: Arc<dyn Transport>, alignment: usize) -> Result<Self, Error> {
+        if !alignment.is_power_of_two() || alignment > MAX_ATOMIC {
+            return Err(Error::Alignment(alignment));
+        }
+        Ok(Aligned { inner, alignment })
+    }
+}
+
+impl Transport for Aligned {
+    fn pid(&self) -> Pid {
+        self.inner.pid()
+    }
+
+    fn read_bytes(&self, buf: &mut [u8], addr: usize) -> Result<usize, Error> {
+        self.inner.read_bytes(buf, addr)
+    }
+
+    fn write_bytes(&self, addr: usize, buf: &[u8]) -> Result<usize, Error> {
+        self.inner.write_bytes(addr, buf)
+    }
+
+    fn readv(&self, bufs: &mut [&mut [u8]], remote: &[RemoteIoVec]) -> Result<usize, Error> {
+        self.inner.readv(bufs, remote)
+    }
+
+    fn writev(&self, bufs: &[&[u8]], remote: &[RemoteIoVec]) -> Result<usize, Error> {
+        self.inner.writev(bufs, remote)
+    }
+
+    fn alignment(&self) -> usize {
+        self.alignment
+    }
+
+    fn stats(&self) -> Option<&Arc<Stats>> {
+        self.inner.stats()
+    }
+
+    fn watched(&self) -> Option<&Watched> {
+        self.inner.watched()
+    }
+}
+
+/// Returns the transport to use for the memory of `pid`: `process_vm_readv`/`process_vm_writev`,
+/// falling back to `/proc/<pid>/mem` if those are denied and the file can be opened.
+///
+/// The process which currently has `pid` is identified and the transport fails with
+/// `Error::Identity` once the pid belongs to another one, see
+/// [`Verified`](../liveness/struct.Verified.html).
+pub fn default_transport(pid: Pid) -> Arc<dyn Transport> {
+    let transport: Arc<dyn Transport> = match ProcMem::open(pid) {
+        Ok(mem) => Arc::new(Fallback::new(Box::new(ProcessVm::new(pid)), Box::new(mem))),
+        Err(e) => {
+            log::debug!("no /proc/{}/mem fallback: {}", pid, e);
+            Arc::new(ProcessVm::new(pid))
+        }
+    };
+    Arc::new(Verified::new(transport))
+}
+
+/// A `Transport` for memory of the current process, accessed with plain memory copies.
+///
+/// Meant for tests, which can then use local buffers as "remote" memory.
+#[derive(Clone, Copy, Debug)]
+pub struct Local {
+    _private: (),
+}
+
+impl Local {
+    /// Creates a transport for the memory of the current process.
+    ///
+    /// # Safety
+    ///
+    /// The caller must guarantee that all addresses accessed through the transport are valid
+    /// for reads and writes, and that nothing else accesses that memory concurrently.
+    pub unsafe fn new() -> Self {
+        Local { _private: () }
+    }
+}
+
+impl Transport for Local {
+    fn pid(&self) -> Pid {
+        Pid::this()
+    }
+
+    fn read_bytes(&self, buf: &mut [u8], addr: usize) -> Result<usize, Error> {
+        // Safe because the creator of the transport guaranteed that `addr` is valid.
+        unsafe { std::ptr::copy(addr as *const u8, buf.as_mut_ptr(), buf.len()) };
+        Ok(buf.len())
+    }
+
+    fn write_bytes(&self, addr: usize, buf: &[u8]) -> Result<usize, Error> {
+        // Safe because the creator of the transport guaranteed that `addr` is valid.
+        unsafe { std::ptr::copy(buf.as_ptr(), addr as *mut u8, buf.len()) };
+        Ok(buf.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::test_utils::TestProcess;
+
+    fn iov(base: usize, len: usize) -> RemoteIoVec {
+        RemoteIoVec { base, len }
+    }
+
+    fn remote_of(base: *const u8, ranges: &[(usize, usize)]) -> Vec<RemoteIoVec> {
+        ranges
+            .iter()
+            .map(|&(off, len)| iov(base as usize + off, len))
+            .collect()
+    }
+
+    #[test]
+    fn test_process_load_store() {
+        let pid = Pid::this();
+        // Aligned to `ALG`, so that the values below are at the start and in the middle of a word.
+        let mut buf = [0u64; 2];
+        let base = buf.as_mut_ptr() as usize;
+
+        process_store(pid, base as *mut c_void, &0x0403_0201u32).unwrap();
+        process_store(pid, (base + 4) as *mut c_void, &0x0807_0605u32).unwrap();
+        process_store(pid, (base + 9) as *mut c_void, &0xffu8).unwrap();
+        assert_eq!(
+            process_load::<u32>(pid, base as *const c_void).unwrap(),
+            0x0403_0201
+        );
+        assert_eq!(
+            process_load::<u32>(pid, (base + 4) as *const c_void).unwrap(),
+            0x0807_0605
+        );
+        assert_eq!(
+            process_load::<u8>(pid, (base + 9) as *const c_void).unwrap(),
+            0xff
+        );
+        assert_eq!(buf[1].to_ne_bytes(), [0, 0xff, 0, 0, 0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn test_batches() {
+        let remote = vec![iov(0x1000, 3); IOV_MAX + 1];
+        let b = batches(&[3 * (IOV_MAX + 1)], &remote);
+        assert_eq!(b.len(), 2);
+        assert_eq!(b[0].local, vec![(0, 0, 3 * IOV_MAX)]);
+        assert_eq!(b[0].remote.len(), IOV_MAX);
+        assert_eq!(b[0].len, 3 * IOV_MAX);
+        assert_eq!(b[1].local, vec![(0, 3 * IOV_MAX, 3)]);
+        assert_eq!(b[1].remote, vec![iov(0x1000, 3)]);
+
+        // Buffers and ranges split at different offsets; empty entries are skipped.
+        let remote = [iov(0x1000, 5), iov(0x2000, 0), iov(0x3000, 5)];
+        let b = batches(&[2, 0, 6, 4], &remote);
+        assert_eq!(b.len(), 1);
+        assert_eq!(b[0].local, vec![(0, 0, 2), (2, 0, 6), (3, 0, 2)]);
+        assert_eq!(b[0].remote, vec![iov(0x1000, 5), iov(0x3000, 5),]);
+        assert_eq!(b[0].len, 10);
+    }
+
+    #[test]
+    fn test_process_readv_writev() {
+        let pid = Pid::this();
+        let src: Vec<u8> = (0..=255).collect();
+        let ranges: Vec<(usize, usize)> = (0..IOV_MAX + 10).map(|i| (i % 200, 3)).collect();
+        let remote = remote_of(src.as_ptr(), &ranges);
+
+        let mut a = vec![0u8; 7];
+        let mut b = vec![0u8; 3 * (IOV_MAX + 10) - 7];
+        let read = process_readv(pid, &mut [&mut a[..], &mut b[..]], &remote).unwrap();
+        assert_eq!(read, 3 * (IOV_MAX + 10));
+        let got: Vec<u8> = a.iter().chain(b.iter()).cloned().collect();
+        let expected: Vec<u8> = ranges
+            .iter()
+            .flat_map(|&(off, len)| src[off..off + len].to_vec())
+            .collect();
+        assert_eq!(got, expected);
+
+        let mut dst = vec![0u8; 16];
+        let remote = remote_of(dst.as_mut_ptr(), &[(0, 4), (8, 8)]);
+        let written = process_writev(
+            pid,
+            &[&[1, 2], &[3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14]],
+            &remote,
+        )
+        .unwrap();
+        assert_eq!(written, 12);
+        assert_eq!(dst[..], [1, 2, 3, 4, 0, 0, 0, 0, 5, 6, 7, 8, 9, 10, 11, 12]);
+    }
+
+    #[test]
+    fn test_process_readv_partial() {
+        let pid = Pid::this();
+        let src = [7u8; 8];
+        let mut remote = remote_of(src.as_ptr(), &[(0, 8)]);
+        remote.push(iov(0, 8));
+        let mut buf = [0u8; 16];
+        assert_eq!(process_readv(pid, &mut [&mut buf[..]], &remote).unwrap(), 8);
+        assert_eq!(buf[..8], src);
+
+        assert!(process_readv(pid, &mut [&mut buf[..]], &remote[1..]).is_err());
+        assert_eq!(process_readv(pid, &mut [], &remote).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_transports() {
+        let src: Vec<u8> = (0..64).collect();
+        let remote = remote_of(src.as_ptr(), &[(60, 4), (0, 10)]);
+        // Safe because the transport only accesses `src` and `dst`.
+        let local = unsafe { Local::new() };
+        let transports: [&dyn Transport; 2] = [&ProcessVm::new(Pid::this()), &local];
+
+        for t in transports.iter() {
+            assert_eq!(t.pid(), Pid::this());
+            let (mut a, mut b) = ([0u8; 6], [0u8; 8]);
+            assert_eq!(t.readv(&mut [&mut a[..], &mut b[..]], &remote).unwrap(), 14);
+            assert_eq!(a, [60, 61, 62, 63, 0, 1]);
+            assert_eq!(b[..], [2, 3, 4, 5, 6, 7, 8, 9]);
+
+            let mut dst = [0u64; 2];
+            let addr = dst.as_mut_ptr() as usize;
+            store(*t, addr + 2, &0xabcdu16).unwrap();
+            store(*t, addr + 8, &u64::max_value()).unwrap();
+            assert_eq!(load::<u16>(*t, addr + 2).unwrap(), 0xabcd);
+            assert_eq!(load::<u32>(*t, addr).unwrap(), 0xabcd_0000);
+            assert_eq!(load::<u64>(*t, addr + 8).unwrap(), u64::max_value());
+        }
+    }
+
+    #[test]
+    fn test_load_store_windows() {
+        let mut dst = [0u64; 6];
+        let addr = dst.as_mut_ptr() as usize;
+        let t = ProcessVm::new(Pid::this());
+
+        // A misaligned value spans two blocks; its neighbours are left alone.
+        store(&t, addr + 6, &0x1122_3344u32).unwrap();
+        assert_eq!(load::<u32>(&t, addr + 6).unwrap(), 0x1122_3344);
+        assert_eq!(load::<u64>(&t, addr).unwrap(), 0x3344_0000_0000_0000);
+        assert_eq!(load::<u16>(&t, addr + 10).unwrap(), 0);
+
+        let v = 0x0102_0304_0506_0708_090a_0b0c_0d0e_0f10u128;
+        store(&t, addr + 16, &v).unwrap();
+        assert_eq!(load::<u128>(&t, addr + 16).unwrap(), v);
+        store(&t, addr + 33, &v).unwrap();
+        assert_eq!(load::<u128>(&t, addr + 33).unwrap(), v);
+
+        assert!(matches!(
+            load::<[u8; 17]>(&t, addr),
+            Err(Error::ValueSize(17))
+        ));
+        assert!(matches!(
+            store_window(&t, usize::max_value() - 1, &0u32),
+            Err(Error::BadAddress { .. })
+        ));
+
+        let inner: Arc<dyn Transport> = Arc::new(t);
+        let t = Aligned::new(inner.clone(), 16).unwrap();
+        assert_eq!(t.alignment(), 16);
+        store_window(&t, addr + 20, &0xaabb_ccddu32).unwrap();
+        assert_eq!(load::<u64>(&t, addr + 16).unwrap(), 0xaabb_ccdd_0d0e_0f10);
+        assert!(matches!(
+            Aligned::new(inner.clone(), 3),
+            Err(Error::Alignment(3))
+        ));
+        assert!(Aligned::new(inner, 32).is_err());
+    }
+
+    /// Records the ranges written through `Local`.
+    #[derive(Debug, Default)]
+    struct Recorder(std::sync::Mutex<Vec<(usize, usize)>>);
+
+    impl Transport for Recorder {
+        fn pid(&self) -> Pid {
+            Pid::this()
+        }
+
+        fn read_bytes(&self, buf: &mut [u8], addr: usize) -> Result<usize, Error> {
+            // Safe because the tests only access their own buffers.
+            unsafe { Local::new() }.read_bytes(buf, addr)
+        }
+
+        fn write_bytes(&self, addr: usize, buf: &[u8]) -> Result<usize, Error> {
+            self.0.lock().unwrap().push((addr, buf.len()));
+            // Safe because the tests only access their own buffers.
+            unsafe { Local::new() }.write_bytes(addr, buf)
+        }
+    }
+
+    #[test]
+    fn test_store_exact() {
+        let mut dst = [0u64; 2];
+        let addr = dst.as_mut_ptr() as usize;
+        let t = Recorder::default();
+
+        store(&t, addr + 4, &0x1122_3344u32).unwrap();
+        store(&t, addr + 6, &0x5566u16).unwrap();
+        store(&t, addr + 1, &0x77u8).unwrap();
+        store(&t, addr + 8, &u64::max_value()).unwrap();
+        assert_eq!(
+            *t.0.lock().unwrap(),
+            vec![(addr + 4, 4), (addr + 6, 2), (addr + 1, 1), (addr + 8, 8)]
+        );
+        assert_eq!(dst, [0x5566_3344_0000_7700, u64::max_value()]);
+
+        // The window is only written back on request.
+        t.0.lock().unwrap().clear();
+        store_window(&t, addr + 2, &0u16).unwrap();
+        assert_eq!(*t.0.lock().unwrap(), vec![(addr, 8)]);
+        assert!(matches!(
+            store(&t, addr, &[0u8; 17]),
+            Err(Error::ValueSize(17))
+        ));
+    }
+
+    #[test]
+    fn test_remote_process() {
+        let child = TestProcess::spawn(&[0x1000, 0x1000]);
+        let pid = Pid::from_raw(child.pid());
+        let addr = child.addr(0);
+
+        process_store(pid, (addr + 2) as *mut c_void, &0xabcdu16).unwrap();
+        assert_eq!(
+            process_load::<u32>(pid, addr as *const c_void).unwrap(),
+            0xabcd_0000
+        );
+        process_store_window(pid, addr as *mut c_void, &0x12u8).unwrap();
+        assert_eq!(
+            process_load::<u32>(pid, addr as *const c_void).unwrap(),
+            0xabcd_0012
+        );
+        process_write(pid, (addr + 0x10) as *mut c_void, &0x1122_3344u32).unwrap();
+        assert_eq!(
+            process_read::<u32>(pid, (addr + 0x10) as *const c_void).unwrap(),
+            0x1122_3344
+        );
+
+        // Both buffers at once, through every transport which works without attaching.
+        let remote = [iov(child.addr(0) + 0xffc, 4), iov(child.addr(1), 4)];
+        let transports: [Box<dyn Transport>; 2] = [
+            Box::new(ProcessVm::new(pid)),
+            Box::new(ProcMem::open(pid).unwrap()),
+        ];
+        for (i, t) in transports.iter().enumerate() {
+            let data = [i as u8 + 1; 8];
+            assert_eq!(t.writev(&[&data[..]], &remote).unwrap(), 8);
+            let mut buf = [0u8; 8];
+            assert_eq!(t.readv(&mut [&mut buf[..]], &remote).unwrap(), 8);
+            assert_eq!(buf, data);
+        }
+    }
+
+    #[test]
+    fn test_ptrace_not_attached() {
+        let buf = [0u8; 8];
+        let t = Ptrace::new(Pid::this());
+        assert!(t.read_bytes(&mut [0u8; 4], buf.as_ptr() as usize).is_err());
+        assert!(t.write_bytes(buf.as_ptr() as usize, &[1, 2]).is_err());
+    }
+
+    #[test]
+    fn test_proc_mem() {
+        let t = ProcMem::open(Pid::this()).unwrap();
+        assert_eq!(t.pid(), Pid::this());
+        let mut buf = vec![0u8; 0x3000];
+        let addr = buf.as_mut_ptr() as usize;
+        let data: Vec<u8> = (0..0x3000u32).map(|i| (i % 251) as u8).collect();
+        assert_eq!(t.write_bytes(addr, &data).unwrap(), data.len());
+        let mut read = vec![0u8; data.len()];
+        assert_eq!(t.read_bytes(&mut read, addr).unwrap(), data.len());
+        assert_eq!(read, data);
+
+        store(&t, addr + 1, &0x55aau16).unwrap();
+        assert_eq!(load::<u16>(&t, addr + 1).unwrap(), 0x55aa);
+        assert!(t.read_bytes(&mut read, 0).is_err());
+        assert!(ProcMem::open(Pid::from_raw(-1)).is_err());
+    }
+
+    #[derive(Debug)]
+    struct Denied(Errno);
+
+    impl Transport for Denied {
+        fn pid(&self) -> Pid {
+            Pid::this()
+        }
+
+        fn read_bytes(&self, buf: &mut [u8], addr: usize) -> Result<usize, Error> {
+            Err(Error::from_nix(
+                nix::Error::Sys(self.0),
+                self.pid(),
+                addr,
+                buf.len(),
+            ))
+        }
+
+        fn write_bytes(&self, addr: usize, buf: &[u8]) -> Result<usize, Error> {
+            Err(Error::from_nix(
+                nix::Error::Sys(self.0),
+                self.pid(),
+                addr,
+                buf.len(),
+            ))
+        }
+    }
+
+    #[test]
+    fn test_fallback() {
+        let src = [1u8, 2, 3, 4];
+        let addr = src.as_ptr() as usize;
+        let mut buf = [0u8; 4];
+
+        let t = Fallback::new(
+            Box::new(Denied(Errno::EPERM)),
+            Box::new(ProcessVm::new(Pid::this())),
+        );
+        assert_eq!(t.read_bytes(&mut buf, addr).unwrap(), 4);
+        assert_eq!(buf, src);
+        assert!(t.failed.load(Ordering::Acquire));
+
+        // Other errors are passed through.
+        let t = Fallback::new(
+            Box::new(Denied(Errno::EFAULT)),
+            Box::new(ProcessVm::new(Pid::this())),
+        );
+        assert!(t.read_bytes(&mut buf, addr).is_err());
+        assert!(!t.failed.load(Ordering::Acquire));
+
+        let t = default_transport(Pid::this());
+        assert_eq!(t.readv(&mut [&mut buf[..]], &[iov(addr, 4)]).unwrap(), 4);
+
+        let t = Fallback::new(
+            Box::new(Denied(Errno::ENOSYS)),
+            Box::new(ProcessVm::new(Pid::this())),
+        );
+        assert_eq!(t.read_bytes(&mut buf, addr).unwrap(), 4);
+    }
+
+    #[test]
+    fn test_errors() {
+        let child = TestProcess::spawn(&[0x1000]);
+        let pid = Pid::from_raw(child.pid());
+        let t = ProcessVm::new(pid);
+        let mut buf = [0u8; 8];
+
+        let e = t.read_bytes(&mut buf, 0x1000).unwrap_err();
+        assert!(matches!(
+            e,
+            Error::BadAddress {
+                addr: 0x1000,
+                len: 8,
+                ..
+            }
+        ));
+        assert!(!e.is_fatal() && !e.is_retryable());
+        assert_eq!(
+            format!("{}", e),
+            format!("cannot access 0x1000+0x8 of process {}: bad address", pid)
+        );
+
+        drop(child);
+        let e = t.readv(&mut [&mut buf[..]], &[iov(0x2000, 4)]).unwrap_err();
+        assert!(matches!(
+            e,
+            Error::ProcessGone {
+                addr: 0x2000,
+                len: 4,
+                ..
+            }
+        ));
+        assert!(e.is_fatal());
+
+        let e = Error::from_nix(nix::Error::Sys(Errno::EPERM), pid, 0, 1);
+        assert!(matches!(e, Error::PermissionDenied { .. }) && e.is_fatal());
+        let e = Error::from_nix(nix::Error::Sys(Errno::EINTR), pid, 0, 1);
+        assert!(e.is_retryable() && !e.is_fatal());
+        assert!(std::error::Error::source(&e).is_some());
+    }
+}
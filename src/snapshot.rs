@@ -0,0 +1,897 @@
+//! Snapshots of guest memory in a file.
+//!
+//! [`write_snapshot`](fn.write_snapshot.html) saves all regions of a `GuestMemoryMmap` to any
+//! `Write`, [`restore`](fn.restore.html) loads them into a fresh `GuestMemoryMmap` of the current
+//! process and [`restore_into`](fn.restore_into.html) writes them back into existing guest
+//! memory with the same layout, e.g. the memory of a remote hypervisor.
+//!
+//! Once the regions track dirty pages, see
+//! [`GuestMemoryMmap::with_dirty_tracking`](../mmap/struct.GuestMemoryMmap.html#method.with_dirty_tracking),
+//! [`write_diff`](fn.write_diff.html) saves only the pages written since the previous diff.
+//! [`restore_chain`](fn.restore_chain.html) restores a snapshot and the diffs taken after it, and
+//! [`apply_diff`](fn.apply_diff.html) applies a single diff to existing guest memory. The dirty
+//! pages should be cleared with `GuestMemory::take_dirty_ranges` just before the snapshot the
+//! chain starts with is taken.
+//!
+//! **Diffs only contain the pages written through this crate.** The dirty bitmaps are set by the
+//! accessors of the guest memory objects, so writes of the guest itself, or of any other code in
+//! a remote hypervisor which doesn't go through this crate, aren't seen. Diffs of a running guest
+//! are incomplete then; its written pages have to be found through the kernel's soft-dirty bits
+//! instead, which are cleared by writing 4 to `/proc/<pid>/clear_refs` and read from
+//! `/proc/<pid>/pagemap`.
+//!
+//! Each snapshot and diff has an identifier, which `write_snapshot` and `write_diff` return. A
+//! diff records the identifier of the snapshot or diff it follows, its base, so that
+//! `apply_diff` and `restore_chain` reject diffs applied on top of anything else.
+//!
+//! # Format
+//!
+//! All integers are little-endian. A snapshot starts with a header of 32 bytes:
+//!
+//! | Offset | Size | Field                                       |
+//! |--------|------|---------------------------------------------|
+//! | 0      | 8    | magic, `GMEMSNAP`                           |
+//! | 8      | 4    | version, currently 1                        |
+//! | 12     | 4    | page size, a power of two                   |
+//! | 16     | 4    | number of regions                           |
+//! | 20     | 4    | reserved, 0                                 |
+//! | 24     | 8    | identifier of the base, 0 for snapshots     |
+//!
+//! It's followed by one section per region, in the order of `GuestMemory::iter`:
+//!
+//! | Size | Field                                                      |
+//! |------|------------------------------------------------------------|
+//! | 8    | guest base address                                         |
+//! | 8    | length in bytes                                            |
+//! | 4    | `prot` of the mapping                                      |
+//! | 4    | `flags` of the mapping                                     |
+//! | ...  | records                                                    |
+//! | 8    | checksum                                                   |
+//!
+//! Each record is an offset into the region and a length of 8 bytes each, followed by that many
+//! bytes of contents. Records are sorted by offset and don't overlap, and the last record has a
+//! length of 0. Pages which are all zero aren't recorded. The checksum is the 64-bit FNV-1a hash
+//! of all bytes of the section before it. The identifier of a snapshot or diff is the FNV-1a hash
+//! of the identifier of its base followed by the checksums of its sections.
+//!
+//! A diff has the same format, except that its magic is `GMEMDIFF`, its page size is the one of
+//! the dirty bitmaps and its region sections have no `prot` and `flags`. Its records are the dirty
+//! pages, whether they are zero or not.
+//!
+//! # Examples
+//!
+//! ```
+//! # #[cfg(feature = "backend-mmap")]
+//! # {
+//! # use std::io::Cursor;
+//! # use vm_memory::{Bytes, GuestAddress, GuestMemoryMmap};
+//! # use vm_memory::snapshot;
+//! # let pid = std::process::id() as i32;
+//! let gm = GuestMemoryMmap::from_ranges(pid, &[(GuestAddress(0), 0x10000)]).unwrap();
+//! gm.write_obj(0x1234_5678u32, GuestAddress(0x2000)).unwrap();
+//!
+//! let mut file = Vec::new();
+//! snapshot::write_snapshot(&gm, 0x1000, &mut file).unwrap();
+//! // Only one page is stored.
+//! assert!(file.len() < 0x1100);
+//!
+//! let restored = snapshot::restore(Cursor::new(&file)).unwrap();
+//! assert_eq!(restored.read_obj::<u32>(GuestAddress(0x2000)).unwrap(), 0x1234_5678);
+//! # }
+//! ```
+
+use std::cmp::min;
+use std::error;
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::result;
+
+use nix::unistd::Pid;
+
+use crate::bitmap::DirtyBitmap;
+use crate::guest_memory::{
+    self, GuestAddress, GuestMemory, GuestMemoryRegion, MemoryRegionAddress,
+};
+use crate::mmap::{self, GuestMemoryMmap, GuestRegionMmap, MmapRegion};
+use crate::Bytes;
+
+/// The first bytes of a snapshot.
+pub const MAGIC: [u8; 8] = *b"GMEMSNAP";
+
+/// The first bytes of a diff.
+pub const DIFF_MAGIC: [u8; 8] = *b"GMEMDIFF";
+
+/// The version of the formats written by `write_snapshot` and `write_diff`.
+pub const VERSION: u32 = 1;
+
+/// The largest region `restore` creates, 1 TiB.
+///
+/// The length of a region is read before its checksum can be verified, so it's bounded to keep a
+/// corrupt snapshot from reserving most of the address space.
+pub const MAX_REGION_LEN: u64 = 1 << 40;
+
+/// Number of bytes read from guest memory at once.
+const BATCH: usize = 0x10_0000;
+
+/// Errors of writing or restoring a snapshot.
+#[derive(Debug)]
+pub enum Error {
+    /// Reading or writing the snapshot failed.
+    Io(io::Error),
+    /// Accessing the guest memory failed.
+    Memory(guest_memory::Error),
+    /// Creating the restored guest memory failed.
+    Mmap(mmap::Error),
+    /// The page size isn't a power of two which fits into 32 bits.
+    InvalidPageSize(usize),
+    /// The file doesn't start with `MAGIC`, or `DIFF_MAGIC` for diffs.
+    BadMagic,
+    /// The snapshot has a version which isn't supported.
+    UnsupportedVersion(u32),
+    /// The checksum of the region at this guest address doesn't match its contents.
+    Checksum(GuestAddress),
+    /// A record of the region at this guest address is out of order or out of the region.
+    InvalidRecord(GuestAddress),
+    /// The region at this guest address is empty, longer than `MAX_REGION_LEN` or extends past
+    /// the end of the address space.
+    InvalidRegion(GuestAddress),
+    /// The region at this guest address doesn't track dirty pages.
+    NoDirtyTracking(GuestAddress),
+    /// The snapshot has a different number of regions than the guest memory.
+    RegionCount {
+        /// Number of regions in the snapshot.
+        snapshot: usize,
+        /// Number of regions of the guest memory.
+        memory: usize,
+    },
+    /// The diff follows another snapshot or diff than the one it's applied to.
+    WrongBase {
+        /// Identifier of the snapshot or diff the diff is applied to.
+        expected: u64,
+        /// Identifier of the base recorded in the diff.
+        found: u64,
+    },
+    /// The guest memory has no region with the base address and length of a region of the
+    /// snapshot.
+    RegionMismatch {
+        /// Guest base address of the region in the snapshot.
+        guest_base: GuestAddress,
+        /// Length of the region in the snapshot.
+        len: u64,
+    },
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Io(e) => write!(f, "cannot access the snapshot: {}", e),
+            Error::Memory(e) => write!(f, "cannot access guest memory: {}", e),
+            Error::Mmap(e) => write!(f, "cannot create guest memory: {}", e),
+            Error::InvalidPageSize(size) => write!(f, "invalid snapshot page size {}", size),
+            Error::BadMagic => write!(f, "not a guest memory snapshot"),
+            Error::UnsupportedVersion(v) => write!(f, "unsupported snapshot version {}", v),
+            Error::Checksum(addr) => write!(
+                f,
+                "checksum mismatch in the snapshot of the region at 0x{:x}",
+                addr.0
+            ),
+            Error::InvalidRecord(addr) => write!(
+                f,
+                "invalid record in the snapshot of the region at 0x{:x}",
+                addr.0
+            ),
+            Error::InvalidRegion(addr) => write!(
+                f,
+                "invalid length of the region at 0x{:x} in the snapshot",
+                addr.0
+            ),
+            Error::NoDirtyTracking(addr) => {
+                write!(f, "the region at 0x{:x} doesn't track dirty pages", addr.0)
+            }
+            Error::RegionCount { snapshot, memory } => write!(
+                f,
+                "the snapshot has {} regions, but the guest memory {}",
+                snapshot, memory
+            ),
+            Error::WrongBase { expected, found } => write!(
+                f,
+                "the diff is based on 0x{:x} instead of 0x{:x}",
+                found, expected
+            ),
+            Error::RegionMismatch { guest_base, len } => write!(
+                f,
+                "the guest memory has no region 0x{:x}+0x{:x}",
+                guest_base.0, len
+            ),
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            Error::Io(e) => Some(e),
+            Error::Memory(e) => Some(e),
+            Error::Mmap(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Error::Io(e)
+    }
+}
+
+impl From<guest_memory::Error> for Error {
+    fn from(e: guest_memory::Error) -> Self {
+        Error::Memory(e)
+    }
+}
+
+/// A specialized `Result` type for snapshots.
+pub type Result<T> = result::Result<T, Error>;
+
+/// A reader or writer hashing all bytes passing through it with 64-bit FNV-1a.
+struct Checked<T> {
+    inner: T,
+    sum: u64,
+}
+
+impl<T> Checked<T> {
+    fn new(inner: T) -> Self {
+        Checked {
+            inner,
+            sum: 0xcbf2_9ce4_8422_2325,
+        }
+    }
+
+    fn update(&mut self, buf: &[u8]) {
+        for &b in buf {
+            self.sum = (self.sum ^ u64::from(b)).wrapping_mul(0x100_0000_01b3);
+        }
+    }
+}
+
+impl<W: Write> Write for Checked<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.update(&buf[..n]);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<R: Read> Read for Checked<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.update(&buf[..n]);
+        Ok(n)
+    }
+}
+
+fn read_u32<R: Read>(r: &mut R) -> io::Result<u32> {
+    let mut buf = [0; 4];
+    r.read_exact(&mut buf)?;
+    Ok(u32::from_le_bytes(buf))
+}
+
+fn read_u64<R: Read>(r: &mut R) -> io::Result<u64> {
+    let mut buf = [0; 8];
+    r.read_exact(&mut buf)?;
+    Ok(u64::from_le_bytes(buf))
+}
+
+/// The guest base address and length at the start of a region section.
+struct RegionLayout {
+    guest_base: GuestAddress,
+    len: u64,
+}
+
+impl RegionLayout {
+    fn of<T: GuestMemoryRegion>(region: &T) -> Self {
+        RegionLayout {
+            guest_base: region.start_addr(),
+            len: region.len(),
+        }
+    }
+
+    fn write<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        w.write_all(&self.guest_base.0.to_le_bytes())?;
+        w.write_all(&self.len.to_le_bytes())
+    }
+
+    fn read<R: Read>(r: &mut R) -> io::Result<Self> {
+        Ok(RegionLayout {
+            guest_base: GuestAddress(read_u64(r)?),
+            len: read_u64(r)?,
+        })
+    }
+
+    /// Returns the region of `mem` with the same base address and length.
+    fn find<'a, M: GuestMemory>(&self, mem: &'a M) -> Result<&'a M::R> {
+        mem.find_region(self.guest_base)
+            .filter(|region| RegionLayout::of(*region) == *self)
+            .ok_or(Error::RegionMismatch {
+                guest_base: self.guest_base,
+                len: self.len,
+            })
+    }
+}
+
+impl PartialEq for RegionLayout {
+    fn eq(&self, other: &Self) -> bool {
+        self.guest_base == other.guest_base && self.len == other.len
+    }
+}
+
+fn write_header<W: Write>(
+    w: &mut W,
+    magic: &[u8; 8],
+    page_size: usize,
+    regions: usize,
+    base: u64,
+) -> Result<()> {
+    if !page_size.is_power_of_two() || page_size > u32::MAX as usize {
+        return Err(Error::InvalidPageSize(page_size));
+    }
+    w.write_all(magic)?;
+    w.write_all(&VERSION.to_le_bytes())?;
+    w.write_all(&(page_size as u32).to_le_bytes())?;
+    w.write_all(&(regions as u32).to_le_bytes())?;
+    w.write_all(&0u32.to_le_bytes())?;
+    w.write_all(&base.to_le_bytes())?;
+    Ok(())
+}
+
+/// Reads the header of a snapshot and returns the page size, the number of regions and the
+/// identifier of the base.
+fn read_header<R: Read>(r: &mut R, expected: &[u8; 8]) -> Result<(usize, usize, u64)> {
+    let mut magic = [0; 8];
+    r.read_exact(&mut magic)?;
+    if magic != *expected {
+        return Err(Error::BadMagic);
+    }
+    let version = read_u32(r)?;
+    if version != VERSION {
+        return Err(Error::UnsupportedVersion(version));
+    }
+    let page_size = read_u32(r)? as usize;
+    let regions = read_u32(r)? as usize;
+    read_u32(r)?;
+    let base = read_u64(r)?;
+    if !page_size.is_power_of_two() {
+        return Err(Error::InvalidPageSize(page_size));
+    }
+    Ok((page_size, regions, base))
+}
+
+/// Starts the identifier of a snapshot or diff following `base`, see the
+/// [module documentation](index.html#format).
+fn file_id(base: u64) -> Checked<()> {
+    let mut id = Checked::new(());
+    id.update(&base.to_le_bytes());
+    id
+}
+
+/// Fails unless the snapshot and `mem` have the same number of regions.
+fn check_count<M: GuestMemory>(mem: &M, count: usize) -> Result<()> {
+    if count != mem.num_regions() {
+        return Err(Error::RegionCount {
+            snapshot: count,
+            memory: mem.num_regions(),
+        });
+    }
+    Ok(())
+}
+
+/// Writes a snapshot of all regions of `mem` to `w`, skipping the pages of `page_size` bytes
+/// which are all zero.
+///
+/// The guest shouldn't run while the snapshot is taken, as pages written meanwhile may or may not
+/// be saved. Returns the identifier of the snapshot, the base of the first diff.
+pub fn write_snapshot<W: Write>(mem: &GuestMemoryMmap, page_size: usize, mut w: W) -> Result<u64> {
+    write_header(&mut w, &MAGIC, page_size, mem.num_regions(), 0)?;
+    let mut id = file_id(0);
+    let mut buf = vec![0; BATCH.max(page_size)];
+    for region in mem.iter() {
+        let mut section = Checked::new(&mut w);
+        write_region(region, page_size, &mut buf, &mut section)?;
+        let sum = section.sum;
+        w.write_all(&sum.to_le_bytes())?;
+        id.update(&sum.to_le_bytes());
+    }
+    w.flush()?;
+    Ok(id.sum)
+}
+
+/// Writes the section of `region` without the checksum.
+fn write_region<W: Write>(
+    region: &GuestRegionMmap,
+    page_size: usize,
+    buf: &mut [u8],
+    w: &mut W,
+) -> Result<()> {
+    let len = region.len() as usize;
+    RegionLayout::of(region).write(w)?;
+    w.write_all(&region.prot().to_le_bytes())?;
+    w.write_all(&region.flags().to_le_bytes())?;
+
+    let mut offset = 0;
+    while offset < len {
+        let batch_len = min(buf.len(), len - offset);
+        let batch = &mut buf[..batch_len];
+        region.read_slice(batch, MemoryRegionAddress(offset as u64))?;
+        // Runs of pages which aren't all zero become one record each.
+        let mut run = None;
+        for (i, page) in batch.chunks(page_size).enumerate() {
+            let start = i * page_size;
+            match (run, page.iter().all(|&b| b == 0)) {
+                (None, false) => run = Some(start),
+                (Some(begin), true) => {
+                    write_record(w, offset + begin, &batch[begin..start])?;
+                    run = None;
+                }
+                _ => {}
+            }
+        }
+        if let Some(begin) = run {
+            write_record(w, offset + begin, &batch[begin..])?;
+        }
+        offset += batch.len();
+    }
+    write_record(w, len, &[])
+}
+
+fn write_record<W: Write>(w: &mut W, offset: usize, data: &[u8]) -> Result<()> {
+    w.write_all(&(offset as u64).to_le_bytes())?;
+    w.write_all(&(data.len() as u64).to_le_bytes())?;
+    w.write_all(data)?;
+    Ok(())
+}
+
+/// Restores a snapshot into a new `GuestMemoryMmap` of the current process.
+///
+/// The regions are anonymous private mappings, regardless of the `prot` and `flags` they had when
+/// the snapshot was taken. Regions longer than `MAX_REGION_LEN` are rejected.
+pub fn restore<R: Read>(r: R) -> Result<GuestMemoryMmap> {
+    restore_with_id(r).map(|(mem, _)| mem)
+}
+
+/// Like `restore`, also returns the identifier of the snapshot.
+fn restore_with_id<R: Read>(mut r: R) -> Result<(GuestMemoryMmap, u64)> {
+    let (_, count, _) = read_header(&mut r, &MAGIC)?;
+    let mut id = file_id(0);
+    // The count isn't verified by any checksum, so it's not used to reserve memory up front.
+    let mut regions = Vec::new();
+    for _ in 0..count {
+        let mut section = Checked::new(&mut r);
+        let layout = RegionLayout::read(&mut section)?;
+        if layout.len == 0
+            || layout.len > MAX_REGION_LEN
+            || layout.guest_base.0.checked_add(layout.len).is_none()
+        {
+            return Err(Error::InvalidRegion(layout.guest_base));
+        }
+        // The protection and flags.
+        read_u64(&mut section)?;
+        let mapping = MmapRegion::new(layout.len as usize)
+            .map_err(|e| Error::Mmap(mmap::Error::MmapRegion(e)))?;
+        let region = GuestRegionMmap::new_local(mapping, layout.guest_base).map_err(Error::Mmap)?;
+        // The new mapping is zeroed already.
+        restore_region(&region, &layout, false, &mut section)?;
+        let sum = section.sum;
+        check_sum(sum, &layout, &mut r)?;
+        id.update(&sum.to_le_bytes());
+        regions.push(region);
+    }
+    let mem = GuestMemoryMmap::from_regions(Pid::this().as_raw(), regions).map_err(Error::Mmap)?;
+    Ok((mem, id.sum))
+}
+
+/// Restores a snapshot into `mem` through `Bytes`, which must have regions with the same base
+/// addresses and lengths as the snapshot.
+///
+/// Pages which are all zero in the snapshot are zeroed in `mem`. A region is written before its
+/// checksum is verified, so `mem` is partially overwritten if the snapshot turns out to be
+/// corrupt.
+pub fn restore_into<M: GuestMemory, R: Read>(mem: &M, mut r: R) -> Result<()> {
+    let (_, count, _) = read_header(&mut r, &MAGIC)?;
+    check_count(mem, count)?;
+    for _ in 0..count {
+        let mut section = Checked::new(&mut r);
+        let layout = RegionLayout::read(&mut section)?;
+        read_u64(&mut section)?;
+        let region = layout.find(mem)?;
+        restore_region(region, &layout, true, &mut section)?;
+        check_sum(section.sum, &layout, &mut r)?;
+    }
+    Ok(())
+}
+
+/// Writes a diff of the pages of `mem` written since the previous diff or since dirty tracking
+/// started to `w`, and marks them clean.
+///
+/// Only pages written through this crate are dirty, see the
+/// [module documentation](index.html): writes of the guest itself or of other code in a remote
+/// hypervisor are missing from the diff.
+///
+/// `base` is the identifier of the snapshot or diff which this diff follows, as returned by
+/// `write_snapshot` or `write_diff`. Returns the identifier of the diff.
+///
+/// All regions must track dirty pages with the same page size, see
+/// `GuestMemoryRegion::dirty_bitmap`. The pages are marked clean before they are read, so a page
+/// written concurrently is saved now or in the next diff. If writing the diff fails, the chain of
+/// diffs has to start over with a full snapshot.
+pub fn write_diff<M: GuestMemory, W: Write>(mem: &M, base: u64, mut w: W) -> Result<u64> {
+    let mut page_size = None;
+    for region in mem.iter() {
+        let bitmap = region
+            .dirty_bitmap()
+            .ok_or_else(|| Error::NoDirtyTracking(region.start_addr()))?;
+        if *page_size.get_or_insert(bitmap.page_size()) != bitmap.page_size() {
+            return Err(Error::InvalidPageSize(bitmap.page_size()));
+        }
+    }
+    write_header(
+        &mut w,
+        &DIFF_MAGIC,
+        page_size.unwrap_or(0x1000),
+        mem.num_regions(),
+        base,
+    )?;
+
+    let mut id = file_id(base);
+    let mut buf = vec![0; BATCH];
+    for region in mem.iter() {
+        let mut section = Checked::new(&mut w);
+        RegionLayout::of(region).write(&mut section)?;
+        let ranges = region.dirty_bitmap().map(DirtyBitmap::take_dirty_ranges);
+        for (mut offset, len) in ranges.into_iter().flatten() {
+            let end = offset + len;
+            while offset < end {
+                let batch = &mut buf[..min(BATCH, end - offset)];
+                region.read_slice(batch, MemoryRegionAddress(offset as u64))?;
+                write_record(&mut section, offset, batch)?;
+                offset += batch.len();
+            }
+        }
+        write_record(&mut section, region.len() as usize, &[])?;
+        let sum = section.sum;
+        w.write_all(&sum.to_le_bytes())?;
+        id.update(&sum.to_le_bytes());
+    }
+    w.flush()?;
+    Ok(id.sum)
+}
+
+/// Applies a diff written by `write_diff` to `mem` through `Bytes`, which must have regions with
+/// the same base addresses and lengths as the memory the diff was taken of.
+///
+/// The diff must follow the snapshot or diff with the identifier `base`, which was restored into
+/// or applied to `mem` last. Returns the identifier of the diff. Like `restore_into`, a region is
+/// written before its checksum is verified.
+pub fn apply_diff<M: GuestMemory, R: Read>(mem: &M, base: u64, mut r: R) -> Result<u64> {
+    let (_, count, found) = read_header(&mut r, &DIFF_MAGIC)?;
+    if found != base {
+        return Err(Error::WrongBase {
+            expected: base,
+            found,
+        });
+    }
+    check_count(mem, count)?;
+    let mut id = file_id(base);
+    for _ in 0..count {
+        let mut section = Checked::new(&mut r);
+        let layout = RegionLayout::read(&mut section)?;
+        let region = layout.find(mem)?;
+        restore_region(region, &layout, false, &mut section)?;
+        let sum = section.sum;
+        check_sum(sum, &layout, &mut r)?;
+        id.update(&sum.to_le_bytes());
+    }
+    Ok(id.sum)
+}
+
+/// Restores the snapshot `base` into a new `GuestMemoryMmap` of the current process like
+/// `restore`, and applies the chain of `diffs` to it in order.
+///
+/// Fails with `Error::WrongBase` if a diff doesn't follow the snapshot or diff before it.
+pub fn restore_chain<R, I>(base: R, diffs: I) -> Result<GuestMemoryMmap>
+where
+    R: Read,
+    I: IntoIterator,
+    I::Item: Read,
+{
+    let (mem, mut id) = restore_with_id(base)?;
+    for diff in diffs {
+        id = apply_diff(&mem, id, diff)?;
+    }
+    Ok(mem)
+}
+
+/// Reads the records of a region section into `region`, zeroing the gaps between them if `zero`.
+fn restore_region<T: GuestMemoryRegion, R: Read>(
+    region: &T,
+    layout: &RegionLayout,
+    zero: bool,
+    r: &mut R,
+) -> Result<()> {
+    let mut pos = 0;
+    loop {
+        let offset = read_u64(r)?;
+        let len = read_u64(r)?;
+        let valid = matches!(offset.checked_add(len), Some(end) if end <= layout.len);
+        if offset < pos || !valid {
+            return Err(Error::InvalidRecord(layout.guest_base));
+        }
+        let end = if len == 0 { layout.len } else { offset };
+        if zero {
+            zero_range(region, pos, end)?;
+        }
+        if len == 0 {
+            return Ok(());
+        }
+        region.read_exact_from(MemoryRegionAddress(offset), r, len as usize)?;
+        pos = offset + len;
+    }
+}
+
+fn zero_range<T: GuestMemoryRegion>(region: &T, mut start: u64, end: u64) -> Result<()> {
+    let zeros = vec![0; min(BATCH as u64, end - start) as usize];
+    while start < end {
+        let len = min(zeros.len() as u64, end - start) as usize;
+        region.write_slice(&zeros[..len], MemoryRegionAddress(start))?;
+        start += len as u64;
+    }
+    Ok(())
+}
+
+fn check_sum<R: Read>(sum: u64, layout: &RegionLayout, r: &mut R) -> Result<()> {
+    if read_u64(r)? != sum {
+        return Err(Error::Checksum(layout.guest_base));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    use crate::test_utils::{local_pid, TestProcess};
+
+    fn fill(mem: &GuestMemoryMmap) {
+        mem.write_slice(&[1; 0x1800], GuestAddress(0x800)).unwrap();
+        mem.write_obj(0xdead_beefu32, GuestAddress(0x5ffc)).unwrap();
+        mem.write_slice(&[2; 0x3000], GuestAddress(0x10000))
+            .unwrap();
+    }
+
+    fn contents(mem: &GuestMemoryMmap) -> Vec<u8> {
+        let mut buf = vec![0; 0x20000];
+        mem.read_slice(&mut buf[..0x10000], GuestAddress(0))
+            .unwrap();
+        mem.read_slice(&mut buf[0x10000..], GuestAddress(0x10000))
+            .unwrap();
+        buf
+    }
+
+    fn check(mem: &GuestMemoryMmap) {
+        let buf = contents(mem);
+        let mut expected = vec![0; 0x20000];
+        expected[0x800..0x2000].copy_from_slice(&[1; 0x1800]);
+        expected[0x5ffc..0x6000].copy_from_slice(&0xdead_beefu32.to_ne_bytes());
+        expected[0x10000..0x13000].copy_from_slice(&[2; 0x3000]);
+        assert!(buf == expected);
+    }
+
+    #[test]
+    fn test_snapshot() {
+        let ranges = [(GuestAddress(0), 0x10000), (GuestAddress(0x10000), 0x10000)];
+        let gm = GuestMemoryMmap::from_ranges(local_pid(), &ranges).unwrap();
+        fill(&gm);
+        let mut file = Vec::new();
+        write_snapshot(&gm, 0x1000, &mut file).unwrap();
+        // Pages 0, 1 and 5 of the first region and 0 to 2 of the second one.
+        assert_eq!(&file[..8], b"GMEMSNAP");
+        assert_eq!(file.len(), 32 + 2 * (24 + 8) + 3 * 16 + 6 * 0x1000 + 2 * 16);
+
+        let restored = restore(Cursor::new(&file)).unwrap();
+        assert_eq!(restored.num_regions(), 2);
+        assert_eq!(restored.last_addr(), GuestAddress(0x1ffff));
+        check(&restored);
+
+        // Writing back zeroes the pages which were all zero.
+        gm.write_slice(&[3; 0x8000], GuestAddress(0xc000)).unwrap();
+        restore_into(&gm, Cursor::new(&file)).unwrap();
+        check(&gm);
+
+        let other = GuestMemoryMmap::from_ranges(local_pid(), &ranges[..1]).unwrap();
+        assert!(matches!(
+            restore_into(&other, Cursor::new(&file)),
+            Err(Error::RegionCount {
+                snapshot: 2,
+                memory: 1
+            })
+        ));
+        let ranges = [(GuestAddress(0), 0x10000), (GuestAddress(0x10000), 0x20000)];
+        let other = GuestMemoryMmap::from_ranges(local_pid(), &ranges).unwrap();
+        assert!(matches!(
+            restore_into(&other, Cursor::new(&file)),
+            Err(Error::RegionMismatch { len: 0x10000, .. })
+        ));
+
+        assert!(matches!(
+            write_snapshot(&gm, 0x1800, Vec::new()),
+            Err(Error::InvalidPageSize(0x1800))
+        ));
+    }
+
+    #[test]
+    fn test_corrupt_snapshot() {
+        let gm = GuestMemoryMmap::from_ranges(local_pid(), &[(GuestAddress(0), 0x4000)]).unwrap();
+        gm.write_slice(&[1; 0x10], GuestAddress(0x1000)).unwrap();
+        let mut file = Vec::new();
+        write_snapshot(&gm, 0x1000, &mut file).unwrap();
+
+        let mut bad = file.clone();
+        bad[0] = b'X';
+        assert!(matches!(restore(Cursor::new(&bad)), Err(Error::BadMagic)));
+        let mut bad = file.clone();
+        bad[8] = 2;
+        assert!(matches!(
+            restore(Cursor::new(&bad)),
+            Err(Error::UnsupportedVersion(2))
+        ));
+        let mut bad = file.clone();
+        bad[32 + 24 + 16 + 4] ^= 1;
+        assert!(matches!(
+            restore(Cursor::new(&bad)),
+            Err(Error::Checksum(GuestAddress(0)))
+        ));
+        // The record of page 1 claims to end past the region.
+        let mut bad = file.clone();
+        bad[32 + 24 + 8 + 1] = 0x40;
+        assert!(matches!(
+            restore(Cursor::new(&bad)),
+            Err(Error::InvalidRecord(GuestAddress(0)))
+        ));
+        assert!(matches!(
+            restore(Cursor::new(&file[..file.len() - 1])),
+            Err(Error::Io(_))
+        ));
+        // A huge region count fails once the file ends.
+        let mut bad = file.clone();
+        bad[16..20].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert!(matches!(restore(Cursor::new(&bad)), Err(Error::Io(_))));
+        for len in &[0, MAX_REGION_LEN + 1] {
+            let mut bad = file.clone();
+            bad[40..48].copy_from_slice(&len.to_le_bytes());
+            assert!(matches!(
+                restore(Cursor::new(&bad)),
+                Err(Error::InvalidRegion(GuestAddress(0)))
+            ));
+        }
+        let mut bad = file.clone();
+        bad[32..40].copy_from_slice(&u64::MAX.to_le_bytes());
+        assert!(matches!(
+            restore(Cursor::new(&bad)),
+            Err(Error::InvalidRegion(GuestAddress(u64::MAX)))
+        ));
+    }
+
+    #[test]
+    fn test_remote_snapshot() {
+        let (_child, gm) = TestProcess::guest_memory(&[
+            (GuestAddress(0), 0x10000),
+            (GuestAddress(0x10000), 0x10000),
+        ]);
+        fill(&gm);
+        let mut file = Vec::new();
+        write_snapshot(&gm, 0x1000, &mut file).unwrap();
+        check(&restore(Cursor::new(&file)).unwrap());
+
+        gm.write_slice(&[3; 0x20000], GuestAddress(0)).unwrap();
+        restore_into(&gm, Cursor::new(&file)).unwrap();
+        check(&gm);
+    }
+
+    #[test]
+    fn test_diff() {
+        let ranges = [(GuestAddress(0), 0x10000), (GuestAddress(0x10000), 0x10000)];
+        let gm = GuestMemoryMmap::from_ranges(local_pid(), &ranges).unwrap();
+        assert!(matches!(
+            write_diff(&gm, 0, Vec::new()),
+            Err(Error::NoDirtyTracking(GuestAddress(0)))
+        ));
+        let gm = gm.with_dirty_tracking(0x1000).unwrap();
+        fill(&gm);
+        gm.take_dirty_ranges();
+        let mut base = Vec::new();
+        let base_id = write_snapshot(&gm, 0x1000, &mut base).unwrap();
+
+        // Pages 1 and 2 of the first region and 8 of the second one.
+        gm.write_slice(&[4; 0x10], GuestAddress(0x1ff8)).unwrap();
+        gm.write_obj(5u64, GuestAddress(0x18000)).unwrap();
+        let mut diff1 = Vec::new();
+        let id1 = write_diff(&gm, base_id, &mut diff1).unwrap();
+        assert_eq!(&diff1[..8], b"GMEMDIFF");
+        assert_eq!(diff1[24..32], base_id.to_le_bytes());
+        assert_eq!(
+            diff1.len(),
+            32 + 2 * (16 + 8) + 2 * 16 + 3 * 0x1000 + 2 * 16
+        );
+        assert!(gm.dirty_ranges().is_empty());
+        let after1 = contents(&gm);
+
+        // Pages which are zeroed are part of the diff.
+        gm.write_slice(&[0; 0x1000], GuestAddress(0x1000)).unwrap();
+        let mut diff2 = Vec::new();
+        let id2 = write_diff(&gm, id1, &mut diff2).unwrap();
+        assert_ne!(id2, id1);
+
+        let diffs = vec![Cursor::new(&diff1), Cursor::new(&diff2)];
+        let restored = restore_chain(Cursor::new(&base), diffs).unwrap();
+        assert!(contents(&restored) == contents(&gm));
+        let restored = restore_chain(Cursor::new(&base), Some(Cursor::new(&diff1))).unwrap();
+        assert!(contents(&restored) == after1);
+
+        // Diffs are only applied on top of their base.
+        assert!(matches!(
+            restore_chain(Cursor::new(&base), Some(Cursor::new(&diff2))),
+            Err(Error::WrongBase { expected, found }) if expected == base_id && found == id1
+        ));
+        let diffs = vec![Cursor::new(&diff1), Cursor::new(&diff1)];
+        assert!(matches!(
+            restore_chain(Cursor::new(&base), diffs),
+            Err(Error::WrongBase { .. })
+        ));
+        assert_eq!(
+            apply_diff(&restored, id1, Cursor::new(&diff2)).unwrap(),
+            id2
+        );
+        assert!(contents(&restored) == contents(&gm));
+
+        // Diffs and snapshots can't be mixed up.
+        assert!(matches!(
+            restore_chain(Cursor::new(&diff1), None::<Cursor<Vec<u8>>>),
+            Err(Error::BadMagic)
+        ));
+        assert!(matches!(
+            apply_diff(&restored, base_id, Cursor::new(&base)),
+            Err(Error::BadMagic)
+        ));
+
+        let ranges = [(GuestAddress(0), 0x10000), (GuestAddress(0x20000), 0x10000)];
+        let other = GuestMemoryMmap::from_ranges(local_pid(), &ranges).unwrap();
+        assert!(matches!(
+            apply_diff(&other, base_id, Cursor::new(&diff1)),
+            Err(Error::RegionMismatch {
+                guest_base: GuestAddress(0x10000),
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn test_remote_diff() {
+        let (_child, gm) = TestProcess::guest_memory(&[
+            (GuestAddress(0), 0x10000),
+            (GuestAddress(0x10000), 0x10000),
+        ]);
+        let gm = gm.with_dirty_tracking(0x1000).unwrap();
+        gm.take_dirty_ranges();
+        let mut base = Vec::new();
+        let base_id = write_snapshot(&gm, 0x1000, &mut base).unwrap();
+
+        fill(&gm);
+        let mut diff = Vec::new();
+        write_diff(&gm, base_id, &mut diff).unwrap();
+        check(&restore_chain(Cursor::new(&base), Some(Cursor::new(&diff))).unwrap());
+    }
+}
@@ -0,0 +1,361 @@
+//! Statistics about accesses to the memory of another process.
+//!
+//! Every access to remote guest memory costs at least one system call. Wrapping the transport of
+//! a guest memory object in an [`Instrumented`](struct.Instrumented.html) transport counts the
+//! calls, the transferred bytes, the failures and the sizes of the accesses, and lets a hook
+//! observe every read, write, load and store of its regions. This helps to find the device paths
+//! which are heavy on system calls.
+
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, RwLock};
+
+use nix::sys::uio::RemoteIoVec;
+use nix::unistd::Pid;
+
+use crate::guest_memory::GuestAddress;
+use crate::liveness::Watched;
+use crate::remote_mem::{Error, Transport};
+
+/// Number of buckets of the access size histogram.
+///
+/// Bucket `i` counts accesses of more than `2^(i-1)` and at most `2^i` bytes, the last bucket
+/// also counts all larger accesses.
+pub const SIZE_BUCKETS: usize = 13;
+
+/// The kind of a guest memory access.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AccessKind {
+    /// `Bytes::read` and the accessors built on it.
+    Read,
+    /// `Bytes::write` and the accessors built on it.
+    Write,
+    /// `Bytes::load`.
+    Load,
+    /// `Bytes::store`.
+    Store,
+}
+
+/// How a remote access failed, see [`remote_mem::Error`](../remote_mem/enum.Error.html).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ErrorKind {
+    /// The remote process doesn't exist anymore.
+    ProcessGone,
+    /// The remote range isn't mapped.
+    BadAddress,
+    /// Accessing the remote memory isn't permitted.
+    PermissionDenied,
+    /// Any other failure.
+    Other,
+}
+
+impl ErrorKind {
+    const COUNT: usize = 4;
+
+    /// Classifies `e`.
+    pub fn of(e: &Error) -> Self {
+        match e {
+            Error::ProcessGone { .. } | Error::Dead { .. } | Error::Identity { .. } => {
+                ErrorKind::ProcessGone
+            }
+            Error::BadAddress { .. } => ErrorKind::BadAddress,
+            Error::PermissionDenied { .. } => ErrorKind::PermissionDenied,
+            _ => ErrorKind::Other,
+        }
+    }
+}
+
+/// An access to a guest memory region, as reported to an [`AccessHook`](type.AccessHook.html).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct AccessEvent {
+    /// The kind of the access.
+    pub kind: AccessKind,
+    /// The guest address of the access.
+    pub addr: GuestAddress,
+    /// The number of bytes requested.
+    pub len: usize,
+    /// How the access failed, `None` if it succeeded.
+    pub error: Option<ErrorKind>,
+}
+
+/// A callback invoked for every access to remote guest memory.
+pub type AccessHook = dyn Fn(&AccessEvent) + Send + Sync;
+
+/// The values of the counters of [`Stats`](struct.Stats.html) at one point in time.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct StatsSnapshot {
+    /// Calls to the transport, each of which usually is one system call.
+    pub syscalls: u64,
+    /// Bytes read from the remote process.
+    pub bytes_read: u64,
+    /// Bytes written to the remote process.
+    pub bytes_written: u64,
+    /// Failed calls, indexed by `ErrorKind`.
+    pub errors: [u64; ErrorKind::COUNT],
+    /// Histogram of the sizes of the calls, see `SIZE_BUCKETS`.
+    pub sizes: [u64; SIZE_BUCKETS],
+}
+
+impl StatsSnapshot {
+    /// Returns the number of calls which failed with `kind`.
+    pub fn errors(&self, kind: ErrorKind) -> u64 {
+        self.errors[kind as usize]
+    }
+
+    /// Adds the counters of `other` to `self`.
+    pub fn merge(&mut self, other: &StatsSnapshot) {
+        self.syscalls += other.syscalls;
+        self.bytes_read += other.bytes_read;
+        self.bytes_written += other.bytes_written;
+        for (a, b) in self.errors.iter_mut().zip(other.errors.iter()) {
+            *a += b;
+        }
+        for (a, b) in self.sizes.iter_mut().zip(other.sizes.iter()) {
+            *a += b;
+        }
+    }
+}
+
+/// Counters of the accesses through a transport, and the hook observing them.
+///
+/// All counters are updated with relaxed atomics, so a snapshot taken while accesses are in
+/// flight may be slightly inconsistent.
+#[derive(Default)]
+pub struct Stats {
+    syscalls: AtomicU64,
+    bytes_read: AtomicU64,
+    bytes_written: AtomicU64,
+    errors: [AtomicU64; ErrorKind::COUNT],
+    sizes: [AtomicU64; SIZE_BUCKETS],
+    hook: RwLock<Option<Arc<AccessHook>>>,
+}
+
+impl fmt::Debug for Stats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Stats")
+            .field("counters", &self.snapshot())
+            .field("hook", &self.hook.read().unwrap().is_some())
+            .finish()
+    }
+}
+
+impl Stats {
+    /// Creates zeroed counters without a hook.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns the current values of the counters.
+    pub fn snapshot(&self) -> StatsSnapshot {
+        let load = |c: &AtomicU64| c.load(Ordering::Relaxed);
+        let mut snapshot = StatsSnapshot {
+            syscalls: load(&self.syscalls),
+            bytes_read: load(&self.bytes_read),
+            bytes_written: load(&self.bytes_written),
+            ..Default::default()
+        };
+        for (s, c) in snapshot.errors.iter_mut().zip(self.errors.iter()) {
+            *s = load(c);
+        }
+        for (s, c) in snapshot.sizes.iter_mut().zip(self.sizes.iter()) {
+            *s = load(c);
+        }
+        snapshot
+    }
+
+    /// Sets all counters to zero.
+    pub fn reset(&self) {
+        let counters = [&self.syscalls, &self.bytes_read, &self.bytes_written];
+        for c in counters
+            .iter()
+            .cloned()
+            .chain(self.errors.iter())
+            .chain(self.sizes.iter())
+        {
+            c.store(0, Ordering::Relaxed);
+        }
+    }
+
+    /// Sets the hook invoked for every access to a region accessed through the transport, or
+    /// removes it with `None`.
+    pub fn set_hook(&self, hook: Option<Arc<AccessHook>>) {
+        *self.hook.write().unwrap() = hook;
+    }
+
+    /// Invokes the hook, if any, for the access of `len` bytes at `addr`.
+    pub(crate) fn notify<T>(
+        &self,
+        kind: AccessKind,
+        addr: GuestAddress,
+        len: usize,
+        res: &Result<T, Error>,
+    ) {
+        let hook = self.hook.read().unwrap().clone();
+        if let Some(hook) = hook {
+            hook(&AccessEvent {
+                kind,
+                addr,
+                len,
+                error: res.as_ref().err().map(ErrorKind::of),
+            });
+        }
+    }
+
+    /// Counts a call transferring `res` of `len` requested bytes.
+    fn record(&self, write: bool, len: usize, res: &Result<usize, Error>) {
+        self.syscalls.fetch_add(1, Ordering::Relaxed);
+        let bucket = (0..SIZE_BUCKETS - 1)
+            .find(|&i| len <= 1 << i)
+            .unwrap_or(SIZE_BUCKETS - 1);
+        self.sizes[bucket].fetch_add(1, Ordering::Relaxed);
+        match res {
+            Ok(n) if write => self.bytes_written.fetch_add(*n as u64, Ordering::Relaxed),
+            Ok(n) => self.bytes_read.fetch_add(*n as u64, Ordering::Relaxed),
+            Err(e) => self.errors[ErrorKind::of(e) as usize].fetch_add(1, Ordering::Relaxed),
+        };
+    }
+}
+
+/// A `Transport` which counts the accesses through `inner` in a [`Stats`](struct.Stats.html).
+///
+/// Regions accessed through it report their accesses to the hook of the statistics.
+#[derive(Debug)]
+pub struct Instrumented {
+    inner: Arc<dyn Transport>,
+    stats: Arc<Stats>,
+}
+
+impl Instrumented {
+    /// Wraps `inner`, counting its accesses in `stats`.
+    pub fn new(inner: Arc<dyn Transport>, stats: Arc<Stats>) -> Self {
+        Instrumented { inner, stats }
+    }
+}
+
+impl Transport for Instrumented {
+    fn pid(&self) -> Pid {
+        self.inner.pid()
+    }
+
+    fn read_bytes(&self, buf: &mut [u8], addr: usize) -> Result<usize, Error> {
+        let res = self.inner.read_bytes(buf, addr);
+        self.stats.record(false, buf.len(), &res);
+        res
+    }
+
+    fn write_bytes(&self, addr: usize, buf: &[u8]) -> Result<usize, Error> {
+        let res = self.inner.write_bytes(addr, buf);
+        self.stats.record(true, buf.len(), &res);
+        res
+    }
+
+    fn readv(&self, bufs: &mut [&mut [u8]], remote: &[RemoteIoVec]) -> Result<usize, Error> {
+        let res = self.inner.readv(bufs, remote);
+        self.stats
+            .record(false, remote.iter().map(|r| r.len).sum(), &res);
+        res
+    }
+
+    fn writev(&self, bufs: &[&[u8]], remote: &[RemoteIoVec]) -> Result<usize, Error> {
+        let res = self.inner.writev(bufs, remote);
+        self.stats
+            .record(true, remote.iter().map(|r| r.len).sum(), &res);
+        res
+    }
+
+    fn alignment(&self) -> usize {
+        self.inner.alignment()
+    }
+
+    fn stats(&self) -> Option<&Arc<Stats>> {
+        Some(&self.stats)
+    }
+
+    fn watched(&self) -> Option<&Watched> {
+        self.inner.watched()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::Mutex;
+
+    use crate::remote_mem::{load, store, ProcessVm};
+
+    #[test]
+    fn test_instrumented() {
+        let stats = Arc::new(Stats::new());
+        let t = Instrumented::new(Arc::new(ProcessVm::new(Pid::this())), stats.clone());
+        assert!(Arc::ptr_eq(t.stats().unwrap(), &stats));
+
+        let mut buf = vec![0u8; 0x3000];
+        let addr = buf.as_mut_ptr() as usize;
+        assert_eq!(t.write_bytes(addr, &[1u8; 0x3000]).unwrap(), 0x3000);
+        store(&t, addr + 4, &7u32).unwrap();
+        assert_eq!(load::<u16>(&t, addr + 4).unwrap(), 7);
+        let remote = [RemoteIoVec { base: addr, len: 3 }];
+        assert_eq!(t.readv(&mut [&mut [0u8; 3][..]], &remote).unwrap(), 3);
+        assert!(t.read_bytes(&mut [0u8; 2], 0).is_err());
+
+        let s = stats.snapshot();
+        assert_eq!(s.syscalls, 5);
+        assert_eq!(s.bytes_written, 0x3000 + 4);
+        assert_eq!(s.bytes_read, 8 + 3);
+        assert_eq!(s.errors(ErrorKind::BadAddress), 1);
+        assert_eq!(s.errors(ErrorKind::ProcessGone), 0);
+        let mut sizes = [0; SIZE_BUCKETS];
+        sizes[1] = 1;
+        sizes[2] = 2;
+        sizes[3] = 1;
+        sizes[SIZE_BUCKETS - 1] = 1;
+        assert_eq!(s.sizes, sizes);
+
+        let mut total = s.clone();
+        total.merge(&s);
+        assert_eq!(total.syscalls, 10);
+        assert_eq!(total.sizes[2], 4);
+
+        stats.reset();
+        assert_eq!(stats.snapshot(), StatsSnapshot::default());
+    }
+
+    #[test]
+    fn test_hook() {
+        let stats = Stats::new();
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let e = events.clone();
+        stats.set_hook(Some(Arc::new(move |event: &AccessEvent| {
+            e.lock().unwrap().push(*event)
+        })));
+
+        stats.notify(AccessKind::Load, GuestAddress(0x1000), 4, &Ok(()));
+        let err: Result<(), Error> = Err(Error::ProcessGone {
+            pid: Pid::this(),
+            addr: 0,
+            len: 1,
+        });
+        stats.notify(AccessKind::Write, GuestAddress(0x2000), 1, &err);
+        stats.set_hook(None);
+        stats.notify(AccessKind::Read, GuestAddress(0), 1, &Ok(()));
+
+        assert_eq!(
+            *events.lock().unwrap(),
+            vec![
+                AccessEvent {
+                    kind: AccessKind::Load,
+                    addr: GuestAddress(0x1000),
+                    len: 4,
+                    error: None,
+                },
+                AccessEvent {
+                    kind: AccessKind::Write,
+                    addr: GuestAddress(0x2000),
+                    len: 1,
+                    error: Some(ErrorKind::ProcessGone),
+                },
+            ]
+        );
+    }
+}
@@ -0,0 +1,159 @@
+//! Helpers for testing accesses to the memory of another process.
+//!
+//! Tests can access the memory of the current process through any transport, but that doesn't
+//! catch remote addresses being dereferenced locally by accident. `TestProcess` forks a child
+//! which allocates buffers and reports their addresses, so that remote guest memory can be
+//! exercised end-to-end.
+
+use std::mem::size_of;
+use std::ptr::null_mut;
+#[cfg(feature = "backend-mmap")]
+use std::sync::Arc;
+
+use libc::{c_void, pid_t};
+use nix::sys::signal::{kill, Signal};
+use nix::sys::wait::waitpid;
+use nix::unistd::{close, fork, pipe, read, ForkResult, Pid};
+
+#[cfg(feature = "backend-mmap")]
+use crate::remote_mem::default_transport;
+#[cfg(feature = "backend-mmap")]
+use crate::stats::{Instrumented, Stats};
+#[cfg(feature = "backend-mmap")]
+use crate::{GuestAddress, GuestMemoryMmap};
+
+/// Returns the pid of the current process, whose memory is accessed directly.
+pub fn local_pid() -> pid_t {
+    Pid::this().as_raw()
+}
+
+/// A child process holding anonymous read-write buffers. It's killed when dropped.
+#[derive(Debug)]
+pub struct TestProcess {
+    pid: Pid,
+    addrs: Vec<usize>,
+}
+
+impl TestProcess {
+    /// Forks a child which maps a buffer for each of `sizes` and then waits to be killed.
+    pub fn spawn(sizes: &[usize]) -> TestProcess {
+        let (rx, tx) = pipe().unwrap();
+        // Safe because the child only makes raw system calls, which is all that's allowed after
+        // forking the multithreaded test harness.
+        match unsafe { fork() }.unwrap() {
+            ForkResult::Child => unsafe { child(sizes, tx) },
+            ForkResult::Parent { child } => {
+                close(tx).unwrap();
+                let mut buf = vec![0u8; sizes.len() * size_of::<usize>()];
+                let mut done = 0;
+                while done < buf.len() {
+                    match read(rx, &mut buf[done..]).unwrap() {
+                        0 => panic!("test process {} exited early", child),
+                        n => done += n,
+                    }
+                }
+                close(rx).unwrap();
+
+                let addrs = buf
+                    .chunks(size_of::<usize>())
+                    .map(|b| {
+                        let mut addr = [0u8; size_of::<usize>()];
+                        addr.copy_from_slice(b);
+                        usize::from_ne_bytes(addr)
+                    })
+                    .collect();
+                TestProcess { pid: child, addrs }
+            }
+        }
+    }
+
+    /// Spawns a child with a buffer for each of `ranges` and returns its guest memory, which has
+    /// a region for each (guest base, size) pair.
+    ///
+    /// The regions share an `Instrumented` transport, so `GuestMemoryMmap::stats` counts the
+    /// accesses.
+    #[cfg(feature = "backend-mmap")]
+    pub fn guest_memory(ranges: &[(GuestAddress, usize)]) -> (TestProcess, GuestMemoryMmap) {
+        let sizes: Vec<usize> = ranges.iter().map(|r| r.1).collect();
+        let child = TestProcess::spawn(&sizes);
+        let hints: Vec<_> = ranges
+            .iter()
+            .enumerate()
+            .map(|(i, &(base, size))| (base, child.addr(i), size))
+            .collect();
+        let transport = Instrumented::new(default_transport(child.pid), Arc::new(Stats::new()));
+        let gm = GuestMemoryMmap::from_hints_with_transport(Arc::new(transport), &hints).unwrap();
+        (child, gm)
+    }
+
+    /// Returns the pid of the child.
+    pub fn pid(&self) -> pid_t {
+        self.pid.as_raw()
+    }
+
+    /// Returns the address of the `index`th buffer in the child.
+    pub fn addr(&self, index: usize) -> usize {
+        self.addrs[index]
+    }
+}
+
+impl Drop for TestProcess {
+    fn drop(&mut self) {
+        let _ = kill(self.pid, Signal::SIGKILL);
+        let _ = waitpid(self.pid, None);
+    }
+}
+
+/// Body of the child: maps the buffers, writes their addresses to `tx` and sleeps.
+unsafe fn child(sizes: &[usize], tx: i32) -> ! {
+    // Don't outlive the test if it dies without dropping the `TestProcess`.
+    libc::prctl(libc::PR_SET_PDEATHSIG, libc::SIGKILL);
+    for &size in sizes {
+        let addr = libc::mmap(
+            null_mut(),
+            size,
+            libc::PROT_READ | libc::PROT_WRITE,
+            libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+            -1,
+            0,
+        );
+        if addr == libc::MAP_FAILED {
+            libc::_exit(1);
+        }
+        let bytes = (addr as usize).to_ne_bytes();
+        if libc::write(tx, bytes.as_ptr() as *const c_void, bytes.len()) != bytes.len() as isize {
+            libc::_exit(1);
+        }
+    }
+    libc::close(tx);
+    loop {
+        libc::pause();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::remote_mem::{ProcessVm, Transport};
+
+    #[test]
+    fn test_spawn() {
+        let child = TestProcess::spawn(&[0x1000, 0x2000]);
+        assert_ne!(child.pid(), local_pid());
+
+        let transport = ProcessVm::new(Pid::from_raw(child.pid()));
+        transport
+            .write_bytes(child.addr(1) + 0x1ff8, &[1u8; 8])
+            .unwrap();
+        let mut buf = [0u8; 16];
+        transport
+            .read_bytes(&mut buf, child.addr(1) + 0x1ff0)
+            .unwrap();
+        assert_eq!(buf, [0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 1, 1, 1, 1]);
+
+        let pid = child.pid;
+        drop(child);
+        assert!(ProcessVm::new(pid).read_bytes(&mut buf, 0x1000).is_err());
+    }
+}
@@ -37,7 +37,12 @@ use std::slice::{from_raw_parts, from_raw_parts_mut};
 use std::sync::atomic::Ordering;
 use std::usize;
 
-use crate::atomic_integer::AtomicInteger;
+use nix::unistd::Pid;
+
+use crate::atomic_integer::{AtomicCompareExchange, AtomicInteger};
+use crate::bitmap::DirtyBitmap;
+use crate::guest_memory::MAX_ACCESS_CHUNK;
+use crate::remote_mem::{self, Transport};
 use crate::{AtomicAccess, ByteValued, Bytes};
 
 use copy_slice_impl::copy_slice;
@@ -58,6 +63,10 @@ pub enum Error {
     IOError(io::Error),
     /// Incomplete read or write
     PartialBuffer { expected: usize, completed: usize },
+    /// `addr` belongs to the memory of a remote process and can't be referenced directly.
+    RemoteAddress { addr: usize },
+    /// Accessing the memory of a remote process failed.
+    RemoteMemError(remote_mem::Error),
 }
 
 impl fmt::Display for Error {
@@ -86,11 +95,24 @@ impl fmt::Display for Error {
                 "only used {} bytes in {} long buffer",
                 completed, expected
             ),
+            Error::RemoteAddress { addr } => write!(
+                f,
+                "address 0x{:x} belongs to a remote process and can't be referenced",
+                addr
+            ),
+            Error::RemoteMemError(e) => write!(f, "{}", e),
         }
     }
 }
 
-impl error::Error for Error {}
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            Error::RemoteMemError(e) => Some(e),
+            _ => None,
+        }
+    }
+}
 
 /// Result of volatile memory operations.
 pub type Result<T> = result::Result<T, Error>;
@@ -139,6 +161,7 @@ pub trait VolatileMemory {
     /// Gets a `VolatileRef` at `offset`.
     fn get_ref<T: ByteValued>(&self, offset: usize) -> Result<VolatileRef<T>> {
         let slice = self.get_slice(offset, size_of::<T>())?;
+        slice.check_local()?;
         unsafe {
             // This is safe because the pointer is range-checked by get_slice, and
             // the lifetime is the same as self.
@@ -158,6 +181,7 @@ pub trait VolatileMemory {
                 size: size_of::<T>(),
             })?;
         let slice = self.get_slice(offset, nbytes as usize)?;
+        slice.check_local()?;
         unsafe {
             // This is safe because the pointer is range-checked by get_slice, and
             // the lifetime is the same as self.
@@ -177,6 +201,7 @@ pub trait VolatileMemory {
     /// [`Error`](enum.Error.html).
     unsafe fn aligned_as_ref<T: ByteValued>(&self, offset: usize) -> Result<&T> {
         let slice = self.get_slice(offset, size_of::<T>())?;
+        slice.check_local()?;
         slice.check_alignment(align_of::<T>())?;
         Ok(&*(slice.addr as *const T))
     }
@@ -194,6 +219,7 @@ pub trait VolatileMemory {
     /// [`Error`](enum.Error.html).
     unsafe fn aligned_as_mut<T: ByteValued>(&self, offset: usize) -> Result<&mut T> {
         let slice = self.get_slice(offset, size_of::<T>())?;
+        slice.check_local()?;
         slice.check_alignment(align_of::<T>())?;
         Ok(&mut *(slice.addr as *mut T))
     }
@@ -206,6 +232,7 @@ pub trait VolatileMemory {
     /// [`Error`](enum.Error.html).
     fn get_atomic_ref<T: AtomicInteger>(&self, offset: usize) -> Result<&T> {
         let slice = self.get_slice(offset, size_of::<T>())?;
+        slice.check_local()?;
         slice.check_alignment(align_of::<T>())?;
 
         unsafe {
@@ -247,10 +274,17 @@ impl<'a> VolatileMemory for &'a mut [u8] {
 struct Packed<T>(T);
 
 /// A slice of raw memory that supports volatile access.
+///
+/// The memory may also belong to another process (see
+/// [`new_remote`](struct.VolatileSlice.html#method.new_remote)), in which case it is only ever
+/// accessed through [`remote_mem`](../remote_mem/index.html) and never referenced directly.
 #[derive(Copy, Clone, Debug)]
 pub struct VolatileSlice<'a> {
     addr: *mut u8,
     size: usize,
+    transport: Option<&'a dyn Transport>,
+    // The bitmap tracking writes and the offset of the slice in the memory it covers.
+    dirty: Option<(&'a DirtyBitmap, usize)>,
     phantom: PhantomData<&'a u8>,
 }
 
@@ -267,15 +301,70 @@ impl<'a> VolatileSlice<'a> {
         VolatileSlice {
             addr,
             size,
+            transport: None,
+            dirty: None,
+            phantom: PhantomData,
+        }
+    }
+
+    /// Creates a slice of `size` bytes at address `addr` in the memory behind `transport`.
+    ///
+    /// All accesses go through `transport`, so the slice never
+    /// dereferences `addr` in the current process. Methods which hand out references to the
+    /// memory, like [`get_ref`](trait.VolatileMemory.html#method.get_ref), fail with
+    /// [`Error::RemoteAddress`](enum.Error.html#variant.RemoteAddress).
+    pub fn new_remote(transport: &'a dyn Transport, addr: usize, size: usize) -> VolatileSlice<'a> {
+        VolatileSlice {
+            addr: addr as *mut u8,
+            size,
+            transport: Some(transport),
+            dirty: None,
             phantom: PhantomData,
         }
     }
 
+    /// Returns the slice, marking the pages it writes in `bitmap`. The slice starts `offset`
+    /// bytes into the memory tracked by `bitmap`.
+    ///
+    /// Subslices share the bitmap.
+    pub fn with_dirty_bitmap(self, bitmap: &'a DirtyBitmap, offset: usize) -> VolatileSlice<'a> {
+        VolatileSlice {
+            dirty: Some((bitmap, offset)),
+            ..self
+        }
+    }
+
+    /// Marks the pages overlapping the `len` bytes at `offset` as dirty in the bitmap of the
+    /// slice, if it has one.
+    ///
+    /// This is done by all writes through the slice, but not by writes through references the
+    /// slice hands out, e.g. by [`get_ref`](trait.VolatileMemory.html#method.get_ref).
+    pub fn mark_dirty(&self, offset: usize, len: usize) {
+        if let Some((bitmap, base)) = self.dirty {
+            bitmap.mark(base + offset, min(len, self.size.saturating_sub(offset)));
+        }
+    }
+
     /// Returns a pointer to the beginning of the slice.
+    ///
+    /// For slices of a remote process this is an address in the remote process, which must not
+    /// be dereferenced.
     pub fn as_ptr(&self) -> *mut u8 {
         self.addr
     }
 
+    /// Returns the process owning the memory of this slice, or `None` if the memory belongs to
+    /// the current process.
+    pub fn pid(&self) -> Option<Pid> {
+        self.transport.map(|t| t.pid())
+    }
+
+    /// Returns the transport used to access the memory of this slice, or `None` if the memory
+    /// belongs to the current process.
+    pub fn transport(&self) -> Option<&'a dyn Transport> {
+        self.transport
+    }
+
     /// Gets the size of this slice.
     pub fn len(&self) -> usize {
         self.size
@@ -310,7 +399,7 @@ impl<'a> VolatileSlice<'a> {
         let end = self.offset(mid)?;
         let start = unsafe {
             // safe because self.offset() already checked the bounds
-            VolatileSlice::new(self.addr, mid)
+            self.subslice_unchecked(self.addr, mid)
         };
         Ok((start, end))
     }
@@ -328,10 +417,7 @@ impl<'a> VolatileSlice<'a> {
         unsafe {
             // This is safe because the pointer is range-checked by compute_end_offset, and
             // the lifetime is the same as the original slice.
-            Ok(VolatileSlice::new(
-                (self.as_ptr() as usize + offset) as *mut u8,
-                count,
-            ))
+            Ok(self.subslice_unchecked((self.as_ptr() as usize + offset) as *mut u8, count))
         }
     }
 
@@ -354,7 +440,7 @@ impl<'a> VolatileSlice<'a> {
         unsafe {
             // Safe because the memory has the same lifetime and points to a subset of the
             // memory of the original slice.
-            Ok(VolatileSlice::new(new_addr as *mut u8, new_size))
+            Ok(self.subslice_unchecked(new_addr as *mut u8, new_size))
         }
     }
 
@@ -382,10 +468,20 @@ impl<'a> VolatileSlice<'a> {
     ///     assert_eq!(v, 0);
     /// }
     /// ```
+    ///
+    /// For the memory of a remote process, an error of the transport is logged and counts as
+    /// nothing copied; use [`try_copy_to`](#method.try_copy_to) to get the error.
     pub fn copy_to<T>(&self, buf: &mut [T]) -> usize
     where
         T: ByteValued,
     {
+        if self.transport.is_some() {
+            return self.try_copy_to(buf).unwrap_or_else(|e| {
+                log::warn!("cannot copy from remote guest memory: {}", e);
+                0
+            });
+        }
+
         // A fast path for u8/i8
         if size_of::<T>() == 1 {
             // It is safe because the pointers are range-checked when the slices are created,
@@ -401,6 +497,29 @@ impl<'a> VolatileSlice<'a> {
         }
     }
 
+    /// Like [`copy_to`](#method.copy_to), but fails if the memory of a remote process can't be
+    /// read.
+    ///
+    /// Returns the number of elements copied, which is less than with `copy_to` if the transport
+    /// only read a part of the slice.
+    pub fn try_copy_to<T>(&self, buf: &mut [T]) -> Result<usize>
+    where
+        T: ByteValued,
+    {
+        let transport = match self.transport {
+            Some(transport) => transport,
+            None => return Ok(self.copy_to(buf)),
+        };
+        let count = min(self.size / size_of::<T>(), buf.len());
+        // Safe because `T` is `ByteValued` and `count` elements fit into `buf`.
+        let dst =
+            unsafe { from_raw_parts_mut(buf.as_mut_ptr() as *mut u8, count * size_of::<T>()) };
+        let read = transport
+            .read_bytes(dst, self.addr as usize)
+            .map_err(Error::RemoteMemError)?;
+        Ok(read / size_of::<T>())
+    }
+
     /// Copies as many bytes as possible from this slice to the provided `slice`.
     ///
     /// The copies happen in an undefined order.
@@ -424,14 +543,45 @@ impl<'a> VolatileSlice<'a> {
     ///         .expect("Could not get VolatileSlice"),
     /// );
     /// ```
+    ///
+    /// If either slice is in the memory of a remote process, copying stops at the first error of
+    /// the transport, which is logged; use
+    /// [`try_copy_to_volatile_slice`](#method.try_copy_to_volatile_slice) to get the error.
     pub fn copy_to_volatile_slice(&self, slice: VolatileSlice) {
+        if let Err(e) = self.try_copy_to_volatile_slice(slice) {
+            log::warn!("cannot copy remote guest memory: {}", e);
+        }
+    }
+
+    /// Like [`copy_to_volatile_slice`](#method.copy_to_volatile_slice), but fails if the memory
+    /// of a remote process can't be accessed.
+    ///
+    /// Returns the number of bytes copied. On error, a prefix of the bytes may have been copied.
+    pub fn try_copy_to_volatile_slice(&self, slice: VolatileSlice) -> Result<usize> {
+        let len = min(self.size, slice.size);
+        if self.transport.is_some() || slice.transport.is_some() {
+            // At least one side lives in another process, so bounce the data through a local
+            // buffer.
+            let mut buf = vec![0u8; min(len, MAX_ACCESS_CHUNK)];
+            let mut done = 0;
+            while done < len {
+                let count = min(len - done, buf.len());
+                self.read_slice(&mut buf[..count], done)?;
+                slice.write_slice(&buf[..count], done)?;
+                done += count;
+            }
+            return Ok(len);
+        }
+
         unsafe {
             // Safe because the pointers are range-checked when the slices
             // are created, and they never escape the VolatileSlices.
             // FIXME: ... however, is it really okay to mix non-volatile
             // operations such as copy with read_volatile and write_volatile?
-            copy(self.addr, slice.addr, min(self.size, slice.size));
+            copy(self.addr, slice.addr, len);
         }
+        slice.mark_dirty(0, len);
+        Ok(len)
     }
 
     /// Copies as many elements of type `T` as possible from `buf` to this slice.
@@ -460,10 +610,24 @@ impl<'a> VolatileSlice<'a> {
     ///     assert_eq!(val, 0x05050505);
     /// }
     /// ```
+    ///
+    /// For the memory of a remote process, errors of the transport are only logged; use
+    /// [`try_copy_from`](#method.try_copy_from) to get them.
     pub fn copy_from<T>(&self, buf: &[T])
     where
         T: ByteValued,
     {
+        if self.transport.is_some() {
+            if let Err(e) = self.try_copy_from(buf) {
+                log::warn!("cannot copy to remote guest memory: {}", e);
+            }
+            return;
+        }
+        self.mark_dirty(
+            0,
+            min(self.size / size_of::<T>(), buf.len()) * size_of::<T>(),
+        );
+
         // A fast path for u8/i8
         if size_of::<T>() == 1 {
             // It is safe because the pointers are range-checked when the slices are created,
@@ -479,6 +643,60 @@ impl<'a> VolatileSlice<'a> {
         }
     }
 
+    /// Like [`copy_from`](#method.copy_from), but fails if the memory of a remote process can't
+    /// be written.
+    ///
+    /// Returns the number of elements copied, which is less than `buf.len()` if the slice is
+    /// too short for it or the transport only wrote a part of it.
+    pub fn try_copy_from<T>(&self, buf: &[T]) -> Result<usize>
+    where
+        T: ByteValued,
+    {
+        let count = min(self.size / size_of::<T>(), buf.len());
+        let transport = match self.transport {
+            Some(transport) => transport,
+            None => {
+                self.copy_from(buf);
+                return Ok(count);
+            }
+        };
+        // Safe because `T` is `ByteValued` and `count` elements fit into `buf`.
+        let src = unsafe { from_raw_parts(buf.as_ptr() as *const u8, count * size_of::<T>()) };
+        let written = transport
+            .write_bytes(self.addr as usize, src)
+            .map_err(Error::RemoteMemError)?;
+        self.mark_dirty(0, written);
+        Ok(written / size_of::<T>())
+    }
+
+    /// Atomically stores `new` at `addr` if the value there is `current`.
+    ///
+    /// Returns the previous value, wrapped in `Ok` if it was replaced and in `Err` otherwise.
+    ///
+    /// # Errors
+    ///
+    /// Fails with `Error::RemoteAddress` for the memory of a remote process, which can't be
+    /// accessed atomically, and with `Error::Misaligned` if `addr` isn't aligned for `T`.
+    pub fn compare_exchange<T: AtomicAccess>(
+        &self,
+        current: T,
+        new: T,
+        addr: usize,
+        success: Ordering,
+        failure: Ordering,
+    ) -> Result<result::Result<T, T>>
+    where
+        T::A: AtomicCompareExchange,
+    {
+        self.get_atomic_ref::<T::A>(addr).map(|r| {
+            let res = r.compare_exchange(current.into(), new.into(), success, failure);
+            if res.is_ok() {
+                self.mark_dirty(addr, size_of::<T>());
+            }
+            res.map(T::from).map_err(T::from)
+        })
+    }
+
     /// Returns a slice corresponding to the data in the underlying memory.
     ///
     /// # Safety
@@ -500,6 +718,205 @@ impl<'a> VolatileSlice<'a> {
         from_raw_parts_mut(self.addr, self.size)
     }
 
+    /// Creates a slice of the same (local or remote) memory as `self`, starting at `addr` and
+    /// spanning `size` bytes.
+    ///
+    /// # Safety
+    ///
+    /// The caller must guarantee that `addr` and `size` describe a part of `self`.
+    unsafe fn subslice_unchecked(&self, addr: *mut u8, size: usize) -> VolatileSlice<'a> {
+        VolatileSlice {
+            addr,
+            size,
+            transport: self.transport,
+            dirty: self
+                .dirty
+                .map(|(bitmap, base)| (bitmap, base + (addr as usize - self.addr as usize))),
+            phantom: PhantomData,
+        }
+    }
+
+    /// Checks that the memory of the slice can be referenced from the current process.
+    fn check_local(&self) -> Result<()> {
+        match self.transport {
+            Some(_) => Err(Error::RemoteAddress {
+                addr: self.addr as usize,
+            }),
+            None => Ok(()),
+        }
+    }
+
+    /// Reads from the remote memory at `offset` into `buf`.
+    ///
+    /// The caller must have checked that `buf` fits into the slice at `offset`.
+    fn process_read(
+        &self,
+        transport: &dyn Transport,
+        buf: &mut [u8],
+        offset: usize,
+    ) -> Result<usize> {
+        transport
+            .read_bytes(buf, self.addr as usize + offset)
+            .map_err(Error::RemoteMemError)
+    }
+
+    /// Writes `buf` to the remote memory at `offset`.
+    ///
+    /// The caller must have checked that `buf` fits into the slice at `offset`.
+    fn process_write(&self, transport: &dyn Transport, buf: &[u8], offset: usize) -> Result<usize> {
+        transport
+            .write_bytes(self.addr as usize + offset, buf)
+            .map_err(Error::RemoteMemError)
+    }
+
+    /// Like `process_read`, but fails if less than `buf.len()` bytes were read.
+    fn process_read_exact(
+        &self,
+        transport: &dyn Transport,
+        buf: &mut [u8],
+        offset: usize,
+    ) -> Result<()> {
+        let read = self.process_read(transport, buf, offset)?;
+        if read != buf.len() {
+            return Err(Error::PartialBuffer {
+                expected: buf.len(),
+                completed: read,
+            });
+        }
+        Ok(())
+    }
+
+    /// Like `process_write`, but fails if less than `buf.len()` bytes were written.
+    fn process_write_all(
+        &self,
+        transport: &dyn Transport,
+        buf: &[u8],
+        offset: usize,
+    ) -> Result<()> {
+        let written = self.process_write(transport, buf, offset)?;
+        if written != buf.len() {
+            return Err(Error::PartialBuffer {
+                expected: buf.len(),
+                completed: written,
+            });
+        }
+        Ok(())
+    }
+
+    /// Implements `Bytes::read_from` for remote slices by reading from `src` into a bounce
+    /// buffer and writing it to the remote process in chunks.
+    fn process_read_from<F>(
+        &self,
+        transport: &dyn Transport,
+        addr: usize,
+        src: &mut F,
+        count: usize,
+    ) -> Result<usize>
+    where
+        F: Read,
+    {
+        let mut buf = vec![0u8; min(count, MAX_ACCESS_CHUNK)].into_boxed_slice();
+        let mut total = 0;
+        while total < count {
+            let len = min(count - total, buf.len());
+            let bytes_read = loop {
+                match src.read(&mut buf[..len]) {
+                    Ok(n) => break n,
+                    Err(ref e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+                    Err(e) => return Err(Error::IOError(e)),
+                }
+            };
+            if bytes_read == 0 {
+                break;
+            }
+            self.process_write_all(transport, &buf[..bytes_read], addr + total)?;
+            total += bytes_read;
+            // A short read means `src` has no more data available right now, so stop here
+            // instead of blocking on the next read, like a single `Read::read` call would.
+            if bytes_read < len {
+                break;
+            }
+        }
+        Ok(total)
+    }
+
+    /// Implements `Bytes::read_exact_from` for remote slices.
+    fn process_read_exact_from<F>(
+        &self,
+        transport: &dyn Transport,
+        addr: usize,
+        src: &mut F,
+        count: usize,
+    ) -> Result<()>
+    where
+        F: Read,
+    {
+        let mut buf = vec![0u8; min(count, MAX_ACCESS_CHUNK)].into_boxed_slice();
+        let mut total = 0;
+        while total < count {
+            let len = min(count - total, buf.len());
+            src.read_exact(&mut buf[..len]).map_err(Error::IOError)?;
+            self.process_write_all(transport, &buf[..len], addr + total)?;
+            total += len;
+        }
+        Ok(())
+    }
+
+    /// Implements `Bytes::write_to` for remote slices by reading the remote memory into a
+    /// bounce buffer in chunks.
+    fn process_write_to<F>(
+        &self,
+        transport: &dyn Transport,
+        addr: usize,
+        dst: &mut F,
+        count: usize,
+    ) -> Result<usize>
+    where
+        F: Write,
+    {
+        let mut buf = vec![0u8; min(count, MAX_ACCESS_CHUNK)].into_boxed_slice();
+        let mut total = 0;
+        while total < count {
+            let len = min(count - total, buf.len());
+            self.process_read_exact(transport, &mut buf[..len], addr + total)?;
+            let bytes_written = loop {
+                match dst.write(&buf[..len]) {
+                    Ok(n) => break n,
+                    Err(ref e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+                    Err(e) => return Err(Error::IOError(e)),
+                }
+            };
+            total += bytes_written;
+            // `dst` didn't take the whole chunk; report what was actually written.
+            if bytes_written < len {
+                break;
+            }
+        }
+        Ok(total)
+    }
+
+    /// Implements `Bytes::write_all_to` for remote slices.
+    fn process_write_all_to<F>(
+        &self,
+        transport: &dyn Transport,
+        addr: usize,
+        dst: &mut F,
+        count: usize,
+    ) -> Result<()>
+    where
+        F: Write,
+    {
+        let mut buf = vec![0u8; min(count, MAX_ACCESS_CHUNK)].into_boxed_slice();
+        let mut total = 0;
+        while total < count {
+            let len = min(count - total, buf.len());
+            self.process_read_exact(transport, &mut buf[..len], addr + total)?;
+            dst.write_all(&buf[..len]).map_err(Error::IOError)?;
+            total += len;
+        }
+        Ok(())
+    }
+
     /// Checks if the current slice is aligned at `alignment` bytes.
     fn check_alignment(&self, alignment: usize) -> Result<()> {
         // Check that the desired alignment is a power of two.
@@ -536,11 +953,18 @@ impl Bytes<usize> for VolatileSlice<'_> {
             return Err(Error::OutOfBounds { addr });
         }
 
-        // Guest memory can't strictly be modeled as a slice because it is
-        // volatile.  Writing to it with what is essentially a fancy memcpy
-        // won't hurt anything as long as we get the bounds checks right.
-        let slice = unsafe { self.as_mut_slice() }.split_at_mut(addr).1;
-        Ok(copy_slice(slice, buf))
+        let written = if let Some(transport) = self.transport {
+            let len = min(buf.len(), self.size - addr);
+            self.process_write(transport, &buf[..len], addr)?
+        } else {
+            // Guest memory can't strictly be modeled as a slice because it is
+            // volatile.  Writing to it with what is essentially a fancy memcpy
+            // won't hurt anything as long as we get the bounds checks right.
+            let slice = unsafe { self.as_mut_slice() }.split_at_mut(addr).1;
+            copy_slice(slice, buf)
+        };
+        self.mark_dirty(addr, written);
+        Ok(written)
     }
 
     /// # Examples
@@ -563,6 +987,11 @@ impl Bytes<usize> for VolatileSlice<'_> {
             return Err(Error::OutOfBounds { addr });
         }
 
+        if let Some(transport) = self.transport {
+            let len = min(buf.len(), self.size - addr);
+            return self.process_read(transport, &mut buf[..len], addr);
+        }
+
         // Guest memory can't strictly be modeled as a slice because it is
         // volatile.  Writing to it with what is essentially a fancy memcpy
         // won't hurt anything as long as we get the bounds checks right.
@@ -657,19 +1086,25 @@ impl Bytes<usize> for VolatileSlice<'_> {
         F: Read,
     {
         let end = self.compute_end_offset(addr, count)?;
-        unsafe {
-            // It is safe to overwrite the volatile memory. Accessing the guest
-            // memory as a mutable slice is OK because nothing assumes another
-            // thread won't change what is loaded.
-            let dst = &mut self.as_mut_slice()[addr..end];
-            loop {
-                match src.read(dst) {
-                    Ok(n) => break Ok(n),
-                    Err(ref e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
-                    Err(e) => break Err(Error::IOError(e)),
+        let read = if let Some(transport) = self.transport {
+            self.process_read_from(transport, addr, src, count)?
+        } else {
+            unsafe {
+                // It is safe to overwrite the volatile memory. Accessing the guest
+                // memory as a mutable slice is OK because nothing assumes another
+                // thread won't change what is loaded.
+                let dst = &mut self.as_mut_slice()[addr..end];
+                loop {
+                    match src.read(dst) {
+                        Ok(n) => break n,
+                        Err(ref e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+                        Err(e) => return Err(Error::IOError(e)),
+                    }
                 }
             }
-        }
+        };
+        self.mark_dirty(addr, read);
+        Ok(read)
     }
 
     /// # Examples
@@ -701,6 +1136,11 @@ impl Bytes<usize> for VolatileSlice<'_> {
         F: Read,
     {
         let end = self.compute_end_offset(addr, count)?;
+        // Failures may leave a part of the range written.
+        self.mark_dirty(addr, count);
+        if let Some(transport) = self.transport {
+            return self.process_read_exact_from(transport, addr, src, count);
+        }
         unsafe {
             // It is safe to overwrite the volatile memory. Accessing the guest
             // memory as a mutable slice is OK because nothing assumes another
@@ -739,6 +1179,9 @@ impl Bytes<usize> for VolatileSlice<'_> {
         F: Write,
     {
         let end = self.compute_end_offset(addr, count)?;
+        if let Some(transport) = self.transport {
+            return self.process_write_to(transport, addr, dst, count);
+        }
         unsafe {
             // It is safe to read from volatile memory. Accessing the guest
             // memory as a slice is OK because nothing assumes another thread
@@ -782,6 +1225,9 @@ impl Bytes<usize> for VolatileSlice<'_> {
         F: Write,
     {
         let end = self.compute_end_offset(addr, count)?;
+        if let Some(transport) = self.transport {
+            return self.process_write_all_to(transport, addr, dst, count);
+        }
         unsafe {
             // It is safe to read from volatile memory. Accessing the guest
             // memory as a slice is OK because nothing assumes another thread
@@ -793,11 +1239,25 @@ impl Bytes<usize> for VolatileSlice<'_> {
     }
 
     fn store<T: AtomicAccess>(&self, val: T, addr: usize, order: Ordering) -> Result<()> {
-        self.get_atomic_ref::<T::A>(addr)
-            .map(|r| r.store(val.into(), order))
+        if let Some(transport) = self.transport {
+            self.compute_end_offset(addr, size_of::<T>())?;
+            remote_mem::store(transport, self.addr as usize + addr, &val)
+                .map_err(Error::RemoteMemError)?;
+        } else {
+            self.get_atomic_ref::<T::A>(addr)
+                .map(|r| r.store(val.into(), order))?;
+        }
+        self.mark_dirty(addr, size_of::<T>());
+        Ok(())
     }
 
     fn load<T: AtomicAccess>(&self, addr: usize, order: Ordering) -> Result<T> {
+        if let Some(transport) = self.transport {
+            self.compute_end_offset(addr, size_of::<T>())?;
+            return remote_mem::load(transport, self.addr as usize + addr)
+                .map_err(Error::RemoteMemError);
+        }
+
         self.get_atomic_ref::<T::A>(addr)
             .map(|r| r.load(order).into())
     }
@@ -813,7 +1273,7 @@ impl VolatileMemory for VolatileSlice<'_> {
         Ok(unsafe {
             // This is safe because the pointer is range-checked by compute_end_offset, and
             // the lifetime is the same as self.
-            VolatileSlice::new((self.addr as usize + offset) as *mut u8, count)
+            self.subslice_unchecked((self.addr as usize + offset) as *mut u8, count)
         })
     }
 }
@@ -1088,6 +1548,10 @@ impl<'a, T: ByteValued> VolatileArrayRef<'a, T> {
     /// }
     /// ```
     pub fn copy_to_volatile_slice(&self, slice: VolatileSlice) {
+        if slice.transport.is_some() {
+            return self.to_slice().copy_to_volatile_slice(slice);
+        }
+
         unsafe {
             // Safe because the pointers are range-checked when the slices
             // are created, and they never escape the VolatileSlices.
@@ -1147,11 +1611,15 @@ impl<'a, T: ByteValued> VolatileArrayRef<'a, T> {
     }
 }
 
-impl<'a> From<VolatileSlice<'a>> for VolatileArrayRef<'a, u8> {
-    fn from(slice: VolatileSlice<'a>) -> Self {
+impl<'a> TryFrom<VolatileSlice<'a>> for VolatileArrayRef<'a, u8> {
+    type Error = Error;
+
+    /// Fails with `Error::RemoteAddress` if `slice` refers to the memory of a remote process.
+    fn try_from(slice: VolatileSlice<'a>) -> Result<Self> {
+        slice.check_local()?;
         // Safe because the result has the same lifetime and points to the same
         // memory as the incoming VolatileSlice.
-        unsafe { VolatileArrayRef::new(slice.as_ptr(), slice.len()) }
+        Ok(unsafe { VolatileArrayRef::new(slice.as_ptr(), slice.len()) })
     }
 }
 
@@ -1232,6 +1700,7 @@ mod tests {
     use std::thread::{sleep, spawn};
     use std::time::Duration;
 
+    use crate::remote_mem::ProcessVm;
     use matches::assert_matches;
     use vmm_sys_util::tempfile::TempFile;
 
@@ -1311,6 +1780,11 @@ mod tests {
             ),
             "only used 90 bytes in 100 long buffer"
         );
+
+        assert_eq!(
+            format!("{}", Error::RemoteAddress { addr: 0x1000 }),
+            "address 0x1000 belongs to a remote process and can't be referenced"
+        );
     }
 
     #[test]
@@ -1760,7 +2234,7 @@ mod tests {
         let a_vec = a.to_vec();
         let a_ref = &mut a[..];
         let a_slice = a_ref.get_slice(0, a_ref.len()).unwrap();
-        let a_array_ref: VolatileArrayRef<u8> = a_slice.into();
+        let a_array_ref = VolatileArrayRef::<u8>::try_from(a_slice).unwrap();
         for (i, entry) in a_vec.iter().enumerate() {
             assert_eq!(&a_array_ref.load(i), entry);
         }
@@ -1833,6 +2307,35 @@ mod tests {
         crate::bytes::tests::check_atomic_accesses(s, 0, 0x1000);
     }
 
+    #[test]
+    fn test_compare_exchange() {
+        let a = VecMem::new(0x100);
+        let s = a.as_volatile_slice();
+
+        s.store(5u32, 0x10, Ordering::Relaxed).unwrap();
+        assert_eq!(
+            s.compare_exchange(4u32, 6, 0x10, Ordering::SeqCst, Ordering::Relaxed)
+                .unwrap(),
+            Err(5)
+        );
+        assert_eq!(
+            s.compare_exchange(5u32, 6, 0x10, Ordering::SeqCst, Ordering::Relaxed)
+                .unwrap(),
+            Ok(5)
+        );
+        assert_eq!(s.load::<u32>(0x10, Ordering::Relaxed).unwrap(), 6);
+        assert_matches!(
+            s.compare_exchange(0u32, 1, 0x11, Ordering::SeqCst, Ordering::Relaxed)
+                .unwrap_err(),
+            Error::Misaligned { .. }
+        );
+        assert_matches!(
+            s.compare_exchange(0u32, 1, 0x100, Ordering::SeqCst, Ordering::Relaxed)
+                .unwrap_err(),
+            Error::OutOfBounds { .. }
+        );
+    }
+
     #[test]
     fn split_at() {
         let mut mem = [0u8; 32];
@@ -1853,4 +2356,189 @@ mod tests {
         let err = vslice.split_at(33).unwrap_err();
         assert_matches!(err, Error::OutOfBounds { addr: _ })
     }
+
+    // Remote slices are exercised against the current process, which `process_vm_readv` and
+    // `process_vm_writev` treat like any other process.
+    fn remote_slice<'a>(transport: &'a ProcessVm, mem: &VecMem) -> VolatileSlice<'a> {
+        VolatileSlice::new_remote(transport, mem.mem.as_ptr() as usize, mem.len())
+    }
+
+    #[test]
+    fn remote_slice_subslice() {
+        let mem = VecMem::new(32);
+        let transport = ProcessVm::new(Pid::this());
+        let vslice = remote_slice(&transport, &mem);
+        assert_eq!(vslice.pid(), Some(Pid::this()));
+
+        let sub = vslice.subslice(8, 16).unwrap();
+        assert_eq!(sub.pid(), Some(Pid::this()));
+        assert_eq!(sub.as_ptr() as usize, vslice.as_ptr() as usize + 8);
+        assert!(vslice.subslice(24, 16).is_err());
+
+        let (start, end) = vslice.split_at(8).unwrap();
+        assert_eq!(start.pid(), Some(Pid::this()));
+        assert_eq!(end.pid(), Some(Pid::this()));
+        assert_eq!(end.len(), 24);
+
+        let off = vslice.offset(30).unwrap();
+        assert_eq!(off.pid(), Some(Pid::this()));
+        assert_eq!(off.len(), 2);
+        assert_eq!(vslice.get_slice(4, 4).unwrap().pid(), Some(Pid::this()));
+    }
+
+    #[test]
+    fn remote_slice_copy() {
+        let mem = VecMem::new(32);
+        let transport = ProcessVm::new(Pid::this());
+        let vslice = remote_slice(&transport, &mem);
+
+        vslice.copy_from(&[0x0102u16; 8][..]);
+        let mut buf = [0u8; 20];
+        assert_eq!(vslice.copy_to(&mut buf[..]), 20);
+        assert_eq!(buf[..16], [2u8, 1].repeat(8)[..]);
+        assert_eq!(buf[16..], [0u8; 4]);
+
+        // Remote to local and local to remote.
+        let local = VecMem::new(8);
+        let local_slice = local.as_volatile_slice();
+        vslice.copy_to_volatile_slice(local_slice);
+        assert_eq!(local.mem[..], [2u8, 1, 2, 1, 2, 1, 2, 1]);
+        local_slice.copy_from(&[7u8; 8][..]);
+        local_slice.copy_to_volatile_slice(vslice.offset(24).unwrap());
+        let mut buf = [0u8; 8];
+        assert_eq!(vslice.offset(24).unwrap().copy_to(&mut buf[..]), 8);
+        assert_eq!(buf, [7u8; 8]);
+
+        // Errors of the transport are only reported by the `try_` variants.
+        let unmapped = VolatileSlice::new_remote(&transport, 0x10, 8);
+        assert_eq!(unmapped.copy_to(&mut buf[..]), 0);
+        assert_matches!(
+            unmapped.try_copy_to(&mut buf[..]).unwrap_err(),
+            Error::RemoteMemError(_)
+        );
+        assert_matches!(
+            unmapped.try_copy_from(&buf[..]).unwrap_err(),
+            Error::RemoteMemError(_)
+        );
+        assert_matches!(
+            local_slice
+                .try_copy_to_volatile_slice(unmapped)
+                .unwrap_err(),
+            Error::RemoteMemError(_)
+        );
+        assert_eq!(vslice.try_copy_to_volatile_slice(local_slice).unwrap(), 8);
+        assert_eq!(vslice.try_copy_from(&[1u32; 16][..]).unwrap(), 8);
+    }
+
+    #[test]
+    fn remote_slice_bytes() {
+        let mem = VecMem::new(1024);
+        let transport = ProcessVm::new(Pid::this());
+        let vslice = remote_slice(&transport, &mem);
+
+        assert_eq!(vslice.write(&[1, 2, 3, 4, 5], 1020).unwrap(), 4);
+        let mut buf = [0u8; 8];
+        assert_eq!(vslice.read(&mut buf, 1016).unwrap(), 8);
+        assert_eq!(buf, [0, 0, 0, 0, 1, 2, 3, 4]);
+        assert!(vslice.write(&[1], 1024).is_err());
+
+        vslice.write_obj(0xdead_beef_u32, 0x10).unwrap();
+        assert_eq!(vslice.read_obj::<u32>(0x10).unwrap(), 0xdead_beef);
+        vslice.store(0x55u8, 0x20, Ordering::Relaxed).unwrap();
+        assert_eq!(vslice.load::<u8>(0x20, Ordering::Relaxed).unwrap(), 0x55);
+
+        let image: Vec<u8> = (0..0x200).map(|i| i as u8).collect();
+        assert_eq!(
+            vslice
+                .read_from(0x100, &mut std::io::Cursor::new(&image), image.len())
+                .unwrap(),
+            image.len()
+        );
+        let mut sink = Vec::new();
+        vslice.write_all_to(0x100, &mut sink, image.len()).unwrap();
+        assert_eq!(sink, image);
+        assert!(vslice.write_all_to(0x300, &mut sink, 0x200).is_err());
+    }
+
+    #[test]
+    fn remote_slice_no_references() {
+        let mem = VecMem::new(32);
+        let transport = ProcessVm::new(Pid::this());
+        let vslice = remote_slice(&transport, &mem);
+
+        assert_matches!(
+            vslice.get_ref::<u32>(0).unwrap_err(),
+            Error::RemoteAddress { addr: _ }
+        );
+        assert_matches!(
+            vslice.get_array_ref::<u8>(0, 4).unwrap_err(),
+            Error::RemoteAddress { addr: _ }
+        );
+        assert_matches!(
+            vslice.get_atomic_ref::<AtomicUsize>(0).unwrap_err(),
+            Error::RemoteAddress { addr: _ }
+        );
+        assert_matches!(
+            vslice
+                .compare_exchange(0u32, 1, 0, Ordering::SeqCst, Ordering::Relaxed)
+                .unwrap_err(),
+            Error::RemoteAddress { addr: _ }
+        );
+        assert_matches!(
+            VolatileArrayRef::<u8>::try_from(vslice).unwrap_err(),
+            Error::RemoteAddress { addr: _ }
+        );
+    }
+
+    #[test]
+    fn dirty_tracking() {
+        let mem = VecMem::new(0x4000);
+        let bitmap = DirtyBitmap::new(0x5000, 0x1000).unwrap();
+        let dirty = |slice: &VolatileSlice| -> Vec<(usize, usize)> {
+            let ranges = bitmap.take_dirty_ranges().collect();
+            assert!(slice.dirty.is_some());
+            ranges
+        };
+
+        // The slice starts at page 1 of the tracked memory.
+        let s = mem.as_volatile_slice().with_dirty_bitmap(&bitmap, 0x1000);
+        s.read_slice(&mut [0u8; 0x100], 0).unwrap();
+        assert_eq!(s.load::<u32>(0x10, Ordering::Relaxed).unwrap(), 0);
+        s.copy_to(&mut [0u16; 8][..]);
+        assert_eq!(dirty(&s), vec![]);
+
+        s.write_slice(&[1u8; 2], 0xfff).unwrap();
+        assert_eq!(dirty(&s), vec![(0x1000, 0x2000)]);
+        s.store(1u64, 0x3ff8, Ordering::Relaxed).unwrap();
+        assert_eq!(dirty(&s), vec![(0x4000, 0x1000)]);
+        s.read_exact_from(0x2000, &mut &[1u8; 0x10][..], 0x10)
+            .unwrap();
+        assert_eq!(dirty(&s), vec![(0x3000, 0x1000)]);
+        assert!(s
+            .compare_exchange(0u32, 1, 0x1800, Ordering::SeqCst, Ordering::Relaxed)
+            .unwrap()
+            .is_ok());
+        assert!(s
+            .compare_exchange(0u32, 1, 0x1800, Ordering::SeqCst, Ordering::Relaxed)
+            .unwrap()
+            .is_err());
+        assert_eq!(dirty(&s), vec![(0x2000, 0x1000)]);
+
+        // Subslices mark the pages relative to their own start.
+        let sub = s.subslice(0x1ff0, 0x20).unwrap();
+        sub.copy_from(&[2u32; 4][..]);
+        assert_eq!(dirty(&sub), vec![(0x2000, 0x1000)]);
+        let (_, end) = s.split_at(0x3000).unwrap();
+        sub.copy_to_volatile_slice(end);
+        assert_eq!(dirty(&end), vec![(0x4000, 0x1000)]);
+
+        // Remote slices are tracked the same.
+        let transport = ProcessVm::new(Pid::this());
+        let s = remote_slice(&transport, &mem).with_dirty_bitmap(&bitmap, 0);
+        s.write(&[3u8; 0x10], 0x3ff8).unwrap();
+        s.read_from(0x10, &mut &[1u8; 4][..], 4).unwrap();
+        assert_eq!(dirty(&s), vec![(0, 0x1000), (0x3000, 0x1000)]);
+        s.mark_dirty(0x3fff, 0x10);
+        assert_eq!(dirty(&s), vec![(0x3000, 0x1000)]);
+    }
 }
@@ -0,0 +1,251 @@
+//! A pool of threads copying between local buffers and guest memory.
+//!
+//! Accesses to remote guest memory block the calling thread in `process_vm_readv` or
+//! `process_vm_writev`, which stalls a device thread serving large disk requests. A
+//! [`WorkerPool`](struct.WorkerPool.html) runs such copies on its own threads and returns a
+//! [`Completion`](struct.Completion.html) for each, so that the device can overlap them with e.g.
+//! file I/O.
+//!
+//! # Examples
+//!
+//! ```
+//! # #[cfg(feature = "backend-mmap")]
+//! # {
+//! # use std::sync::Arc;
+//! # use vm_memory::{GuestAddress, GuestMemoryMmap};
+//! # use vm_memory::worker_pool::WorkerPool;
+//! # let pid = std::process::id() as i32;
+//! let gm = GuestMemoryMmap::from_ranges(pid, &[(GuestAddress(0), 0x10000)]).unwrap();
+//! let pool = WorkerPool::new(Arc::new(gm), 2).unwrap();
+//!
+//! let write = pool.write(GuestAddress(0x1000), vec![0xaa; 0x2000]);
+//! // ... read the next request from disk ...
+//! assert_eq!(write.wait().1.unwrap(), 0x2000);
+//! let (buf, read) = pool.read(GuestAddress(0x1000), vec![0; 0x2000]).wait();
+//! assert_eq!(read.unwrap(), 0x2000);
+//! assert!(buf.iter().all(|&b| b == 0xaa));
+//! # }
+//! ```
+
+use std::error;
+use std::fmt;
+use std::io;
+use std::result;
+use std::sync::mpsc::{self, Receiver, Sender, TryRecvError};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+
+use crate::guest_memory::{self, GuestAddress, GuestAddressSpace};
+use crate::Bytes;
+
+/// Errors of copies run by a `WorkerPool`.
+#[derive(Debug)]
+pub enum Error {
+    /// Accessing the guest memory failed.
+    Memory(guest_memory::Error),
+    /// The worker running the copy is gone.
+    WorkerGone,
+    /// Spawning a worker thread failed.
+    Spawn(io::Error),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Memory(e) => write!(f, "cannot copy guest memory: {}", e),
+            Error::WorkerGone => write!(f, "the guest memory worker is gone"),
+            Error::Spawn(e) => write!(f, "cannot spawn guest memory worker: {}", e),
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            Error::Memory(e) => Some(e),
+            Error::Spawn(e) => Some(e),
+            Error::WorkerGone => None,
+        }
+    }
+}
+
+/// A specialized `Result` type for the copies of a `WorkerPool`.
+pub type Result<T> = result::Result<T, Error>;
+
+/// The buffer of a finished copy and the number of bytes copied.
+type Done = (Vec<u8>, Result<usize>);
+
+/// A copy waiting for a worker.
+struct Job {
+    write: bool,
+    addr: GuestAddress,
+    buf: Vec<u8>,
+    done: Sender<Done>,
+}
+
+/// Threads copying between local buffers and guest memory, see the
+/// [module documentation](index.html).
+///
+/// Copies are started in the order they are submitted, but may complete in any order. Dropping
+/// the pool waits for all submitted copies.
+#[derive(Debug)]
+pub struct WorkerPool {
+    jobs: Option<Sender<Job>>,
+    workers: Vec<JoinHandle<()>>,
+}
+
+impl WorkerPool {
+    /// Spawns `threads` workers (at least one) copying from and to `mem`.
+    ///
+    /// The memory is looked up for every copy, so the workers follow e.g. the updates of a
+    /// `GuestMemoryAtomic`.
+    pub fn new<AS>(mem: AS, threads: usize) -> Result<Self>
+    where
+        AS: GuestAddressSpace + Send + Sync + 'static,
+    {
+        let (tx, rx) = mpsc::channel();
+        let rx = Arc::new(Mutex::new(rx));
+        let mem = Arc::new(mem);
+        let mut pool = WorkerPool {
+            jobs: Some(tx),
+            workers: Vec::new(),
+        };
+        for i in 0..threads.max(1) {
+            let (rx, mem) = (rx.clone(), mem.clone());
+            let worker = thread::Builder::new()
+                .name(format!("guest-mem-{}", i))
+                .spawn(move || work(&*mem, &rx))
+                .map_err(Error::Spawn)?;
+            pool.workers.push(worker);
+        }
+        Ok(pool)
+    }
+
+    /// Reads up to `buf.len()` bytes at `addr` into `buf`, like `Bytes::read`.
+    pub fn read(&self, addr: GuestAddress, buf: Vec<u8>) -> Completion {
+        self.submit(false, addr, buf)
+    }
+
+    /// Writes up to `buf.len()` bytes of `buf` to `addr`, like `Bytes::write`.
+    pub fn write(&self, addr: GuestAddress, buf: Vec<u8>) -> Completion {
+        self.submit(true, addr, buf)
+    }
+
+    fn submit(&self, write: bool, addr: GuestAddress, buf: Vec<u8>) -> Completion {
+        let (done, rx) = mpsc::channel();
+        let job = Job {
+            write,
+            addr,
+            buf,
+            done,
+        };
+        if let Some(Err(mpsc::SendError(job))) = self.jobs.as_ref().map(|jobs| jobs.send(job)) {
+            let _ = job.done.send((job.buf, Err(Error::WorkerGone)));
+        }
+        Completion { done: rx }
+    }
+}
+
+impl Drop for WorkerPool {
+    fn drop(&mut self) {
+        // Workers finish the queued jobs before they see the channel closed.
+        self.jobs.take();
+        for worker in self.workers.drain(..) {
+            let _ = worker.join();
+        }
+    }
+}
+
+/// Body of a worker: runs jobs until the pool is dropped.
+fn work<AS: GuestAddressSpace>(mem: &AS, jobs: &Mutex<Receiver<Job>>) {
+    loop {
+        let job = match jobs.lock().unwrap().recv() {
+            Ok(job) => job,
+            Err(_) => return,
+        };
+        let mem = mem.memory();
+        let mut buf = job.buf;
+        let res = if job.write {
+            mem.write(&buf, job.addr)
+        } else {
+            mem.read(&mut buf, job.addr)
+        };
+        let _ = job.done.send((buf, res.map_err(Error::Memory)));
+    }
+}
+
+/// The handle of a copy submitted to a `WorkerPool`.
+#[derive(Debug)]
+pub struct Completion {
+    done: Receiver<Done>,
+}
+
+impl Completion {
+    /// Blocks until the copy is done. Returns the buffer and the number of bytes copied.
+    pub fn wait(self) -> (Vec<u8>, Result<usize>) {
+        self.done
+            .recv()
+            .unwrap_or_else(|_| (Vec::new(), Err(Error::WorkerGone)))
+    }
+
+    /// Returns the result of `wait` if the copy is done, or the handle otherwise.
+    pub fn try_wait(self) -> result::Result<(Vec<u8>, Result<usize>), Self> {
+        match self.done.try_recv() {
+            Ok(done) => Ok(done),
+            Err(TryRecvError::Empty) => Err(self),
+            Err(TryRecvError::Disconnected) => Ok((Vec::new(), Err(Error::WorkerGone))),
+        }
+    }
+}
+
+#[cfg(all(test, feature = "backend-mmap"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_worker_pool() {
+        use crate::test_utils::TestProcess;
+
+        let (_child, gm) = TestProcess::guest_memory(&[
+            (GuestAddress(0), 0x10000),
+            (GuestAddress(0x10000), 0x10000),
+        ]);
+        let gm = Arc::new(gm);
+        let pool = WorkerPool::new(gm.clone(), 3).unwrap();
+
+        let writes: Vec<Completion> = (0..8u8)
+            .map(|i| {
+                let addr = GuestAddress(0x1000 + u64::from(i) * 0x3000);
+                pool.write(addr, vec![i + 1; 0x3000])
+            })
+            .collect();
+        for write in writes {
+            assert_eq!(write.wait().1.unwrap(), 0x3000);
+        }
+
+        // Across the regions, i.e. the writes of 5 and 6.
+        let mut read = pool.read(GuestAddress(0xf000), vec![0; 0x2000]);
+        let (buf, res) = loop {
+            match read.try_wait() {
+                Ok(done) => break done,
+                Err(c) => read = c,
+            }
+        };
+        assert_eq!(res.unwrap(), 0x2000);
+        assert!(buf[..0x1000].iter().all(|&b| b == 5));
+        assert!(buf[0x1000..].iter().all(|&b| b == 6));
+
+        // Partial and failed copies.
+        let (_, res) = pool.read(GuestAddress(0x1ff00), vec![0; 0x200]).wait();
+        assert_eq!(res.unwrap(), 0x100);
+        let (buf, res) = pool.write(GuestAddress(0x20000), vec![1; 4]).wait();
+        assert_eq!(buf, vec![1; 4]);
+        assert!(matches!(res, Err(Error::Memory(_))));
+
+        // Dropping the pool completes the queued copies.
+        let write = pool.write(GuestAddress(0), vec![9; 0x10]);
+        drop(pool);
+        assert_eq!(write.wait().1.unwrap(), 0x10);
+        assert_eq!(gm.read_obj::<u8>(GuestAddress(0xf)).unwrap(), 9);
+    }
+}
@@ -0,0 +1,407 @@
+//! Write combining for guest memory of another process.
+//!
+//! Every write to remote guest memory costs a system call. A
+//! [`WriteBuffer`](struct.WriteBuffer.html) accumulates the writes of a device, coalesces
+//! adjacent and overlapping ranges, and commits them with a single vectored call on `flush()` or
+//! when it's dropped. Reads through the buffer see the pending writes.
+//!
+//! Atomic stores aren't buffered. A store with `Ordering::Release` (or stronger) flushes the
+//! pending writes first, so e.g. an update of a used ring index becomes visible only after the
+//! used elements and data it publishes.
+
+use std::cmp::{max, min};
+use std::collections::BTreeMap;
+use std::io::{Read, Write};
+use std::sync::atomic::Ordering;
+use std::sync::Mutex;
+
+use crate::guest_memory::{remote_iovecs, Error, GuestAddress, GuestMemory, Result};
+use crate::remote_mem::Transport;
+use crate::{AtomicAccess, Bytes};
+
+/// A write-combining wrapper around guest memory, see the [module documentation](index.html).
+#[derive(Debug)]
+pub struct WriteBuffer<'a, M: GuestMemory> {
+    mem: &'a M,
+    /// Pending writes by guest address. The ranges neither overlap nor touch each other.
+    pending: Mutex<BTreeMap<u64, Vec<u8>>>,
+}
+
+impl<'a, M: GuestMemory> WriteBuffer<'a, M> {
+    /// Creates an empty buffer for writes to `mem`.
+    pub fn new(mem: &'a M) -> Self {
+        WriteBuffer {
+            mem,
+            pending: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    /// Returns the number of coalesced ranges waiting to be written.
+    pub fn pending_ranges(&self) -> usize {
+        self.pending.lock().unwrap().len()
+    }
+
+    /// Writes all pending ranges to the guest memory.
+    ///
+    /// If all of them are accessed through the same remote transport, this is a single vectored
+    /// call. The writes that fail, or are only done in part, stay pending, so that `flush()` can
+    /// be retried.
+    pub fn flush(&self) -> Result<()> {
+        let mut pending = self.pending.lock().unwrap();
+        if pending.is_empty() {
+            return Ok(());
+        }
+
+        if let Some((transport, iovecs)) = self.remote_iovecs(&pending) {
+            let bufs: Vec<&[u8]> = pending.values().map(|data| &data[..]).collect();
+            let expected = bufs.iter().map(|buf| buf.len()).sum();
+            let written = transport
+                .writev(&bufs, &iovecs)
+                .map_err(Error::RemoteMemError)?;
+            for (addr, len) in remove_written(&mut pending, written) {
+                self.mem.mark_dirty(GuestAddress(addr), len);
+            }
+            if written != expected {
+                return Err(Error::PartialBuffer {
+                    expected,
+                    completed: written,
+                });
+            }
+            return Ok(());
+        }
+        while let Some((&addr, data)) = pending.iter().next() {
+            let written = self.mem.write(data, GuestAddress(addr))?;
+            let expected = data.len();
+            remove_written(&mut pending, written);
+            if written != expected {
+                return Err(Error::PartialBuffer {
+                    expected,
+                    completed: written,
+                });
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the transport and remote ranges of all `pending` writes, if they share a transport.
+    fn remote_iovecs(
+        &self,
+        pending: &BTreeMap<u64, Vec<u8>>,
+    ) -> Option<(&'a dyn Transport, Vec<nix::sys::uio::RemoteIoVec>)> {
+        let id = |t: &dyn Transport| t as *const dyn Transport as *const u8 as usize;
+        let mut transport: Option<&'a dyn Transport> = None;
+        let mut all = Vec::new();
+        for (&addr, data) in pending.iter() {
+            let (t, iovecs) = remote_iovecs(self.mem, data.len(), GuestAddress(addr))?;
+            match transport {
+                Some(u) if id(u) != id(t) => return None,
+                _ => transport = Some(t),
+            }
+            all.extend(iovecs);
+        }
+        transport.map(|t| (t, all))
+    }
+
+    /// Returns `true` if a pending write overlaps `len` bytes at `addr`.
+    fn overlaps(&self, addr: GuestAddress, len: usize) -> bool {
+        let end = addr.0.saturating_add(len as u64);
+        let pending = self.pending.lock().unwrap();
+        matches!(
+            pending.range(..end).next_back(),
+            Some((&start, data)) if start + data.len() as u64 > addr.0
+        )
+    }
+}
+
+impl<'a, M: GuestMemory> Drop for WriteBuffer<'a, M> {
+    fn drop(&mut self) {
+        if let Err(e) = self.flush() {
+            log::warn!("cannot flush buffered guest memory writes: {}", e);
+        }
+    }
+}
+
+/// Adds a write of `buf` at `addr` to `pending`, merging it with the ranges it overlaps or
+/// touches. Later writes win.
+fn insert(pending: &mut BTreeMap<u64, Vec<u8>>, addr: u64, buf: &[u8]) {
+    let end = addr + buf.len() as u64;
+    // Ranges are disjoint, so their ends are sorted like their starts.
+    let merged: Vec<u64> = pending
+        .range(..=end)
+        .rev()
+        .take_while(|(&start, data)| start + data.len() as u64 >= addr)
+        .map(|(&start, _)| start)
+        .collect();
+
+    let mut start = addr;
+    let mut merged_end = end;
+    for s in merged.iter() {
+        start = min(start, *s);
+        merged_end = max(merged_end, s + pending[s].len() as u64);
+    }
+    let mut data = vec![0u8; (merged_end - start) as usize];
+    for s in merged.iter() {
+        let old = pending.remove(s).unwrap();
+        let offset = (s - start) as usize;
+        data[offset..offset + old.len()].copy_from_slice(&old);
+    }
+    let offset = (addr - start) as usize;
+    data[offset..offset + buf.len()].copy_from_slice(buf);
+    pending.insert(start, data);
+}
+
+/// Removes the first `written` bytes of `pending`, in the order of their addresses, and returns
+/// the address and length of the ranges they were taken from. A range written in part keeps its
+/// tail.
+fn remove_written(pending: &mut BTreeMap<u64, Vec<u8>>, mut written: usize) -> Vec<(u64, usize)> {
+    let mut done = Vec::new();
+    while written > 0 {
+        let addr = match pending.keys().next() {
+            Some(&addr) => addr,
+            None => break,
+        };
+        let mut data = pending.remove(&addr).unwrap();
+        let len = min(written, data.len());
+        if len < data.len() {
+            pending.insert(addr + len as u64, data.split_off(len));
+        }
+        done.push((addr, len));
+        written -= len;
+    }
+    done
+}
+
+impl<'a, M: GuestMemory> Bytes<GuestAddress> for WriteBuffer<'a, M> {
+    type E = Error;
+
+    /// Buffers the write if the whole range is guest memory. Otherwise the pending writes are
+    /// flushed and `buf` is written right away, possibly partially.
+    fn write(&self, buf: &[u8], addr: GuestAddress) -> Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        if !self.mem.check_range(addr, buf.len()) {
+            self.flush()?;
+            return self.mem.write(buf, addr);
+        }
+        insert(&mut self.pending.lock().unwrap(), addr.0, buf);
+        Ok(buf.len())
+    }
+
+    /// Reads from the guest memory and overlays the pending writes.
+    fn read(&self, buf: &mut [u8], addr: GuestAddress) -> Result<usize> {
+        let pending = self.pending.lock().unwrap();
+        let read = self.mem.read(buf, addr)?;
+        let end = addr.0 + read as u64;
+        for (&start, data) in pending.range(..end) {
+            let data_end = start + data.len() as u64;
+            if data_end <= addr.0 {
+                continue;
+            }
+            let from = max(start, addr.0);
+            let to = min(data_end, end);
+            buf[(from - addr.0) as usize..(to - addr.0) as usize]
+                .copy_from_slice(&data[(from - start) as usize..(to - start) as usize]);
+        }
+        Ok(read)
+    }
+
+    fn write_slice(&self, buf: &[u8], addr: GuestAddress) -> Result<()> {
+        let written = self.write(buf, addr)?;
+        if written != buf.len() {
+            return Err(Error::PartialBuffer {
+                expected: buf.len(),
+                completed: written,
+            });
+        }
+        Ok(())
+    }
+
+    fn read_slice(&self, buf: &mut [u8], addr: GuestAddress) -> Result<()> {
+        let read = self.read(buf, addr)?;
+        if read != buf.len() {
+            return Err(Error::PartialBuffer {
+                expected: buf.len(),
+                completed: read,
+            });
+        }
+        Ok(())
+    }
+
+    /// Flushes the pending writes and reads into the guest memory right away.
+    fn read_from<F>(&self, addr: GuestAddress, src: &mut F, count: usize) -> Result<usize>
+    where
+        F: Read,
+    {
+        self.flush()?;
+        self.mem.read_from(addr, src, count)
+    }
+
+    /// Flushes the pending writes and reads into the guest memory right away.
+    fn read_exact_from<F>(&self, addr: GuestAddress, src: &mut F, count: usize) -> Result<()>
+    where
+        F: Read,
+    {
+        self.flush()?;
+        self.mem.read_exact_from(addr, src, count)
+    }
+
+    /// Flushes the pending writes and writes from the guest memory.
+    fn write_to<F>(&self, addr: GuestAddress, dst: &mut F, count: usize) -> Result<usize>
+    where
+        F: Write,
+    {
+        self.flush()?;
+        self.mem.write_to(addr, dst, count)
+    }
+
+    /// Flushes the pending writes and writes from the guest memory.
+    fn write_all_to<F>(&self, addr: GuestAddress, dst: &mut F, count: usize) -> Result<()>
+    where
+        F: Write,
+    {
+        self.flush()?;
+        self.mem.write_all_to(addr, dst, count)
+    }
+
+    /// Stores right away. The pending writes are flushed before, if `order` is `Release` or
+    /// stronger or if they overlap the value.
+    fn store<T: AtomicAccess>(&self, val: T, addr: GuestAddress, order: Ordering) -> Result<()> {
+        if order != Ordering::Relaxed || self.overlaps(addr, std::mem::size_of::<T>()) {
+            self.flush()?;
+        }
+        self.mem.store(val, addr, order)
+    }
+
+    /// Loads right away. The pending writes are flushed before if they overlap the value.
+    fn load<T: AtomicAccess>(&self, addr: GuestAddress, order: Ordering) -> Result<T> {
+        if self.overlaps(addr, std::mem::size_of::<T>()) {
+            self.flush()?;
+        }
+        self.mem.load(addr, order)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_insert() {
+        let mut pending = BTreeMap::new();
+        insert(&mut pending, 0x10, &[1, 2]);
+        insert(&mut pending, 0x20, &[3]);
+        insert(&mut pending, 0x12, &[4]);
+        insert(&mut pending, 0x0e, &[5, 6, 7]);
+        assert_eq!(pending.len(), 2);
+        assert_eq!(pending[&0x0e], vec![5, 6, 7, 2, 4]);
+
+        // Touching the range at 0x20 merges it, too.
+        insert(&mut pending, 0x13, &[8; 13]);
+        assert_eq!(pending.len(), 1);
+        let mut expected = vec![5, 6, 7, 2, 4];
+        expected.extend_from_slice(&[8; 13]);
+        expected.push(3);
+        assert_eq!(pending[&0x0e], expected);
+    }
+
+    #[test]
+    fn test_remove_written() {
+        let mut pending = BTreeMap::new();
+        insert(&mut pending, 0x10, &[1, 2, 3]);
+        insert(&mut pending, 0x20, &[4, 5]);
+        insert(&mut pending, 0x30, &[6]);
+
+        assert_eq!(remove_written(&mut pending, 4), vec![(0x10, 3), (0x20, 1)]);
+        assert_eq!(pending.len(), 2);
+        assert_eq!(pending[&0x21], vec![5]);
+        assert_eq!(pending[&0x30], vec![6]);
+        assert!(remove_written(&mut pending, 0).is_empty());
+        assert_eq!(remove_written(&mut pending, 2), vec![(0x21, 1), (0x30, 1)]);
+        assert!(pending.is_empty());
+    }
+
+    #[cfg(feature = "backend-mmap")]
+    #[test]
+    fn test_write_buffer() {
+        use nix::unistd::Pid;
+
+        use crate::remote_mem::ProcessVm;
+        use crate::test_utils::TestProcess;
+
+        let (child, gm) =
+            TestProcess::guest_memory(&[(GuestAddress(0), 0x1000), (GuestAddress(0x1000), 0x1000)]);
+        let guest = ProcessVm::new(Pid::from_raw(child.pid()));
+        let remote = |addr: usize| {
+            let mut buf = [0u8; 4];
+            guest.read_bytes(&mut buf, addr).unwrap();
+            u32::from_le_bytes(buf)
+        };
+
+        let wb = WriteBuffer::new(&gm);
+        wb.write_obj(1u32, GuestAddress(0x10)).unwrap();
+        wb.write_obj(2u32, GuestAddress(0x14)).unwrap();
+        wb.write_obj(0x0403_0201u32, GuestAddress(0xffe)).unwrap();
+        assert_eq!(wb.pending_ranges(), 2);
+        assert_eq!(
+            wb.read_obj::<u64>(GuestAddress(0x10)).unwrap(),
+            0x2_0000_0001
+        );
+        assert_eq!(remote(child.addr(0) + 0x10), 0);
+        assert_eq!(gm.stats().bytes_written, 0);
+
+        // Relaxed stores elsewhere don't flush, releasing ones do.
+        wb.store(4u16, GuestAddress(0x100), Ordering::Relaxed)
+            .unwrap();
+        assert_eq!(wb.pending_ranges(), 2);
+        wb.store(5u16, GuestAddress(0x102), Ordering::Release)
+            .unwrap();
+        assert_eq!(wb.pending_ranges(), 0);
+        assert_eq!(remote(child.addr(0) + 0x10), 1);
+        assert_eq!(remote(child.addr(0) + 0x14), 2);
+        // Both halves of the value written across the regions.
+        assert_eq!(remote(child.addr(0) + 0xffc), 0x0201_0000);
+        assert_eq!(remote(child.addr(1)), 0x0403);
+        assert_eq!(remote(child.addr(0) + 0x100), 0x5_0004);
+        // One call for the read and each store, a single one for the pending ranges.
+        assert_eq!(gm.stats().syscalls, 1 + 2 + 1);
+
+        // Loads of pending data flush them, dropping the buffer too.
+        wb.write_obj(6u8, GuestAddress(0x200)).unwrap();
+        assert_eq!(
+            wb.load::<u8>(GuestAddress(0x200), Ordering::Acquire)
+                .unwrap(),
+            6
+        );
+        wb.write_obj(7u32, GuestAddress(0x1800)).unwrap();
+        assert!(wb.write_obj(8u32, GuestAddress(0x1ffe)).is_err());
+        assert_eq!(remote(child.addr(1) + 0x800), 7);
+        wb.write_obj(9u32, GuestAddress(0x1900)).unwrap();
+        drop(wb);
+        assert_eq!(remote(child.addr(1) + 0x900), 9);
+    }
+
+    #[cfg(feature = "backend-mmap")]
+    #[test]
+    fn test_flush_error() {
+        use nix::sys::signal::{kill, Signal};
+        use nix::sys::wait::waitpid;
+        use nix::unistd::Pid;
+
+        use crate::test_utils::TestProcess;
+
+        let (child, gm) = TestProcess::guest_memory(&[(GuestAddress(0), 0x1000)]);
+        let wb = WriteBuffer::new(&gm);
+        wb.write_obj(1u32, GuestAddress(0x10)).unwrap();
+        wb.write_obj(2u32, GuestAddress(0x20)).unwrap();
+
+        // The writes stay pending if the process is gone.
+        let pid = Pid::from_raw(child.pid());
+        kill(pid, Signal::SIGKILL).unwrap();
+        waitpid(pid, None).unwrap();
+        assert!(wb.flush().is_err());
+        assert_eq!(wb.pending_ranges(), 2);
+        assert!(wb.flush().is_err());
+        assert_eq!(wb.pending_ranges(), 2);
+    }
+}